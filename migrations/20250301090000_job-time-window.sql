@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE jobs ADD COLUMN starttime varchar(100);
+ALTER TABLE jobs ADD COLUMN endtime varchar(100);
@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE timeoff (
+    id integer not null primary key autoincrement,
+    worker integer not null references users(id),
+    start_date date not null,
+    end_date date not null,
+    reason text not null default '',
+    status varchar(20) not null default 'pending',
+    decided_by integer references users(id)
+);
+
+CREATE TABLE availability (
+    worker integer not null references users(id),
+    weekday integer not null,
+    available boolean not null default true,
+    starttime varchar(100),
+    endtime varchar(100),
+
+    primary key (worker, weekday)
+);
@@ -0,0 +1,21 @@
+-- Add migration script here
+ALTER TABLE jobs ADD COLUMN open boolean not null default false;
+ALTER TABLE jobs ADD COLUMN max_crew int not null default 1;
+ALTER TABLE jobs ADD COLUMN needs_confirmation boolean not null default false;
+
+CREATE TABLE jobclaims (
+    id integer not null primary key autoincrement,
+    job integer not null references jobs(id),
+    worker integer not null references users(id),
+    status varchar(20) not null default 'pending'
+);
+
+-- no reference to jobs so the history outlives deleted jobs
+CREATE TABLE claimevents (
+    id integer not null primary key autoincrement,
+    job integer not null,
+    worker integer not null references users(id),
+    actor integer not null references users(id),
+    action varchar(20) not null,
+    at datetime not null default current_timestamp
+);
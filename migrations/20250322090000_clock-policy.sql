@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE settings (
+    name varchar(100) not null primary key,
+    value varchar(100) not null
+);
+
+INSERT INTO settings (name, value) VALUES ('clock_policy', 'manual');
+
+-- punches recorded with the server clock, and admin edits of recorded punches
+ALTER TABLE jobworkers ADD COLUMN signin_clocked boolean not null default false;
+ALTER TABLE jobworkers ADD COLUMN signout_clocked boolean not null default false;
+ALTER TABLE jobworkers ADD COLUMN signin_edited boolean not null default false;
+ALTER TABLE jobworkers ADD COLUMN signout_edited boolean not null default false;
+ALTER TABLE jobworkers ADD COLUMN orig_signin varchar(100);
+ALTER TABLE jobworkers ADD COLUMN orig_signout varchar(100);
+ALTER TABLE jobworkers ADD COLUMN edited_by integer references users(id);
@@ -0,0 +1,35 @@
+-- Add migration script here
+CREATE TABLE punches (
+    id integer not null primary key autoincrement,
+    job integer not null references jobs(id),
+    worker integer not null references users(id),
+    kind varchar(20) not null default 'work',
+    paid boolean not null default true,
+    punchin varchar(100) not null,
+    punchout varchar(100),
+    clocked_in boolean not null default false,
+    clocked_out boolean not null default false,
+    edited boolean not null default false,
+    orig_in varchar(100),
+    orig_out varchar(100),
+    edited_by integer references users(id)
+);
+
+-- every recorded sign in becomes a single work segment
+INSERT INTO punches (job, worker, punchin, punchout, clocked_in, clocked_out, edited, orig_in, orig_out, edited_by)
+    SELECT job, worker, signin, signout, signin_clocked, signout_clocked,
+        signin_edited or signout_edited,
+        case when signin_edited then orig_signin else signin end,
+        case when signout_edited then orig_signout else signout end,
+        edited_by
+    FROM jobworkers
+    WHERE signin is not null;
+
+-- jobworkers.signin and signout now only hold the first punch in and last punch out
+ALTER TABLE jobworkers DROP COLUMN signin_clocked;
+ALTER TABLE jobworkers DROP COLUMN signout_clocked;
+ALTER TABLE jobworkers DROP COLUMN signin_edited;
+ALTER TABLE jobworkers DROP COLUMN signout_edited;
+ALTER TABLE jobworkers DROP COLUMN orig_signin;
+ALTER TABLE jobworkers DROP COLUMN orig_signout;
+ALTER TABLE jobworkers DROP COLUMN edited_by;
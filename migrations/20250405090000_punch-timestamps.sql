@@ -0,0 +1,24 @@
+-- Add migration script here
+-- punches were times of day on the job's date, now they carry their own date.
+-- a punch out earlier than its punch in was an overnight shift ending the next day
+UPDATE punches SET
+    punchin = (select date(date) from jobs where jobs.id = punches.job) || ' ' || punchin,
+    punchout = (
+        select case when punches.punchout < punches.punchin
+            then date(date, '+1 day') else date(date) end
+        from jobs where jobs.id = punches.job
+    ) || ' ' || punchout,
+    orig_in = (select date(date) from jobs where jobs.id = punches.job) || ' ' || orig_in,
+    orig_out = (
+        select case when punches.orig_out < punches.orig_in
+            then date(date, '+1 day') else date(date) end
+        from jobs where jobs.id = punches.job
+    ) || ' ' || orig_out;
+
+UPDATE jobworkers SET
+    signin = (select date(date) from jobs where jobs.id = jobworkers.job) || ' ' || signin,
+    signout = (
+        select case when jobworkers.signout < jobworkers.signin
+            then date(date, '+1 day') else date(date) end
+        from jobs where jobs.id = jobworkers.job
+    ) || ' ' || signout;
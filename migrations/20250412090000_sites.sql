@@ -0,0 +1,6 @@
+-- Add migration script here
+-- sites without a row here use the server's time zone
+CREATE TABLE sites (
+    name varchar(255) NOT NULL PRIMARY KEY,
+    tz varchar(100) NOT NULL
+);
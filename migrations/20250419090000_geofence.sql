@@ -0,0 +1,25 @@
+-- Add migration script here
+-- sites can have coordinates without a time zone of their own
+CREATE TABLE sites_new (
+    name varchar(255) not null primary key,
+    tz varchar(100),
+    lat real,
+    lon real
+);
+INSERT INTO sites_new (name, tz) SELECT name, tz FROM sites;
+DROP TABLE sites;
+ALTER TABLE sites_new RENAME TO sites;
+
+-- where the worker's browser said they were when they clocked in and out,
+-- and how far that was from the site in meters
+ALTER TABLE punches ADD COLUMN in_lat real;
+ALTER TABLE punches ADD COLUMN in_lon real;
+ALTER TABLE punches ADD COLUMN in_accuracy real;
+ALTER TABLE punches ADD COLUMN in_distance real;
+ALTER TABLE punches ADD COLUMN out_lat real;
+ALTER TABLE punches ADD COLUMN out_lon real;
+ALTER TABLE punches ADD COLUMN out_accuracy real;
+ALTER TABLE punches ADD COLUMN out_distance real;
+ALTER TABLE punches ADD COLUMN offsite boolean not null default false;
+
+INSERT INTO settings (name, value) VALUES ('geofence_radius', '200');
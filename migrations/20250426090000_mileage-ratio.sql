@@ -0,0 +1,4 @@
+-- Add migration script here
+-- entered mileage or driving time more than this many times the straight-line
+-- suggestion is highlighted for review
+INSERT INTO settings (name, value) VALUES ('mileage_ratio', '1.5');
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- short PINs for punching in on a site's kiosk, hashed like passwords
+ALTER TABLE users ADD COLUMN pin_hash varchar(255);
+ALTER TABLE users ADD COLUMN pin_salt varchar(255);
+
+-- shared devices at a site, authorized by an admin with a device token
+CREATE TABLE kiosks (
+    id integer not null primary key autoincrement,
+    name varchar(255) not null,
+    sitename varchar(255) not null,
+    token_hash varchar(255) not null,
+    created_by integer not null references users(id),
+    created_at datetime not null default current_timestamp,
+    last_used datetime,
+    revoked boolean not null default false
+);
+
+-- wrong PINs, so a kiosk can be locked out after too many guesses
+CREATE TABLE kiosk_failures (
+    id integer not null primary key autoincrement,
+    kiosk integer not null references kiosks(id),
+    worker integer not null references users(id),
+    at datetime not null default current_timestamp
+);
@@ -0,0 +1,13 @@
+-- Add migration script here
+-- submissions a phone queued while offline, keyed so a replay is only applied once
+CREATE TABLE offline_submissions (
+    key varchar(100) not null primary key,
+    worker integer not null references users(id),
+    kind varchar(20) not null,
+    captured_at datetime not null,
+    received_at datetime not null default current_timestamp,
+    status varchar(20) not null,
+    error text
+);
+
+ALTER TABLE punches ADD COLUMN offline boolean not null default false;
@@ -0,0 +1,17 @@
+-- Add migration script here
+-- per service code settings, codes without a row here use the defaults
+CREATE TABLE servicecodes (
+    code varchar(255) not null primary key,
+    signature_required boolean not null default false
+);
+
+-- the on-site customer's sign off for a job, in the site's local time
+CREATE TABLE signatures (
+    job integer not null primary key references jobs(id),
+    signer varchar(255) not null,
+    image blob not null,
+    mime varchar(50) not null,
+    signed_at varchar(100) not null,
+    worker integer not null references users(id),
+    offline boolean not null default false
+);
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- photos and documents on a job, or on one worker's assignment when worker is set.
+-- The files themselves are in the attachment store under storage_key.
+CREATE TABLE attachments (
+    id integer not null primary key autoincrement,
+    job integer not null references jobs(id),
+    worker integer references users(id),
+    label varchar(20) not null default 'other',
+    filename varchar(255) not null,
+    mime varchar(100) not null,
+    size integer not null,
+    storage_key varchar(255) not null,
+    thumb_key varchar(255),
+    uploaded_by integer not null references users(id),
+    uploaded_at varchar(100) not null
+);
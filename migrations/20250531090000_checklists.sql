@@ -0,0 +1,25 @@
+-- Add migration script here
+-- the steps a service code's jobs need, retired items stay for old assignments
+CREATE TABLE checklist_items (
+    id integer not null primary key autoincrement,
+    servicecode varchar(255) not null,
+    label varchar(255) not null,
+    required boolean not null default true,
+    position integer not null default 0,
+    retired boolean not null default false
+);
+
+-- each assignment's copy of its service code's checklist, made before it's checked out of
+CREATE TABLE assignment_checks (
+    id integer not null primary key autoincrement,
+    job integer not null references jobs(id),
+    worker integer not null references users(id),
+    item integer not null references checklist_items(id),
+    label varchar(255) not null,
+    required boolean not null,
+    done boolean not null default false,
+    reason text,
+    checked_by integer references users(id),
+    checked_at varchar(100),
+    unique (job, worker, item)
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- what an assignment needs filled in before it can be checked out of, no limit when null
+ALTER TABLE servicecodes ADD COLUMN notes_required boolean not null default false;
+ALTER TABLE servicecodes ADD COLUMN min_miles real;
+ALTER TABLE servicecodes ADD COLUMN max_miles real;
+ALTER TABLE servicecodes ADD COLUMN min_drive_hours real;
+ALTER TABLE servicecodes ADD COLUMN max_drive_hours real;
+ALTER TABLE servicecodes ADD COLUMN min_expenses real;
+ALTER TABLE servicecodes ADD COLUMN max_expenses real;
+ALTER TABLE servicecodes ADD COLUMN min_photos integer not null default 0;
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- bumped on every save from a form, so a save made from a stale copy of the form can be caught
+ALTER TABLE jobs ADD COLUMN version integer not null default 0;
+ALTER TABLE jobworkers ADD COLUMN version integer not null default 0;
+ALTER TABLE users ADD COLUMN version integer not null default 0;
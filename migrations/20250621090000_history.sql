@@ -0,0 +1,23 @@
+-- Add migration script here
+-- every change to a job or one of its assignments, no reference to jobs so
+-- the history outlives deleted jobs
+CREATE TABLE changes (
+    id integer not null primary key autoincrement,
+    job integer not null,
+    -- the assignment's worker, null for changes to the job itself
+    worker integer references users(id),
+    actor integer not null references users(id),
+    action varchar(20) not null,
+    at datetime not null default current_timestamp
+);
+
+-- the fields a change touched, old is null when created and new when deleted
+CREATE TABLE change_fields (
+    id integer not null primary key autoincrement,
+    change integer not null references changes(id),
+    field varchar(100) not null,
+    old text,
+    new text
+);
+
+CREATE INDEX changes_job ON changes (job, worker);
@@ -0,0 +1,23 @@
+-- Add migration script here
+-- what admins did and where from, only ever added to
+CREATE TABLE audit_log (
+    id integer not null primary key autoincrement,
+    actor integer not null references users(id),
+    action varchar(100) not null,
+    target varchar(255) not null,
+    ip varchar(100) not null,
+    user_agent text not null,
+    at datetime not null default current_timestamp
+);
+
+CREATE INDEX audit_log_at ON audit_log (at);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log can only be added to');
+END;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log can only be added to');
+END;
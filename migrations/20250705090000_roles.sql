@@ -0,0 +1,6 @@
+-- Add migration script here
+-- what each user is allowed to do, in place of the admin flag
+ALTER TABLE users ADD COLUMN role text not null default 'worker'
+    check (role in ('admin', 'dispatcher', 'payroll', 'crew_lead', 'worker', 'auditor'));
+UPDATE users SET role = 'admin' WHERE admin;
+ALTER TABLE users DROP COLUMN admin;
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- the worker in charge of a job's crew, who can clock the rest of it in and out
+ALTER TABLE jobworkers ADD COLUMN lead boolean not null default false;
+-- the crew lead who clocked a segment for its worker, null when they did it themselves
+ALTER TABLE punches ADD COLUMN entered_by integer references users(id);
@@ -0,0 +1,15 @@
+-- Add migration script here
+-- named groups of workers, by trade, region or crew, to filter and assign together
+CREATE TABLE teams (
+    id integer not null primary key autoincrement,
+    name varchar(100) not null,
+    -- made crew lead on a job when the whole team is assigned to it
+    lead integer references users(id),
+    unique(name)
+);
+
+CREATE TABLE team_members (
+    team integer not null references teams(id),
+    worker integer not null references users(id),
+    primary key (team, worker)
+);
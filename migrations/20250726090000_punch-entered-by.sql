@@ -0,0 +1,6 @@
+-- Add migration script here
+-- a crew lead can clock a worker in and leave them to clock out themselves, or the other way round,
+-- so who entered each end is kept apart. The old column doesn't say which end it was, so it's kept for both.
+ALTER TABLE punches RENAME COLUMN entered_by TO in_entered_by;
+ALTER TABLE punches ADD COLUMN out_entered_by integer references users(id);
+UPDATE punches SET out_entered_by = in_entered_by WHERE punchout IS NOT NULL;
@@ -1,11 +1,10 @@
+use crate::roles::{can, Allowed};
 use crate::{errors::CustomError, AppEngine, AppState, Job, JobWorker};
-use crate::{get_admin, Backend};
 use axum::{
     extract::{Path, State},
     response::{Html, IntoResponse, Redirect},
     Form,
 };
-use axum_login::AuthSession;
 use axum_template::RenderHtml;
 use git_version::git_version;
 use rust_decimal::prelude::*;
@@ -18,15 +17,14 @@ pub(crate) async fn admin(
     State(AppState {
         pool: _, engine, ..
     }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    Allowed { role, .. }: Allowed<can::Staff>,
 ) -> Result<impl IntoResponse, CustomError> {
-    get_admin(&auth)?;
-
     let data = serde_json::json!({
     "git_ver": git_version!(),
         "admin": true,
         "logged_in": true,
-        "title": "CZ4R"
+        "title": "CZ4R",
+        "can": role.view(),
     });
 
     Ok(RenderHtml("admin.hbs", engine, data))
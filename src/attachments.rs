@@ -0,0 +1,486 @@
+use std::env;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use crate::punches::db_datetime;
+use crate::roles::{can, SelfOr};
+use crate::signatures::check_on_job;
+use crate::sites::job_tz;
+use crate::{errors::CustomError, AppState};
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::{config::Region, primitives::ByteStream, Client};
+use axum::{
+    extract::{multipart::MultipartError, Multipart, State},
+    http::header,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use image::{ImageFormat, ImageReader, Limits};
+use rand::{thread_rng, RngCore};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, Pool, Sqlite, SqliteConnection};
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::{info, warn};
+
+/// The biggest file a worker can upload
+pub const MAX_ATTACHMENT_BYTES: usize = 20 * 1024 * 1024;
+
+/// Thumbnails fit in a square this many pixels wide
+const THUMB_SIZE: u32 = 320;
+
+/// Where attachment files are kept, the database only has their keys
+#[derive(Clone, Debug)]
+pub enum Store {
+    Local(PathBuf),
+    S3 { client: Client, bucket: String },
+}
+
+impl Store {
+    /// ATTACHMENT_BUCKET keeps files in that S3 bucket in AWS_REGION,
+    /// otherwise they go in ATTACHMENT_DIR, or ./attachments
+    pub async fn from_env() -> Store {
+        if let Ok(bucket) = env::var("ATTACHMENT_BUCKET") {
+            let region = env::var("AWS_REGION").expect("AWS_REGION not set");
+            let shared_config = aws_config::defaults(BehaviorVersion::latest())
+                .region(Region::new(region))
+                .load()
+                .await;
+            info!("storing attachments in S3 bucket {bucket}");
+            Store::S3 {
+                client: Client::new(&shared_config),
+                bucket,
+            }
+        } else {
+            let dir = PathBuf::from(env::var("ATTACHMENT_DIR").unwrap_or("attachments".into()));
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .expect("Can't create the attachment directory");
+            info!("storing attachments in {}", dir.display());
+            Store::Local(dir)
+        }
+    }
+
+    fn s3_key(key: &str) -> String {
+        format!("attachments/{key}")
+    }
+
+    pub async fn put(&self, key: &str, bytes: Vec<u8>, mime: &str) -> anyhow::Result<()> {
+        match self {
+            Store::Local(dir) => tokio::fs::write(dir.join(key), bytes).await?,
+            Store::S3 { client, bucket } => {
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(key))
+                    .content_type(mime)
+                    .body(ByteStream::from(bytes))
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Store::Local(dir) => tokio::fs::read(dir.join(key)).await?,
+            Store::S3 { client, bucket } => client
+                .get_object()
+                .bucket(bucket)
+                .key(Self::s3_key(key))
+                .send()
+                .await?
+                .body
+                .collect()
+                .await?
+                .to_vec(),
+        })
+    }
+
+    pub async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self {
+            Store::Local(dir) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            Store::S3 { client, bucket } => {
+                client
+                    .delete_object()
+                    .bucket(bucket)
+                    .key(Self::s3_key(key))
+                    .send()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the file really is from its first bytes, whatever the phone said it was.
+/// Images come with their format so they can get a thumbnail.
+fn sniff(bytes: &[u8]) -> Option<(&'static str, Option<ImageFormat>)> {
+    if bytes.starts_with(b"%PDF-") {
+        return Some(("application/pdf", None));
+    }
+    match image::guess_format(bytes).ok()? {
+        format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP) => {
+            Some((format.to_mime_type(), Some(format)))
+        }
+        _ => None,
+    }
+}
+
+fn thumbnail(bytes: &[u8], format: ImageFormat) -> anyhow::Result<Vec<u8>> {
+    // phone photos are big, but nothing a camera takes is this big
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(16_000);
+    limits.max_image_height = Some(16_000);
+    limits.max_alloc = Some(512 * 1024 * 1024);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let thumb = reader
+        .decode()?
+        .thumbnail(THUMB_SIZE, THUMB_SIZE)
+        .into_rgb8();
+
+    let mut out = Cursor::new(Vec::new());
+    thumb.write_to(&mut out, ImageFormat::Jpeg)?;
+    Ok(out.into_inner())
+}
+
+/// A broken upload won't go through on a retry either
+fn unreadable(e: MultipartError) -> CustomError {
+    CustomError::Validation(format!("The upload couldn't be read: {e}"))
+}
+
+fn new_key(job: i64) -> String {
+    let mut id = [0u8; 16];
+    thread_rng().fill_bytes(&mut id);
+    format!("{job}-{}", URL_SAFE_NO_PAD.encode(id))
+}
+
+fn human_size(bytes: i64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024. * 1024.)),
+        b if b >= 1024 => format!("{:.0} KB", b as f64 / 1024.),
+        b => format!("{b} B"),
+    }
+}
+
+/// A job's attachments for showing, just the whole job's and `worker`'s when
+/// there is one, or every assignment's when there isn't
+pub(crate) async fn job_attachments(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: Option<i64>,
+) -> Result<Vec<Value>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select attachments.id, attachments.worker, attachments.label, attachments.filename,
+            attachments.mime, attachments.size, attachments.thumb_key, attachments.uploaded_at,
+            attachments.uploaded_by as uploader, uploader.name as uploaded_by, assignee.name as "assignee?"
+            from attachments
+            inner join users uploader on attachments.uploaded_by = uploader.id
+            left join users assignee on attachments.worker = assignee.id
+        where attachments.job = $1
+            and ($2 is null or attachments.worker is null or attachments.worker = $2)
+        order by attachments.uploaded_at asc, attachments.id asc;
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|a| {
+        json!({
+            "id": a.id,
+            "label": a.label,
+            "filename": a.filename,
+            "image": a.mime.starts_with("image/"),
+            "thumb": a.thumb_key.is_some(),
+            "size": human_size(a.size),
+            "assignee": a.assignee,
+            "uploader": a.uploader,
+            "uploaded_by": a.uploaded_by,
+            "uploaded_at": db_datetime(&Some(a.uploaded_at))
+                .and_then(|t| t.format(format_description!("[month repr:short] [day] [hour]:[minute]")).ok()),
+        })
+    })
+    .collect())
+}
+
+/// Removes a job's files from the store along with their rows
+/// Removes a job's attachments, or only those on one worker's time sheet,
+/// answering with the files to delete once that's been committed
+pub(crate) async fn detach_attachments(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: Option<i64>,
+) -> Result<Vec<String>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        delete from attachments where job = $1 and ($2 is null or worker = $2)
+            returning storage_key, thumb_key;
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .flat_map(|k| std::iter::once(k.storage_key).chain(k.thumb_key))
+    .collect())
+}
+
+/// Deletes the files of removed attachments. One that can't be deleted is
+/// only left behind, its attachment is already gone.
+pub(crate) async fn delete_files(store: &Store, keys: Vec<String>) {
+    for key in keys {
+        if let Err(e) = store.delete(&key).await {
+            warn!("couldn't delete attachment file {key}: {e}");
+        }
+    }
+}
+
+pub(crate) async fn upload(
+    State(AppState {
+        pool, attachments, ..
+    }): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut job = None;
+    let mut worker = None;
+    let mut assignment = false;
+    let mut label = String::new();
+    let mut file = None;
+
+    while let Some(field) = multipart.next_field().await.map_err(unreadable)? {
+        match field.name() {
+            Some("JobId") => job = field.text().await.map_err(unreadable)?.parse::<i64>().ok(),
+            Some("WorkerId") => {
+                worker = field.text().await.map_err(unreadable)?.parse::<i64>().ok()
+            }
+            Some("scope") => assignment = field.text().await.map_err(unreadable)? == "assignment",
+            Some("label") => label = field.text().await.map_err(unreadable)?,
+            Some("file") => {
+                let name = field.file_name().unwrap_or("upload").to_string();
+                file = Some((name, field.bytes().await.map_err(unreadable)?));
+            }
+            _ => {}
+        }
+    }
+
+    let (Some(job), Some(worker)) = (job, worker) else {
+        return Err(CustomError::Validation(
+            "That upload wasn't for a job".to_string(),
+        ));
+    };
+    check_on_job(&pool, &user, job, Some(worker)).await?;
+    let SelfOr {
+        id: my_id,
+        name: my_name,
+        role,
+        ..
+    } = user;
+
+    let (filename, bytes) = file
+        .filter(|(_, bytes)| !bytes.is_empty())
+        .ok_or_else(|| CustomError::Validation("Pick a photo or a file to attach".to_string()))?;
+    if bytes.len() > MAX_ATTACHMENT_BYTES {
+        return Err(CustomError::Validation(format!(
+            "{filename} is too big, attachments can be up to {}",
+            human_size(MAX_ATTACHMENT_BYTES as i64)
+        )));
+    }
+    let (mime, format) = sniff(&bytes).ok_or_else(|| {
+        CustomError::Validation(format!(
+            "{filename} isn't a photo or a PDF, only JPEG, PNG, GIF, WebP and PDF files can be attached"
+        ))
+    })?;
+    let label = match label.as_str() {
+        "before" | "after" => label.as_str(),
+        _ => "other",
+    };
+
+    let thumb = match format {
+        Some(format) => {
+            let bytes = bytes.clone();
+            Some(
+                tokio::task::spawn_blocking(move || thumbnail(&bytes, format))
+                    .await?
+                    .map_err(|e| {
+                        CustomError::Validation(format!("Couldn't read the photo {filename}: {e}"))
+                    })?,
+            )
+        }
+        None => None,
+    };
+
+    let storage_key = new_key(job);
+    attachments.put(&storage_key, bytes.to_vec(), mime).await?;
+    let thumb_key = match thumb {
+        Some(thumb) => {
+            let key = format!("{storage_key}-thumb.jpg");
+            attachments.put(&key, thumb, "image/jpeg").await?;
+            Some(key)
+        }
+        None => None,
+    };
+
+    let local = OffsetDateTime::now_utc().to_timezone(job_tz(&pool, job).await?);
+    let stamp = PrimitiveDateTime::new(local.date(), local.time()).replace_nanosecond(0)?;
+    let assignee = assignment.then_some(worker);
+    let size = bytes.len() as i64;
+
+    let id = query!(
+        r#"
+        insert into attachments
+            (job, worker, label, filename, mime, size, storage_key, thumb_key, uploaded_by, uploaded_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            returning id;
+        "#,
+        job,
+        assignee,
+        label,
+        filename,
+        mime,
+        size,
+        storage_key,
+        thumb_key,
+        my_id,
+        stamp
+    )
+    .fetch_one(&pool)
+    .await?
+    .id;
+
+    info!(
+        "{} {my_name} (id {my_id}) attached {filename} ({mime}, {size} bytes) as attachment {id} to {}job {job}",
+        role.as_str(),
+        if assignment {
+            format!("user {worker}'s assignment on ")
+        } else {
+            String::new()
+        }
+    );
+
+    Ok(Redirect::to(&format!(
+        "/checkinout?id={job}&worker={worker}"
+    )))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AttachmentForm {
+    id: i64,
+    thumb: Option<bool>,
+}
+
+/// Something safe to put in a header, phones name files all sorts of things
+fn header_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || ".-_ ".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub(crate) async fn attachment(
+    State(AppState {
+        pool, attachments, ..
+    }): State<AppState>,
+    user: SelfOr<can::ViewAllJobs>,
+    Form(form): Form<AttachmentForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let a = query!(
+        "select job, filename, mime, storage_key, thumb_key from attachments where id = $1;",
+        form.id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| CustomError::NotFound(format!("There is no attachment {}", form.id)))?;
+
+    check_on_job(&pool, &user, a.job, None).await?;
+
+    let (key, mime) = match (form.thumb.unwrap_or(false), a.thumb_key) {
+        (true, Some(thumb)) => (thumb, "image/jpeg".to_string()),
+        _ => (a.storage_key, a.mime),
+    };
+    let bytes = attachments.get(&key).await?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"{}\"", header_filename(&a.filename)),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
+        ],
+        bytes,
+    ))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteAttachmentForm {
+    id: i64,
+    /// Back to this worker's time sheet, or the job edit page without one
+    worker: Option<i64>,
+}
+
+/// Admins can remove any attachment, workers only the ones they uploaded
+pub(crate) async fn delete_attachment(
+    State(AppState {
+        pool, attachments, ..
+    }): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Form(form): Form<DeleteAttachmentForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let a = query!(
+        "select job, filename, storage_key, thumb_key, uploaded_by from attachments where id = $1;",
+        form.id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| CustomError::NotFound(format!("There is no attachment {}", form.id)))?;
+
+    if user.check(a.uploaded_by).is_err() {
+        return Err(CustomError::Forbidden(
+            "You can only remove attachments you uploaded".to_string(),
+        ));
+    }
+
+    query!("delete from attachments where id = $1;", form.id)
+        .execute(&pool)
+        .await?;
+    for key in std::iter::once(a.storage_key).chain(a.thumb_key) {
+        if let Err(e) = attachments.delete(&key).await {
+            warn!("couldn't delete attachment file {key}: {e}");
+        }
+    }
+
+    info!(
+        "{} {} (id {}) removed attachment {} ({}) from job {}",
+        user.role.as_str(),
+        user.name,
+        user.id,
+        form.id,
+        a.filename,
+        a.job
+    );
+
+    Ok(Redirect::to(&match form.worker {
+        Some(worker) => format!("/checkinout?id={}&worker={worker}", a.job),
+        None => format!("/jobedit?id={}", a.job),
+    }))
+}
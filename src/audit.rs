@@ -0,0 +1,288 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use crate::roles::{can, Allowed};
+use crate::sites::instant;
+use crate::Backend;
+use crate::{empty_string_as_none, errors::CustomError, AppState, TZ};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, State},
+    http::{header, request::Parts},
+    response::IntoResponse,
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, types::time::Date, Pool, Sqlite, SqliteConnection};
+use time::{macros::format_description, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+use time_tz::OffsetDateTimeExt;
+
+/// Where a request came from, for the audit log
+pub(crate) struct Requester {
+    ip: String,
+    user_agent: String,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Requester {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        // a proxy in front says who it's forwarding for, but so could anyone
+        let ip = match header(header::FORWARDED.as_str()).or(header("x-forwarded-for")) {
+            Some(forwarded) => format!("{peer} (forwarded for {forwarded})"),
+            None => peer,
+        };
+
+        Ok(Self {
+            ip,
+            user_agent: header(header::USER_AGENT.as_str()).unwrap_or_default(),
+        })
+    }
+}
+
+/// Adds an admin's action to the audit log, which can't be changed afterwards.
+/// It goes in the action's own transaction, so neither happens without the other.
+pub(crate) async fn audit(
+    conn: &mut SqliteConnection,
+    from: &Requester,
+    actor: i64,
+    action: &str,
+    target: &str,
+) -> Result<(), sqlx::Error> {
+    query!(
+        r#"
+        insert into audit_log (actor, action, target, ip, user_agent)
+            values ($1, $2, $3, $4, $5);
+        "#,
+        actor,
+        action,
+        target,
+        from.ip,
+        from.user_agent
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct AuditFilter {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    q: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    actor: Option<i64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    action: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    from: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    to: Option<String>,
+}
+
+struct Entry {
+    at: OffsetDateTime,
+    actor: String,
+    action: String,
+    target: String,
+    ip: String,
+    user_agent: String,
+}
+
+/// The start of a day in the server's time zone
+fn day_start(day: &Option<String>) -> Result<Option<OffsetDateTime>, CustomError> {
+    let Some(day) = day else {
+        return Ok(None);
+    };
+    let date = Date::parse(day, format_description!("[year]-[month]-[day]"))
+        .map_err(|_| CustomError::Validation(format!("\"{day}\" isn't a date")))?;
+    Ok(Some(instant(
+        PrimitiveDateTime::new(date, Time::MIDNIGHT),
+        TZ.get().unwrap(),
+    )))
+}
+
+/// The entries matching the filter, newest first
+async fn entries(
+    pool: &Pool<Sqlite>,
+    filter: &AuditFilter,
+    limit: i64,
+) -> Result<Vec<Entry>, CustomError> {
+    // stored as UTC text, which datetime() turns the bounds into
+    let from = day_start(&filter.from)?;
+    // up to the end of the last day
+    let to = day_start(&filter.to)?.map(|t| t + Duration::days(1));
+    let q = filter.q.as_deref().map(str::trim);
+
+    Ok(query!(
+        r#"
+        select audit_log.at, users.name as actor, audit_log.action, audit_log.target,
+            audit_log.ip, audit_log.user_agent
+            from audit_log inner join users on audit_log.actor = users.id
+        where ($1 is null or audit_log.actor = $1)
+            and ($2 is null or audit_log.action = $2)
+            and ($3 is null or audit_log.at >= datetime($3))
+            and ($4 is null or audit_log.at < datetime($4))
+            and ($5 is null
+                or instr(lower(users.name), lower($5))
+                or instr(lower(audit_log.action), lower($5))
+                or instr(lower(audit_log.target), lower($5))
+                or instr(lower(audit_log.ip), lower($5))
+                or instr(lower(audit_log.user_agent), lower($5)))
+        order by audit_log.id desc
+        limit $6;
+        "#,
+        filter.actor,
+        filter.action,
+        from,
+        to,
+        q,
+        limit
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| Entry {
+        at: r.at,
+        actor: r.actor,
+        action: r.action,
+        target: r.target,
+        ip: r.ip,
+        user_agent: r.user_agent,
+    })
+    .collect())
+}
+
+fn show_at(at: OffsetDateTime) -> String {
+    at.to_timezone(*TZ.get().unwrap())
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]"
+        ))
+        .unwrap_or_default()
+}
+
+pub(crate) async fn auditpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ViewAuditLog>,
+    Form(filter): Form<AuditFilter>,
+) -> Result<impl IntoResponse, CustomError> {
+    let entries = entries(&pool, &filter, 500).await?;
+
+    let actors = query!(
+        r#"
+        select distinct users.id, users.name from audit_log
+            inner join users on audit_log.actor = users.id
+        order by users.name;
+        "#
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| json!({"id": r.id, "name": r.name, "selected": filter.actor == Some(r.id)}))
+    .collect::<Vec<_>>();
+
+    let actions = query!("select distinct action from audit_log order by action;")
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|r| json!({"name": r.action, "selected": filter.action.as_ref() == Some(&r.action)}))
+        .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Audit Log",
+        "admin": true,
+        "logged_in": true,
+        "q": filter.q.clone().unwrap_or_default(),
+        "from": filter.from.clone().unwrap_or_default(),
+        "to": filter.to.clone().unwrap_or_default(),
+        "actors": actors,
+        "actions": actions,
+        "entries": entries.into_iter().map(|e| json!({
+            "at": show_at(e.at),
+            "actor": e.actor,
+            "action": e.action,
+            "target": e.target,
+            "ip": e.ip,
+            "user_agent": e.user_agent,
+        })).collect::<Vec<_>>(),
+    });
+
+    Ok(RenderHtml("audit.hbs", engine, data))
+}
+
+/// Quotes a CSV field when it needs it
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Every entry matching the filter, as a spreadsheet
+pub(crate) async fn export_audit(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ViewAuditLog>,
+    from: Requester,
+    Form(filter): Form<AuditFilter>,
+) -> Result<impl IntoResponse, CustomError> {
+    let entries = entries(&pool, &filter, i64::MAX).await?;
+
+    let mut csv = String::from("time,admin,action,target,ip,user agent\r\n");
+    for e in &entries {
+        csv += &[
+            show_at(e.at).as_str(),
+            &e.actor,
+            &e.action,
+            &e.target,
+            &e.ip,
+            &e.user_agent,
+        ]
+        .map(csv_field)
+        .join(",");
+        csv += "\r\n";
+    }
+
+    audit(
+        &mut *pool.acquire().await?,
+        &from,
+        my_id,
+        "exported audit log",
+        &format!("{} entries", entries.len()),
+    )
+    .await?;
+    tracing::info!(
+        "admin {my_name} (id {my_id}) exported {} audit log entries",
+        entries.len()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit-log.csv\"",
+            ),
+        ],
+        csv,
+    ))
+}
@@ -0,0 +1,179 @@
+use std::iter::successors;
+
+use crate::roles::{can, SelfOr};
+use crate::teams::team_options;
+use crate::{empty_string_as_none, errors::CustomError, now, AppState};
+use axum::{extract::State, response::IntoResponse, Form};
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query, types::time::Date};
+use time::{macros::format_description, Duration};
+
+#[derive(Deserialize)]
+pub(crate) struct CalendarForm {
+    month: Option<String>,
+    /// Just the jobs and time off of this team's members
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    team: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct CalendarJob {
+    id: i64,
+    sitename: String,
+    workers: String,
+}
+
+#[derive(Serialize)]
+struct CalendarDay {
+    date: String,
+    day: u8,
+    in_month: bool,
+    today: bool,
+    jobs: Vec<CalendarJob>,
+    off: Vec<String>,
+}
+
+pub(crate) async fn calendarpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    user: SelfOr<can::ViewAllJobs>,
+    Form(form): Form<CalendarForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, role) = (user.id, user.role);
+    let everyone = user.granted();
+    let team = form.team.filter(|_| everyone);
+
+    let today = now().date();
+    let first = match form.month.as_deref().map(str::trim) {
+        None | Some("") => today.replace_day(1)?,
+        Some(m) => Date::parse(
+            &format!("{m}-01"),
+            format_description!("[year]-[month]-[day]"),
+        )
+        .map_err(|_| CustomError::Validation(format!("\"{m}\" isn't a month")))?,
+    };
+
+    // whole weeks, sunday to saturday, and the days either side of the month
+    // for the links to the ones before and after
+    let last = first.replace_day(first.month().length(first.year()))?;
+    let (Some(start), Some(end), Some(before), Some(after)) = (
+        first.checked_sub(Duration::days(
+            first.weekday().number_days_from_sunday() as i64
+        )),
+        last.checked_add(Duration::days(
+            6 - last.weekday().number_days_from_sunday() as i64,
+        )),
+        first.previous_day(),
+        last.next_day(),
+    ) else {
+        return Err(CustomError::Validation(format!(
+            "{} is too far off to show",
+            first.year()
+        )));
+    };
+
+    let jobs = query!(
+        r#"
+        select jobs.id, jobs.sitename, jobs.date,
+            coalesce(group_concat(users.name, ', '), '') as "workers!: String"
+            from jobs left join jobworkers
+            on jobs.id = jobworkers.job
+            and ($5 is null or jobworkers.worker in (
+                select worker from team_members where team = $5
+            ))
+            left join users
+            on jobworkers.worker = users.id
+        where jobs.date >= $1 and jobs.date <= $2
+            and ($3 or exists (
+                select * from jobworkers as mine
+                where mine.job = jobs.id and mine.worker = $4
+            ))
+        group by jobs.id
+        having $5 is null or count(users.id) > 0
+        order by jobs.date asc, jobs.starttime asc;
+        "#,
+        start,
+        end,
+        everyone,
+        my_id,
+        team
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let timeoff = query!(
+        r#"
+        select users.name, timeoff.start_date, timeoff.end_date
+            from timeoff inner join users
+            on timeoff.worker = users.id
+        where timeoff.status = 'approved'
+            and timeoff.start_date <= $2 and timeoff.end_date >= $1
+            and ($3 or timeoff.worker = $4)
+            and ($5 is null or timeoff.worker in (
+                select worker from team_members where team = $5
+            ));
+        "#,
+        start,
+        end,
+        everyone,
+        my_id,
+        team
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let weeks = successors(Some(start), |d| d.next_day())
+        .take_while(|d| *d <= end)
+        .map(|date| CalendarDay {
+            date: date.to_string(),
+            day: date.day(),
+            in_month: date.month() == first.month(),
+            today: date == today,
+            jobs: jobs
+                .iter()
+                .filter(|j| j.date == date)
+                .map(|j| CalendarJob {
+                    id: j.id,
+                    sitename: j.sitename.clone(),
+                    workers: j.workers.clone(),
+                })
+                .collect(),
+            off: timeoff
+                .iter()
+                .filter(|t| t.start_date <= date && t.end_date >= date)
+                .map(|t| t.name.clone())
+                .collect(),
+        })
+        .chunks(7)
+        .into_iter()
+        .map(|week| week.collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let month_fmt = format_description!("[year]-[month]");
+    let prev = before.format(month_fmt)?;
+    let next = after.format(month_fmt)?;
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Calendar",
+        "admin": role.is_staff(),
+        "logged_in": true,
+        "month": first.format(month_fmt)?,
+        "month_name": format!("{} {}", first.month(), first.year()),
+        "prev": prev,
+        "next": next,
+        "weeks": weeks,
+        "me": my_id,
+        "team": team,
+        "teams": if everyone {
+            team_options(&pool, team).await?
+        } else {
+            vec![]
+        },
+    });
+
+    Ok(RenderHtml("calendar.hbs", engine, data))
+}
@@ -2,14 +2,15 @@
 
 use crate::errors::CustomError;
 use crate::get_user;
+use crate::validation::{rerender, FieldErrors};
 use crate::AppState;
 use crate::Backend;
-use anyhow::anyhow;
 use axum::extract::Path;
 use axum::extract::State;
 use axum::response::Html;
 use axum::response::IntoResponse;
 use axum::response::Redirect;
+use axum::response::Response;
 use axum::Form;
 use axum_login::AuthSession;
 use axum_template::RenderHtml;
@@ -19,7 +20,7 @@ use password_hash::SaltString;
 use rand::thread_rng;
 use scrypt::Scrypt;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use sqlx::query;
 use sqlx::Pool;
 use tracing::*;
@@ -40,19 +41,33 @@ pub(crate) async fn change_pw_page(
     let id = if let Some(id) = form.id {
         id
     } else {
-        return Err(CustomError(anyhow!("No ID selected.")));
+        return Err(CustomError::Validation("No ID selected.".to_string()));
     };
 
-    let data = json!({
+    let mut errors = FieldErrors::default();
+    if form.no_match == Some(true) {
+        errors.add("password2", "Passwords do not match");
+    }
+
+    Ok(RenderHtml(
+        "changepw.hbs",
+        engine,
+        change_pw_data(id, &errors),
+    ))
+}
+
+/// The inputs on the change password form, by name
+const PW_FIELDS: [&str; 2] = ["password1", "password2"];
+
+fn change_pw_data(id: i64, errors: &FieldErrors) -> Value {
+    json!({
     "git_ver": git_version!(),
         "title": "CZ4R Login",
         "admin": false,
         "logged_in": true,
-        "failure": form.no_match == Some(true),
+        "errors": errors.view(&PW_FIELDS),
         "chg_id": id
-    });
-
-    Ok(RenderHtml("changepw.hbs", engine, data))
+    })
 }
 
 #[derive(Deserialize)]
@@ -63,10 +78,10 @@ pub(crate) struct ChangePwForm {
 }
 
 pub(crate) async fn change_pw(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState { pool, engine, .. }): State<AppState>,
     mut _auth: AuthSession<Backend>,
     Form(form): Form<ChangePwForm>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<Response, CustomError> {
     let must_change = query!(
         r#"
     select (must_change_pw) from users 
@@ -84,18 +99,26 @@ pub(crate) async fn change_pw(
             "user id {} attempted to change their password when not allowed to",
             form.id
         );
-        return Err(CustomError(anyhow!(
+        return Err(CustomError::Forbidden(format!(
             "User {} cannot change their password right now. Nice try.",
             form.id
         )));
     }
 
-    if form.password1 != form.password2 {
+    // the passwords aren't sent back, they have to be typed again
+    let mut errors = FieldErrors::default();
+    if form.password1.is_empty() {
+        errors.add("password1", "Choose a password");
+    } else if form.password1 != form.password2 {
         debug!("user id {} put in the wrong password", form.id);
-        return Ok(Redirect::to(&format!(
-            "/change-pw?id={}&no_match=true",
-            form.id
-        )));
+        errors.add("password2", "Passwords do not match");
+    }
+    if !errors.is_empty() {
+        return Ok(rerender(
+            "changepw.hbs",
+            engine,
+            change_pw_data(form.id, &errors),
+        ));
     }
 
     let salt = SaltString::generate(&mut thread_rng());
@@ -124,5 +147,5 @@ pub(crate) async fn change_pw(
 
     info!("user id {} changed their password", form.id);
 
-    Ok(Redirect::to("/loginpage"))
+    Ok(Redirect::to("/loginpage").into_response())
 }
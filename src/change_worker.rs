@@ -1,24 +1,30 @@
 //Name=&Address=&Phone=&Email=&Hourly=&Mileage=&Drivetime=
 
 use super::Worker;
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed, Role};
+use crate::validation::{rerender, FieldErrors};
+use crate::versions::{Field, Stale};
+use crate::workeredit::worker_page;
 use crate::AppState;
 use crate::Backend;
-use anyhow::anyhow;
 use axum::debug_handler;
 use axum::extract::Path;
 use axum::extract::State;
 use axum::response::IntoResponse;
 use axum::response::Redirect;
+use axum::response::Response;
 use axum::Form;
 use axum_login::AuthSession;
 use axum_template::engine;
 use rust_decimal::prelude::*;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::query;
 use sqlx::query_as;
 use sqlx::Pool;
+use sqlx::Sqlite;
 
 #[derive(Deserialize)]
 pub(crate) struct WorkerChangeForm {
@@ -30,56 +36,138 @@ pub(crate) struct WorkerChangeForm {
     Mileage: String,
     Drivetime: String,
     Flatrate: String,
-    Admin: Option<String>,
+    Role: Role,
     id: i64,
+    version: Option<i64>,
+}
+
+fn dollars(cents: i64) -> String {
+    format!("{:.2}", cents as f64 / 100.)
+}
+
+/// What's saved on a worker now, next to what was sent from an older copy of
+/// the form
+async fn stale_worker(
+    pool: &Pool<Sqlite>,
+    workerdata: &WorkerChangeForm,
+) -> Result<Stale, CustomError> {
+    let saved = query_as!(Worker, "select * from users where id = $1;", workerdata.id)
+        .fetch_one(pool)
+        .await?;
+    let role = saved.role();
+
+    Ok(Stale {
+        what: saved.name.clone(),
+        action: "/admin/api/v1/change-worker",
+        back: format!("/admin/worker-edit?worker={}", workerdata.id),
+        fields: vec![
+            Field::text("Name", "Name", &workerdata.Name, saved.name),
+            Field::text("Address", "Address", &workerdata.Address, saved.address),
+            Field::text("Phone", "Phone", &workerdata.Phone, saved.phone),
+            Field::text("Email", "Email", &workerdata.Email, saved.email),
+            Field::text(
+                "Hourly",
+                "Hourly rate $",
+                &workerdata.Hourly,
+                dollars(saved.rate_hourly_cents),
+            ),
+            Field::text(
+                "Mileage",
+                "Mileage rate $",
+                &workerdata.Mileage,
+                dollars(saved.rate_mileage_cents),
+            ),
+            Field::text(
+                "Drivetime",
+                "Drive time rate $",
+                &workerdata.Drivetime,
+                dollars(saved.rate_drive_hourly_cents),
+            ),
+            Field::text(
+                "Flatrate",
+                "Flat rate $",
+                &workerdata.Flatrate,
+                dollars(saved.flat_rate_cents),
+            ),
+            Field::text("Role", "Role", workerdata.Role.as_str(), role.as_str())
+                .shown(workerdata.Role.label(), role.label()),
+        ],
+        carried: vec![
+            ("id", workerdata.id.to_string()),
+            ("version", saved.version.to_string()),
+        ],
+    })
 }
 
 // Result<impl IntoResponse, impl IntoResponse>
 
 pub(crate) async fn change_worker(
-    State(AppState { pool, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    State(AppState { pool, engine, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
+    from: Requester,
     Form(workerdata): Form<WorkerChangeForm>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let (my_id, my_name) = get_admin(&auth)?;
-    let hourly = Decimal::from_str_exact(&workerdata.Hourly)? * Decimal::ONE_HUNDRED;
-
-    let mileage = Decimal::from_str_exact(&workerdata.Mileage)? * Decimal::ONE_HUNDRED;
+) -> Result<Response, CustomError> {
+    let role = workerdata.Role.as_str();
 
-    let drivetime = Decimal::from_str_exact(&workerdata.Drivetime)? * Decimal::ONE_HUNDRED;
-    let flatrate = Decimal::from_str_exact(&workerdata.Flatrate)? * Decimal::ONE_HUNDRED;
+    let mut errors = FieldErrors::default();
+    if workerdata.id == my_id && workerdata.Role != Role::Admin {
+        errors.add("Role", "You can't take away your own admin role");
+    }
+    errors.required("Name", "The name", &workerdata.Name);
+    errors.email("Email", &workerdata.Email);
+    let hourly = errors.cents("Hourly", &workerdata.Hourly);
+    let mileage = errors.cents("Mileage", &workerdata.Mileage);
+    let drivetime = errors.cents("Drivetime", &workerdata.Drivetime);
+    let flatrate = errors.cents("Flatrate", &workerdata.Flatrate);
 
-    let admin = match workerdata.Admin.as_deref() {
-        Some("on" | "true" | "yes") => true,
-        Some("off" | "false" | "no") | None => false,
-        _ => {
-            return Err(CustomError(anyhow!(
-                "Client didn't return a boolean string"
-            )))
-        }
-    };
+    if !errors.is_empty() {
+        let typed = json!({
+            "name": workerdata.Name,
+            "address": workerdata.Address,
+            "phone": workerdata.Phone,
+            "email": workerdata.Email,
+            "role": workerdata.Role,
+            "roles": Role::options(workerdata.Role),
+            "rate_hourly_cents": workerdata.Hourly,
+            "rate_mileage_cents": workerdata.Mileage,
+            "rate_drive_hourly_cents": workerdata.Drivetime,
+            "flat_rate_cents": workerdata.Flatrate,
+            "version": workerdata.version,
+        });
+        let data = worker_page(
+            &pool,
+            my_id,
+            Some(workerdata.id),
+            false,
+            Some((typed, &errors)),
+        )
+        .await?;
+        return Ok(rerender("workeredit.hbs", engine, data));
+    }
 
-    let hourly = hourly.to_i32().unwrap();
-    let mileage = mileage.to_i32().unwrap();
-    let drivetime = drivetime.to_i32().unwrap();
-    let flatrate = flatrate.to_i32().unwrap();
+    let mut tx = pool.begin().await?;
 
-    query!(
+    let updated = query!(
         r#"update users 
             set 
             name = $1, 
-            admin = $2, 
+            role = $2, 
             address = $3, 
             phone = $4, 
             email = $5, 
             rate_hourly_cents = $6, 
             rate_mileage_cents = $7, 
             rate_drive_hourly_cents = $8,
-            flat_rate_cents = $9
-            where id = $10; 
+            flat_rate_cents = $9,
+            version = version + 1
+            where id = $10 and version = $11; 
         "#,
         workerdata.Name,
-        admin,
+        role,
         workerdata.Address,
         workerdata.Phone,
         workerdata.Email,
@@ -87,17 +175,42 @@ pub(crate) async fn change_worker(
         mileage,
         drivetime,
         flatrate,
-        workerdata.id
+        workerdata.id,
+        workerdata.version
     )
-    .execute(&pool)
-    .await?;
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if updated == 0 {
+        drop(tx);
+        tracing::info!(
+            "admin {my_name} (id {my_id}) tried to save user {} from a stale copy of the form",
+            workerdata.id
+        );
+        return Ok(stale_worker(&pool, &workerdata).await?.render(engine, true));
+    }
 
-    tracing::info!("admin {} (id {}) modified user {} as follows:\nname: {}\nadmin: {}\naddress: {}\nphone number: {}\nemail address: {}\nhourly rate (cents): {}\ndriving milage rate (cents): {}\ndriving hourly rate (cents): {}\nflat rate worker: {}",
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "changed worker",
+        &format!(
+            "user {} ({}), {}",
+            workerdata.id,
+            workerdata.Name,
+            workerdata.Role.label()
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("admin {} (id {}) modified user {} as follows:\nname: {}\nrole: {}\naddress: {}\nphone number: {}\nemail address: {}\nhourly rate (cents): {}\ndriving milage rate (cents): {}\ndriving hourly rate (cents): {}\nflat rate worker: {}",
         my_id,
         my_name,
         workerdata.id,
         workerdata.Name,
-        admin,
+        role,
         workerdata.Address,
         workerdata.Phone,
         workerdata.Email,
@@ -107,7 +220,8 @@ pub(crate) async fn change_worker(
         flatrate,
     );
 
-    Ok(Redirect::to(
-        format!("/admin/worker-edit?worker={}", workerdata.id).as_str(),
-    ))
+    Ok(
+        Redirect::to(format!("/admin/worker-edit?worker={}", workerdata.id).as_str())
+            .into_response(),
+    )
 }
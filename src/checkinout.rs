@@ -1,47 +1,74 @@
-use crate::{errors::CustomError, AppState, Job, JobWorker};
-use crate::{get_user, Backend};
-use anyhow::anyhow;
-use axum::http::StatusCode;
+use crate::attachments::job_attachments;
+use crate::checklists::{assignment_checklist, instantiate};
+use crate::dispatch::publish;
+use crate::history::{crew_snapshots, record};
+use crate::mileage::suggested_travel;
+use crate::punches::{
+    job_punches, leads_crew, paid_hours, worker_punches, Punch, PunchKind, PUNCH_FIELDS,
+};
+use crate::roles::{can, Permission, SelfOr};
+use crate::servicecodes::{job_rules, photo_count, saved_completion, Completion, FIELDS};
+use crate::settings::{clock_policy, mileage_ratio, ClockPolicy};
+use crate::signatures::{job_signature, Signature};
+use crate::sites::job_tz;
+use crate::validation::{rerender, FieldErrors};
+use crate::versions::{Field, Stale};
+use crate::{errors::CustomError, now_in, AppState, Job, JobWorker};
+use axum::http::{HeaderMap, StatusCode};
 use axum::{
     extract::{Path, State},
-    response::{Html, IntoResponse, Redirect},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
-use axum_login::AuthSession;
 use axum_template::RenderHtml;
 use git_version::git_version;
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
 use serde::Deserialize;
-use serde_json::json;
-use sqlx::{query, query_as, Pool};
-use time::format_description::well_known::Iso8601;
-use time::{format_description, macros::format_description, Time};
+use serde_json::{json, Value};
+use sqlx::{query, query_as, Pool, Sqlite};
 use tracing::*;
 
 #[derive(Deserialize)]
 pub(crate) struct CheckInOutPage {
-    id: i64,
-    worker: i64,
+    pub id: i64,
+    pub worker: i64,
 }
 
 pub(crate) async fn checkinoutpage(
     State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    user: SelfOr<can::ViewAllJobs>,
     Form(form): Form<CheckInOutPage>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let (my_id, my_name, admin) = get_user(&auth)?;
+) -> Result<impl IntoResponse, CustomError> {
+    let data = time_sheet_page(&pool, &user, &form, None).await?;
+    Ok(RenderHtml("checkinout.hbs", engine, data))
+}
+
+/// The time sheet page. When a plain post didn't go through, `submitted` is
+/// shown in place of what's saved, with its errors.
+pub(crate) async fn time_sheet_page(
+    pool: &Pool<Sqlite>,
+    user: &SelfOr<can::ViewAllJobs>,
+    form: &CheckInOutPage,
+    submitted: Option<(&CheckInOutForm, &FieldErrors)>,
+) -> Result<Value, CustomError> {
+    let (my_id, my_name, role) = (user.id, user.name.as_str(), user.role);
 
     let worker = form.worker;
 
-    if !admin && worker != my_id {
+    // crew leads get the clock buttons on their crew's sheets and nothing else
+    let on_behalf = user.check(worker).is_err();
+
+    if on_behalf
+        && !(role.can(Permission::LeadCrews) && leads_crew(pool, form.id, my_id, worker).await?)
+    {
         debug!(
             "user {} (id {}) tried to check in for user {}",
             my_name, my_id, worker
         );
-        return Err(CustomError(anyhow!(
-            "Attempted to check in for other worker"
-        )));
+        return Err(CustomError::Forbidden(
+            "Attempted to check in for other worker".to_string(),
+        ));
     }
 
     let jw = query!(
@@ -55,7 +82,7 @@ pub(crate) async fn checkinoutpage(
         form.id,
         worker
     )
-    .fetch_one(&pool)
+    .fetch_one(pool)
     .await?;
 
     let job = query_as!(
@@ -67,27 +94,64 @@ pub(crate) async fn checkinoutpage(
     "#,
         form.id
     )
-    .fetch_one(&pool)
+    .fetch_one(pool)
     .await?;
 
-    let signin = jw.signin.map(|t| {
-        Time::parse(&t, &Iso8601::TIME)
-            .unwrap()
-            .format(&format_description::parse("[hour]:[minute]").unwrap())
-            .unwrap()
-    });
-    let signout = jw.signout.map(|t| {
-        Time::parse(&t, &Iso8601::TIME)
-            .unwrap()
-            .format(&format_description::parse("[hour]:[minute]").unwrap())
-            .unwrap()
-    });
+    let punches = job_punches(pool, form.id, worker).await?;
+    let open = punches.iter().find(|p| p.punchout.is_none());
 
-    let data = json!({
+    let policy = clock_policy(pool).await?;
+    let today = now_in(job_tz(pool, form.id).await?).date();
+
+    let signature = job_signature(pool, form.id).await?;
+    let attachments = job_attachments(pool, form.id, Some(worker)).await?;
+    instantiate(pool, form.id, worker).await?;
+    let checklist = assignment_checklist(pool, form.id, worker).await?;
+    let rules = job_rules(pool, form.id).await?;
+    let feedback = match submitted {
+        Some((_, errors)) => errors.view(&FIELDS),
+        None => {
+            let saved = saved_completion(pool, form.id, worker).await?;
+            FieldErrors::from(rules.problems(&saved)).view(&FIELDS)
+        }
+    };
+
+    let worker_name = query!("select name from users where id = $1;", worker)
+        .fetch_one(pool)
+        .await?
+        .name;
+
+    let crew = if jw.lead && worker == my_id && role.can(Permission::LeadCrews) {
+        query!(
+            r#"
+            select users.id, users.name from jobworkers inner join users
+                on jobworkers.worker = users.id
+            where jobworkers.job = $1 and jobworkers.worker != $2
+                and users.deactivated = false
+            order by users.name;
+            "#,
+            form.id,
+            my_id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| json!({ "id": r.id, "name": r.name }))
+        .collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
+
+    let ratio = mileage_ratio(pool).await?;
+    let travel =
+        suggested_travel(&worker_punches(pool, worker, job.date, job.date).await?).remove(&form.id);
+
+    let mut data = json!({
     "git_ver": git_version!(),
         "title": "CZ4R Time Tracking",
-        "admin": admin,
+        "admin": role.is_staff(),
         "logged_in": true,
+        "user_id": my_id,
         "job_id": form.id,
         "worker_id": form.worker,
         "work_order": job.workorder.as_str(),
@@ -95,127 +159,344 @@ pub(crate) async fn checkinoutpage(
         "site_name": job.sitename.as_str(),
         "address": job.address.as_str(),
         "date": format!("{} {}, {}", job.date.month(), job.date.day(),  job.date.year()),
-        "signin": signin.unwrap_or_default(),
-        "signout": signout.unwrap_or_default(),
+        "job_date": job.date.to_string(),
+        "worker_name": worker_name,
+        "on_behalf": on_behalf,
+        "crew": crew,
+        "clock_only": on_behalf
+            || !role.can(Permission::EditTimesheets) && policy == ClockPolicy::ClockOnly,
+        "can_clock_in": open.is_none() && job.date == today,
+        "working": open.is_some_and(|p| p.kind == PunchKind::Work),
+        "on_break": open.is_some_and(|p| p.kind == PunchKind::Break),
+        "punches": punches.iter().map(Punch::view).collect::<Vec<_>>(),
+        "new_punch": {
+            "kind": PunchKind::Work.as_str(),
+            "paid": false,
+            "punchin_value": format!("{}T00:00", job.date),
+            "punchout_value": "",
+            "errors": FieldErrors::default().view(&PUNCH_FIELDS),
+        },
+        "paid_hours": format!("{:.2}", paid_hours(&punches)),
+        "signature": signature.as_ref().map(Signature::describe),
+        "signature_offline": signature.as_ref().is_some_and(|s| s.offline),
+        "signature_required": rules.signature,
+        "feedback": feedback,
+        "attachments": attachments,
+        "checklist": checklist,
+        "me": my_id,
         "miles": jw.miles_driven,
+        "suggested_miles": travel.map(|t| format!("{:.2}", t.miles)),
+        "suggested_hours": travel.map(|t| format!("{:.2}", t.hours)),
+        "travel_outlier": travel.is_some_and(|t| {
+            t.outlier(jw.miles_driven, jw.hours_driven, ratio)
+        }),
         "hours": jw.hours_driven.floor(),
         "minutes": 60. * (jw.hours_driven - jw.hours_driven.floor()),
         "extra_exp_ct": format!("{:.2}", (jw.extraexpcents as f64 / 100.)),
         "notes": jw.notes.as_str(),
         "jobnotes": job.notes.as_str(),
+        "version": jw.version,
     });
 
-    Ok(RenderHtml("checkinout.hbs", engine, data))
+    if let Some((typed, _)) = submitted {
+        data["miles"] = json!(typed.MilesDriven);
+        data["hours"] = json!(typed.HoursDriven);
+        data["minutes"] = json!(typed.MinutesDriven);
+        data["extra_exp_ct"] = json!(typed.ExtraExpenses);
+        data["notes"] = json!(typed.Notes.clone().unwrap_or_default());
+        data["version"] = json!(typed.Version.unwrap_or(jw.version));
+    }
+
+    Ok(data)
 }
 
 //?Signin=&Signout=&MilesDriven=2&ExtraExpenses=&Notes=
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub(crate) struct CheckInOutForm {
-    Signin: Option<String>,
-    Signout: Option<String>,
-    MilesDriven: Option<f32>,
-    HoursDriven: Option<f32>,
-    MinutesDriven: Option<f32>,
-    ExtraExpenses: Option<String>,
+    #[serde(default)]
+    MilesDriven: String,
+    #[serde(default)]
+    HoursDriven: String,
+    #[serde(default)]
+    MinutesDriven: String,
+    #[serde(default)]
+    ExtraExpenses: String,
     Notes: Option<String>,
     JobId: i64,
     WorkerId: i64,
+    /// The version of the time sheet the form was loaded with. Edits queued
+    /// offline are sent on with the version the one before them was saved as.
+    /// Without one the save is treated as coming from a stale copy.
+    #[serde(default)]
+    Version: Option<i64>,
 }
 
-pub(crate) async fn checkinout(
-    State(AppState { pool, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
-    Form(form): Form<CheckInOutForm>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let (my_id, my_name, admin) = get_user(&auth)?;
+/// What's saved on a time sheet now, next to what was sent from an older copy
+/// of its form
+async fn stale_time_sheet(
+    pool: &Pool<Sqlite>,
+    form: &CheckInOutForm,
+) -> Result<Stale, CustomError> {
+    let jw = query!(
+        r#"
+        select miles_driven, hours_driven, extraexpcents, notes, version from jobworkers
+            where job = $1 and worker = $2;
+        "#,
+        form.JobId,
+        form.WorkerId
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Stale {
+        what: format!("The time sheet for job {}", form.JobId),
+        action: "/api/v1/checkinout",
+        back: format!("/checkinout?id={}&worker={}", form.JobId, form.WorkerId),
+        fields: vec![
+            Field::text(
+                "MilesDriven",
+                "Miles driven",
+                &form.MilesDriven,
+                format!("{:.2}", jw.miles_driven),
+            ),
+            Field::text(
+                "HoursDriven",
+                "Hours driven",
+                &form.HoursDriven,
+                format!("{:.0}", jw.hours_driven.floor()),
+            ),
+            Field::text(
+                "MinutesDriven",
+                "Minutes driven",
+                &form.MinutesDriven,
+                format!("{:.2}", 60. * (jw.hours_driven - jw.hours_driven.floor())),
+            ),
+            Field::text(
+                "ExtraExpenses",
+                "Extra expenses $",
+                &form.ExtraExpenses,
+                format!("{:.2}", jw.extraexpcents as f64 / 100.),
+            ),
+            Field::text(
+                "Notes",
+                "Field notes",
+                form.Notes.clone().unwrap_or_default(),
+                jw.notes,
+            ),
+        ],
+        carried: vec![
+            ("JobId", form.JobId.to_string()),
+            ("WorkerId", form.WorkerId.to_string()),
+            ("Version", jw.version.to_string()),
+        ],
+    })
+}
+
+/// How saving a time sheet went
+pub(crate) enum Saved {
+    /// Saved as the given version, with whatever still has to be done before
+    /// clocking out
+    Yes(FieldErrors, i64),
+    /// Some of it couldn't be read, so none of it was saved
+    Unreadable(FieldErrors),
+    /// It's been clocked out of and the change would break its service
+    /// code's rules, so it wasn't saved
+    Blocked(FieldErrors),
+    /// Someone else saved it since the form was loaded, so it wasn't saved
+    Stale(Stale),
+}
+
+/// Saves the time sheet and checks it against its service code's rules,
+/// which only hold it up once it's been checked out of
+pub(crate) async fn save_time_sheet(
+    AppState { pool, events, .. }: &AppState,
+    user: &SelfOr<can::EditTimesheets>,
+    form: CheckInOutForm,
+) -> Result<Saved, CustomError> {
+    let (my_id, my_name, role) = (user.id, user.name.as_str(), user.role);
 
     let worker = form.WorkerId;
+    user.check(worker)?;
+
+    let mut errors = FieldErrors::default();
+    let milesdriven: f64 = errors.number("MilesDriven", &form.MilesDriven);
+    let hoursdriven: f64 = errors.number("DriveTime", &form.HoursDriven);
+    let minutesdriven: f64 = errors.number("DriveTime", &form.MinutesDriven);
+    let true_extra_exp = errors.cents("ExtraExpenses", &form.ExtraExpenses);
 
-    if !admin && worker != my_id {
-        return Err(CustomError(anyhow!(
-            "Attempted to check in for other worker"
-        )));
+    if !errors.is_empty() {
+        return Ok(Saved::Unreadable(errors));
     }
 
-    let signin = form.Signin.unwrap_or_default();
-    let signout = form.Signout.unwrap_or_default();
-    let milesdriven = form.MilesDriven.unwrap_or_default();
-    let hoursdriven = form.HoursDriven.unwrap_or_default();
-    let minutesdriven = form.MinutesDriven.unwrap_or_default();
-    let extraexpenses = form.ExtraExpenses.unwrap_or_default();
+    let true_hours_driven = hoursdriven + (minutesdriven / 60.);
 
-    let extraexp = Decimal::from_str_exact(&extraexpenses)? * Decimal::ONE_HUNDRED;
+    let finished = query!(
+        r#"
+        select signout is not null as "finished!: bool" from jobworkers
+            where job = $1 and worker = $2;
+        "#,
+        form.JobId,
+        worker
+    )
+    .fetch_one(pool)
+    .await?
+    .finished;
 
-    let signin = if signin.is_empty() {
-        None
-    } else {
-        Some(Time::parse(
-            &signin,
-            format_description!("[hour]:[minute]"),
-        )?)
-    };
+    let problems = job_rules(pool, form.JobId).await?.problems(&Completion {
+        notes: form.Notes.clone().unwrap_or_default(),
+        miles: milesdriven,
+        drive_hours: true_hours_driven,
+        expenses: true_extra_exp as f64 / 100.,
+        photos: photo_count(pool, form.JobId, worker).await?,
+        signed: job_signature(pool, form.JobId).await?.is_some(),
+    });
 
-    let signout = if signout.is_empty() {
-        None
-    } else {
-        Some(Time::parse(
-            &signout,
-            format_description!("[hour]:[minute]"),
-        )?)
-    };
+    // photos and the signature aren't on this form, so they can't hold it up
+    let blocked = finished
+        && problems
+            .iter()
+            .any(|(field, _)| !matches!(*field, "Photos" | "Signature"));
+    let problems = FieldErrors::from(problems);
 
-    let true_hours_driven = hoursdriven + (minutesdriven / 60.);
-    let true_extra_exp = extraexp.to_i32().unwrap();
+    if blocked {
+        info!(
+            "{} {my_name} (id {my_id}) tried to change the finished time sheet for job {} assigned to user {worker}: {}",
+            role.as_str(),
+            form.JobId,
+            problems.summary()
+        );
+        return Ok(Saved::Blocked(problems));
+    }
+
+    let mut tx = pool.begin().await?;
+    let before = crew_snapshots(&mut tx, form.JobId, Some(worker))
+        .await?
+        .remove(&worker);
 
-    query!(
+    let Some(saved) = query!(
         r#"
     update jobworkers
     set
-        signin = $1,
-        signout = $2,
-        miles_driven = $3,
-        hours_driven = $4,
-        extraexpcents = $5,
-        notes = $6
-    where worker = $7
-    and job = $8;
+        miles_driven = $1,
+        hours_driven = $2,
+        extraexpcents = $3,
+        notes = $4,
+        version = version + 1
+    where worker = $5
+    and job = $6
+    and version = $7
+    returning version;
     "#,
-        signin,
-        signout,
         milesdriven,
         true_hours_driven,
         true_extra_exp,
         form.Notes,
         worker,
-        form.JobId
+        form.JobId,
+        form.Version
     )
-    .execute(&pool)
-    .await?;
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        drop(tx);
+        info!(
+            "{} {my_name} (id {my_id}) tried to save the time sheet for job {} assigned to user {worker} from a stale copy of its form",
+            role.as_str(),
+            form.JobId
+        );
+        return Ok(Saved::Stale(stale_time_sheet(pool, &form).await?));
+    };
+
+    let after = crew_snapshots(&mut tx, form.JobId, Some(worker))
+        .await?
+        .remove(&worker);
+    record(&mut tx, form.JobId, Some(worker), my_id, before, after).await?;
+    tx.commit().await?;
+
+    publish(events, form.JobId, Some(worker), "checkinout");
 
     info!(
         "job {} assigned to user {} updated by {} {} (id {}):\n
-sign in time: {}\n
-sign out time: {}\n
 miles driven: {}\n
 hours driven: {}\n
 extra expenses (cents): {}\n
 notes: {}",
         form.JobId,
         worker,
-        if admin { "admin" } else { "user" },
+        role.as_str(),
         my_name,
         my_id,
-        signin
-            .map(|t| t.to_string())
-            .unwrap_or("removed".to_string()),
-        signout
-            .map(|t| t.to_string())
-            .unwrap_or("removed".to_string()),
         milesdriven,
         true_hours_driven,
         true_extra_exp,
         form.Notes.unwrap_or_default(),
     );
 
-    Ok(StatusCode::OK.into_response())
+    Ok(Saved::Yes(problems, saved.version))
+}
+
+/// Autosaves the time sheet form, answering with each field's message for
+/// htmx to swap in next to it. Plain posts, from the edit conflict page, go
+/// back to the time sheet.
+pub(crate) async fn checkinout(
+    State(state): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    headers: HeaderMap,
+    Form(form): Form<CheckInOutForm>,
+) -> Result<Response, CustomError> {
+    let htmx = headers.contains_key("hx-request");
+    let back = format!("/checkinout?id={}&worker={}", form.JobId, form.WorkerId);
+
+    let typed = form.clone();
+    let saved = save_time_sheet(&state, &user, form).await?;
+    let version = match &saved {
+        Saved::Yes(_, version) => Some(*version),
+        _ => None,
+    };
+    let (code, errors, status) = match saved {
+        Saved::Stale(stale) => return Ok(stale.render(state.engine, user.role.is_staff())),
+        Saved::Yes(..) if !htmx => return Ok(Redirect::to(&back).into_response()),
+        Saved::Unreadable(errors) | Saved::Blocked(errors) if !htmx => {
+            let page = CheckInOutPage {
+                id: typed.JobId,
+                worker: typed.WorkerId,
+            };
+            let data =
+                time_sheet_page(&state.pool, &user.with(), &page, Some((&typed, &errors))).await?;
+            return Ok(rerender("checkinout.hbs", state.engine, data));
+        }
+        Saved::Yes(problems, _) => {
+            let status = if problems.is_empty() {
+                "Saved"
+            } else {
+                "Saved, but fix the fields marked below before clocking out"
+            };
+            (StatusCode::OK, problems, status)
+        }
+        Saved::Unreadable(errors) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            errors,
+            "Not saved, fix the fields marked below",
+        ),
+        Saved::Blocked(problems) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            problems,
+            "Not saved, this time sheet is already clocked out of and has to meet its service code's rules",
+        ),
+    };
+
+    let data = json!({
+        "feedback": errors.view(&FIELDS),
+        "fields": FIELDS,
+        "status": status,
+        "saved": code == StatusCode::OK,
+        "version": version,
+        "oob": true,
+    });
+
+    Ok((
+        code,
+        RenderHtml("checkinoutfeedback.hbs", state.engine, data),
+    )
+        .into_response())
 }
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+
+use crate::audit::{audit, Requester};
+use crate::dispatch::publish;
+use crate::roles::{can, Allowed, SelfOr};
+use crate::servicecodes::{job_rules, saved_completion};
+use crate::signatures::check_on_job;
+use crate::sites::job_tz;
+use crate::teams::team_options;
+use crate::{empty_string_as_none, errors::CustomError, now, AppState};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, types::time::Date, Pool, Sqlite};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::info;
+
+/// Copies the job's service code checklist onto an assignment that hasn't been
+/// checked out of yet, so later changes to the checklist don't rewrite history
+pub(crate) async fn instantiate(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<(), sqlx::Error> {
+    query!(
+        r#"
+        insert or ignore into assignment_checks (job, worker, item, label, required)
+            select jobworkers.job, jobworkers.worker, checklist_items.id,
+                checklist_items.label, checklist_items.required
+            from jobworkers
+                inner join jobs on jobworkers.job = jobs.id
+                inner join checklist_items on checklist_items.servicecode = jobs.servicecode
+            where jobworkers.job = $1 and jobworkers.worker = $2
+                and jobworkers.signout is null
+                and not checklist_items.retired;
+        "#,
+        job,
+        worker
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// An assignment's checklist for the time sheet
+pub(crate) async fn assignment_checklist(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<Vec<Value>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select assignment_checks.id, assignment_checks.label, assignment_checks.required,
+            assignment_checks.done, assignment_checks.reason
+            from assignment_checks inner join checklist_items
+            on assignment_checks.item = checklist_items.id
+        where assignment_checks.job = $1 and assignment_checks.worker = $2
+        order by checklist_items.position asc, checklist_items.id asc;
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|c| {
+        json!({
+            "id": c.id,
+            "label": c.label,
+            "required": c.required,
+            "done": c.done,
+            "reason": c.reason.unwrap_or_default(),
+        })
+    })
+    .collect())
+}
+
+/// Whatever is still needed before the worker can finish the job: the time
+/// sheet filled in the way the service code's rules want, and every required
+/// checklist item ticked or given a reason
+pub(crate) async fn ready_to_check_out(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<(), CustomError> {
+    let problems = job_rules(pool, job)
+        .await?
+        .problems(&saved_completion(pool, job, worker).await?);
+    if !problems.is_empty() {
+        return Err(CustomError::Validation(format!(
+            "Finish the time sheet before you clock out: {}",
+            problems.iter().map(|(_, p)| p).join("; ")
+        )));
+    }
+
+    instantiate(pool, job, worker).await?;
+    let missing = query!(
+        r#"
+        select label from assignment_checks
+            where job = $1 and worker = $2 and required
+            and not done and coalesce(trim(reason), '') = '';
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if !missing.is_empty() {
+        return Err(CustomError::Validation(format!(
+            "Finish the checklist before you clock out, or give a reason for skipping: {}",
+            missing.iter().map(|m| m.label.as_str()).join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CheckForm {
+    JobId: i64,
+    WorkerId: i64,
+    id: i64,
+    done: Option<String>,
+    reason: Option<String>,
+}
+
+/// Ticks a checklist item, or says why it wasn't done
+pub(crate) async fn check_item(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Form(form): Form<CheckForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    check_on_job(&pool, &user, form.JobId, Some(form.WorkerId)).await?;
+    let SelfOr {
+        id: my_id,
+        name: my_name,
+        role,
+        ..
+    } = user;
+
+    let done = form.done.is_some();
+    let reason = form
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+
+    let local = OffsetDateTime::now_utc().to_timezone(job_tz(&pool, form.JobId).await?);
+    let stamp = PrimitiveDateTime::new(local.date(), local.time()).replace_nanosecond(0)?;
+
+    let updated = query!(
+        r#"
+        update assignment_checks set done = $1, reason = $2, checked_by = $3, checked_at = $4
+            where id = $5 and job = $6 and worker = $7;
+        "#,
+        done,
+        reason,
+        my_id,
+        stamp,
+        form.id,
+        form.JobId,
+        form.WorkerId
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(CustomError::NotFound(
+            "That checklist item isn't on this time sheet".to_string(),
+        ));
+    }
+
+    publish(&events, form.JobId, Some(form.WorkerId), "checkinout");
+
+    info!(
+        "{} {my_name} (id {my_id}) marked checklist item {} for user {} on job {} {}{}",
+        role.as_str(),
+        form.id,
+        form.WorkerId,
+        form.JobId,
+        if done { "done" } else { "not done" },
+        reason.map(|r| format!(": {r}")).unwrap_or_default()
+    );
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Each service code's current checklist, for the service code page
+pub(crate) async fn checklist_templates(
+    pool: &Pool<Sqlite>,
+) -> Result<HashMap<String, Vec<Value>>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select id, servicecode, label, required from checklist_items
+            where not retired
+        order by position asc, id asc;
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|i| {
+        (
+            i.servicecode,
+            json!({
+                "id": i.id,
+                "label": i.label,
+                "required": i.required,
+            }),
+        )
+    })
+    .into_group_map())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NewItemForm {
+    code: String,
+    label: String,
+    required: Option<String>,
+}
+
+pub(crate) async fn add_item(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<NewItemForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let label = form.label.trim();
+    if label.is_empty() {
+        return Err(CustomError::Validation(
+            "A checklist item needs a name".to_string(),
+        ));
+    }
+    let required = form.required.is_some();
+
+    let mut tx = pool.begin().await?;
+
+    query!(
+        r#"
+        insert into checklist_items (servicecode, label, required, position)
+            values ($1, $2, $3,
+                (select coalesce(max(position), 0) + 1 from checklist_items where servicecode = $1));
+        "#,
+        form.code,
+        label,
+        required
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "added checklist item",
+        &format!("\"{label}\" on service code {}", form.code),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) added {}checklist item \"{label}\" to service code {}",
+        if required { "required " } else { "" },
+        form.code
+    );
+
+    Ok(Redirect::to("/admin/servicecodes"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RetireItemForm {
+    id: i64,
+}
+
+/// Takes an item off its service code's checklist. Assignments that already
+/// have it keep it.
+pub(crate) async fn retire_item(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<RetireItemForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut tx = pool.begin().await?;
+
+    query!(
+        "update checklist_items set retired = true where id = $1;",
+        form.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "removed checklist item",
+        &format!("item {}", form.id),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) removed checklist item {}",
+        form.id
+    );
+
+    Ok(Redirect::to("/admin/servicecodes"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChecklistReportForm {
+    start_date: Option<Date>,
+    end_date: Option<Date>,
+    /// Only this team's time sheets
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    team: Option<i64>,
+}
+
+/// How checklists were completed, by site and service code
+pub(crate) async fn checklistreport(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ViewAllJobs>,
+    Form(form): Form<ChecklistReportForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let today = now().date();
+    let from = form.start_date.unwrap_or(today - Duration::days(30));
+    let to = form.end_date.unwrap_or(today);
+
+    let rows = query!(
+        r#"
+        select jobs.sitename, jobs.servicecode, assignment_checks.label,
+            assignment_checks.required,
+            count(*) as "total!: i64",
+            sum(assignment_checks.done) as "done!: i64",
+            sum(not assignment_checks.done and coalesce(trim(assignment_checks.reason), '') != '')
+                as "skipped!: i64",
+            group_concat(case when not assignment_checks.done then assignment_checks.reason end, '; ')
+                as "reasons?: String"
+            from assignment_checks inner join jobs on assignment_checks.job = jobs.id
+        where jobs.date >= $1 and jobs.date <= $2
+            and ($3 is null or assignment_checks.worker in (
+                select worker from team_members where team = $3
+            ))
+        group by jobs.sitename, jobs.servicecode, assignment_checks.item
+        order by jobs.sitename asc, jobs.servicecode asc, min(assignment_checks.id) asc;
+        "#,
+        from,
+        to,
+        form.team
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let groups = rows
+        .into_iter()
+        .chunk_by(|r| (r.sitename.clone(), r.servicecode.clone()))
+        .into_iter()
+        .map(|((site, code), items)| {
+            json!({
+                "site": site,
+                "code": code,
+                "items": items
+                    .map(|r| {
+                        json!({
+                            "label": r.label,
+                            "required": r.required,
+                            "total": r.total,
+                            "done": r.done,
+                            "skipped": r.skipped,
+                            "open": r.total - r.done - r.skipped,
+                            "reasons": r.reasons.unwrap_or_default(),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Checklists",
+        "admin": true,
+        "logged_in": true,
+        "from": from.to_string(),
+        "to": to.to_string(),
+        "teams": team_options(&pool, form.team).await?,
+        "groups": groups,
+    });
+
+    Ok(RenderHtml("checklists.hbs", engine, data))
+}
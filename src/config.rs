@@ -186,11 +186,10 @@ impl Config {
 
             let salt_str = salt.as_str();
             query!(
-                "insert into users (name, hash, salt, admin) values ($1, $2, $3, $4);",
+                "insert into users (name, hash, salt, role) values ($1, $2, $3, 'admin');",
                 admin_uname,
                 hash,
-                salt_str,
-                true
+                salt_str
             )
             .execute(&config_pool)
             .await
@@ -0,0 +1,390 @@
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+
+use crate::roles::{can, Allowed};
+use crate::Backend;
+use crate::{errors::CustomError, now, AppState};
+use axum::{extract::State, response::IntoResponse};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde::Serialize;
+use sqlx::{query, types::time::Date, Pool, Sqlite, SqliteConnection};
+use time::{format_description::well_known::Iso8601, macros::format_description, Time};
+use tracing::debug;
+
+/// Parses a time as stored in the database
+pub(crate) fn db_time(t: &Option<String>) -> Option<Time> {
+    t.as_ref().and_then(|t| Time::parse(t, &Iso8601::TIME).ok())
+}
+
+#[derive(Debug, Clone)]
+struct Booking {
+    job: i64,
+    sitename: String,
+    date: Date,
+    start: Option<Time>,
+    end: Option<Time>,
+}
+
+impl Booking {
+    /// A job without a time window could be any time that day, so it
+    /// overlaps every other job on the same day
+    fn overlaps(&self, other: &Booking) -> bool {
+        if self.date != other.date || self.job == other.job {
+            return false;
+        }
+        match (self.start, self.end, other.start, other.end) {
+            (Some(s1), Some(e1), Some(s2), Some(e2)) => s1 < e2 && s2 < e1,
+            _ => true,
+        }
+    }
+
+    /// When it runs, to finish a sentence
+    fn window(&self) -> String {
+        match (self.start, self.end) {
+            (Some(_), Some(_)) => format!("from {}", window_str(self.start, self.end)),
+            _ => String::from("with no set time"),
+        }
+    }
+}
+
+fn window_str(start: Option<Time>, end: Option<Time>) -> String {
+    let fmt = format_description!("[hour]:[minute]");
+    match (start, end) {
+        (Some(s), Some(e)) => format!(
+            "{} to {}",
+            s.format(&fmt).unwrap_or_default(),
+            e.format(&fmt).unwrap_or_default()
+        ),
+        _ => String::from("all day"),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum ConflictKind {
+    Overlap,
+    Deactivated,
+    TimeOff,
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub worker_id: i64,
+    pub worker_name: String,
+    pub job_id: Option<i64>,
+    pub date: String,
+    pub kind: ConflictKind,
+    pub other_job: Option<i64>,
+    pub description: String,
+}
+
+/// Workers who can't work on `date` during `window`, and why. Approved time
+/// off wins over the weekly availability pattern.
+pub(crate) async fn unavailable_workers(
+    conn: &mut SqliteConnection,
+    date: Date,
+    window: (Option<Time>, Option<Time>),
+) -> Result<HashMap<i64, (ConflictKind, String)>, sqlx::Error> {
+    let mut unavailable = HashMap::new();
+
+    let weekday = date.weekday();
+    let weekday_num = weekday.number_days_from_sunday();
+    let patterns = query!(
+        r#"
+        select worker, available, starttime, endtime from availability
+            where weekday = $1;
+        "#,
+        weekday_num
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for p in patterns {
+        let (start, end) = (db_time(&p.starttime), db_time(&p.endtime));
+        if !p.available {
+            unavailable.insert(
+                p.worker,
+                (
+                    ConflictKind::Unavailable,
+                    format!("is not available on {}s", weekday),
+                ),
+            );
+        } else if let (Some(s), Some(e), Some(js), Some(je)) = (start, end, window.0, window.1) {
+            if js < s || je > e {
+                unavailable.insert(
+                    p.worker,
+                    (
+                        ConflictKind::Unavailable,
+                        format!(
+                            "is only available from {} on {}s",
+                            window_str(start, end),
+                            weekday
+                        ),
+                    ),
+                );
+            }
+        }
+    }
+
+    let timeoff = query!(
+        r#"
+        select worker, start_date, end_date, reason from timeoff
+            where status = 'approved'
+            and start_date <= $1 and end_date >= $1;
+        "#,
+        date
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for t in timeoff {
+        unavailable.insert(
+            t.worker,
+            (
+                ConflictKind::TimeOff,
+                if t.reason.is_empty() {
+                    format!("has time off from {} to {}", t.start_date, t.end_date)
+                } else {
+                    format!(
+                        "has time off from {} to {} ({})",
+                        t.start_date, t.end_date, t.reason
+                    )
+                },
+            ),
+        );
+    }
+
+    Ok(unavailable)
+}
+
+/// Everything that would make assigning `workers` to a job on `date` during
+/// `window` a bad idea. `job_id` is the job being edited, if it exists yet.
+pub(crate) async fn find_conflicts(
+    conn: &mut SqliteConnection,
+    job_id: Option<i64>,
+    date: Date,
+    window: (Option<Time>, Option<Time>),
+    workers: &[i64],
+) -> Result<Vec<Conflict>, sqlx::Error> {
+    let this = Booking {
+        job: job_id.unwrap_or(-1),
+        sitename: String::new(),
+        date,
+        start: window.0,
+        end: window.1,
+    };
+
+    let mut conflicts = vec![];
+    let unavailable = unavailable_workers(conn, date, window).await?;
+
+    for worker in workers {
+        let user = query!(
+            "select id, name, deactivated from users where id = $1;",
+            worker
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        let user = if let Some(u) = user {
+            u
+        } else {
+            continue;
+        };
+
+        if user.deactivated {
+            conflicts.push(Conflict {
+                worker_id: user.id,
+                worker_name: user.name.clone(),
+                job_id,
+                date: date.to_string(),
+                kind: ConflictKind::Deactivated,
+                other_job: None,
+                description: format!("{} is deactivated", user.name),
+            });
+        }
+
+        if let Some((kind, reason)) = unavailable.get(worker) {
+            conflicts.push(Conflict {
+                worker_id: user.id,
+                worker_name: user.name.clone(),
+                job_id,
+                date: date.to_string(),
+                kind: kind.clone(),
+                other_job: None,
+                description: format!("{} {}", user.name, reason),
+            });
+        }
+
+        let others = query!(
+            r#"
+            select jobs.id, jobs.sitename, jobs.date, jobs.starttime, jobs.endtime
+                from jobs inner join jobworkers
+                on jobs.id = jobworkers.job
+            where jobworkers.worker = $1
+                and jobs.date = $2
+                and jobs.id != $3;
+            "#,
+            worker,
+            date,
+            this.job
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|r| Booking {
+            job: r.id,
+            sitename: r.sitename,
+            date: r.date,
+            start: db_time(&r.starttime),
+            end: db_time(&r.endtime),
+        });
+
+        for other in others.filter(|o| this.overlaps(o)) {
+            conflicts.push(Conflict {
+                worker_id: user.id,
+                worker_name: user.name.clone(),
+                job_id,
+                date: date.to_string(),
+                kind: ConflictKind::Overlap,
+                other_job: Some(other.job),
+                description: format!(
+                    "{} is already assigned to job {} ({}) {}",
+                    user.name,
+                    other.job,
+                    other.sitename,
+                    other.window()
+                ),
+            });
+        }
+    }
+
+    Ok(conflicts)
+}
+
+/// Conflicts across every assignment from today onwards
+pub(crate) async fn all_conflicts(pool: &Pool<Sqlite>) -> Result<Vec<Conflict>, sqlx::Error> {
+    let today = now().date();
+
+    let rows = query!(
+        r#"
+        select jobs.id, jobs.sitename, jobs.date, jobs.starttime, jobs.endtime,
+            users.id as worker, users.name, users.deactivated
+            from jobs inner join jobworkers
+            on jobs.id = jobworkers.job
+            inner join users
+            on jobworkers.worker = users.id
+        where jobs.date >= $1
+        order by jobs.date asc, jobs.id asc;
+        "#,
+        today
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut conflicts = vec![];
+    let mut by_worker_day: BTreeMap<(i64, Date), Vec<Booking>> = BTreeMap::new();
+    let mut unavailable_by_job = HashMap::new();
+
+    for r in rows {
+        let booking = Booking {
+            job: r.id,
+            sitename: r.sitename,
+            date: r.date,
+            start: db_time(&r.starttime),
+            end: db_time(&r.endtime),
+        };
+
+        if r.deactivated {
+            conflicts.push(Conflict {
+                worker_id: r.worker,
+                worker_name: r.name.clone(),
+                job_id: Some(booking.job),
+                date: booking.date.to_string(),
+                kind: ConflictKind::Deactivated,
+                other_job: None,
+                description: format!(
+                    "{} is deactivated but still assigned to job {} ({})",
+                    r.name, booking.job, booking.sitename
+                ),
+            });
+        }
+
+        if let Entry::Vacant(e) = unavailable_by_job.entry(booking.job) {
+            e.insert(
+                unavailable_workers(
+                    &mut *pool.acquire().await?,
+                    booking.date,
+                    (booking.start, booking.end),
+                )
+                .await?,
+            );
+        }
+        if let Some((kind, reason)) = unavailable_by_job[&booking.job].get(&r.worker) {
+            conflicts.push(Conflict {
+                worker_id: r.worker,
+                worker_name: r.name.clone(),
+                job_id: Some(booking.job),
+                date: booking.date.to_string(),
+                kind: kind.clone(),
+                other_job: None,
+                description: format!(
+                    "{} {} but is assigned to job {} ({})",
+                    r.name, reason, booking.job, booking.sitename
+                ),
+            });
+        }
+
+        let day = by_worker_day.entry((r.worker, r.date)).or_default();
+        for other in day.iter().filter(|o| o.overlaps(&booking)) {
+            conflicts.push(Conflict {
+                worker_id: r.worker,
+                worker_name: r.name.clone(),
+                job_id: Some(booking.job),
+                date: booking.date.to_string(),
+                kind: ConflictKind::Overlap,
+                other_job: Some(other.job),
+                description: format!(
+                    "{} is assigned to job {} ({}) {} and job {} ({}) {}",
+                    r.name,
+                    other.job,
+                    other.sitename,
+                    other.window(),
+                    booking.job,
+                    booking.sitename,
+                    booking.window()
+                ),
+            });
+        }
+        day.push(booking);
+    }
+
+    Ok(conflicts)
+}
+
+pub(crate) async fn conflictspage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+) -> Result<impl IntoResponse, CustomError> {
+    let conflicts = all_conflicts(&pool).await?;
+
+    debug!(
+        "admin {my_name} (id {my_id}) viewed {} scheduling conflicts",
+        conflicts.len()
+    );
+
+    let data = serde_json::json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Scheduling Conflicts",
+        "admin": true,
+        "logged_in": true,
+        "count": conflicts.len(),
+        "conflicts": conflicts,
+    });
+
+    Ok(RenderHtml("conflicts.hbs", engine, data))
+}
@@ -1,8 +1,11 @@
 //Name=&Address=&Phone=&Email=&Hourly=&Mileage=&Drivetime=
 
 use super::Worker;
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed, Role};
+use crate::validation::{rerender, FieldErrors};
+use crate::workeredit::worker_page;
 use crate::AppState;
 use crate::Backend;
 use anyhow::{anyhow, bail};
@@ -11,10 +14,12 @@ use axum::extract::Path;
 use axum::extract::State;
 use axum::response::IntoResponse;
 use axum::response::Redirect;
+use axum::response::Response;
 use axum::Form;
 use axum_login::AuthSession;
 use rust_decimal::prelude::*;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::query;
 use sqlx::query_as;
 use sqlx::Pool;
@@ -29,48 +34,58 @@ pub(crate) struct WorkerCreateForm {
     Mileage: String,
     Drivetime: String,
     Flatrate: String,
-    Admin: Option<String>,
+    Role: Role,
 }
 
 pub(crate) async fn create_worker(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState { pool, engine, .. }): State<AppState>,
     //Path(id): Path<i64>,
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
+    from: Requester,
     Form(workerdata): Form<WorkerCreateForm>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let (my_id, my_name) = get_admin(&auth)?;
+) -> Result<Response, CustomError> {
+    let role = workerdata.Role.as_str();
 
-    let hourly = Decimal::from_str_exact(&workerdata.Hourly)? * Decimal::ONE_HUNDRED;
+    let mut errors = FieldErrors::default();
+    errors.required("Name", "The name", &workerdata.Name);
+    errors.email("Email", &workerdata.Email);
+    let hourly = errors.cents("Hourly", &workerdata.Hourly);
+    let mileage = errors.cents("Mileage", &workerdata.Mileage);
+    let drivetime = errors.cents("Drivetime", &workerdata.Drivetime);
+    let flatrate = errors.cents("Flatrate", &workerdata.Flatrate);
 
-    let mileage = Decimal::from_str_exact(&workerdata.Mileage)? * Decimal::ONE_HUNDRED;
+    if !errors.is_empty() {
+        let typed = json!({
+            "name": workerdata.Name,
+            "address": workerdata.Address,
+            "phone": workerdata.Phone,
+            "email": workerdata.Email,
+            "role": workerdata.Role,
+            "roles": Role::options(workerdata.Role),
+            "rate_hourly_cents": workerdata.Hourly,
+            "rate_mileage_cents": workerdata.Mileage,
+            "rate_drive_hourly_cents": workerdata.Drivetime,
+            "flat_rate_cents": workerdata.Flatrate,
+        });
+        let data = worker_page(&pool, my_id, None, true, Some((typed, &errors))).await?;
+        return Ok(rerender("workeredit.hbs", engine, data));
+    }
 
-    let drivetime = Decimal::from_str_exact(&workerdata.Drivetime)? * Decimal::ONE_HUNDRED;
+    let mut tx = pool.begin().await?;
 
-    let flatrate = Decimal::from_str_exact(&workerdata.Flatrate)? * Decimal::ONE_HUNDRED;
-
-    let admin = match workerdata.Admin.as_deref() {
-        Some("on" | "true" | "yes") => true,
-        Some("off" | "false" | "no") | None => false,
-        _ => {
-            return Err(CustomError(anyhow!(
-                "Client didn't return a boolean string"
-            )))
-        }
-    };
-
-    let hourly = hourly.to_i32().unwrap();
-    let mileage = mileage.to_i32().unwrap();
-    let drivetime = drivetime.to_i32().unwrap();
-    let flatrate = flatrate.to_i32().unwrap();
     let id = query!(
-            r#"insert into users (name, hash, salt, admin, address, phone, email, rate_hourly_cents, rate_mileage_cents, rate_drive_hourly_cents, flat_rate_cents, must_change_pw)
+            r#"insert into users (name, hash, salt, role, address, phone, email, rate_hourly_cents, rate_mileage_cents, rate_drive_hourly_cents, flat_rate_cents, must_change_pw)
         values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
         returning id;
         "#,
             workerdata.Name,
             "",
             "",
-            admin,
+            role,
             workerdata.Address,
             workerdata.Phone,
             workerdata.Email,
@@ -79,14 +94,23 @@ pub(crate) async fn create_worker(
             drivetime,
             flatrate,
             true
-        ).fetch_one(&pool).await?.id;
+        ).fetch_one(&mut *tx).await?.id;
 
-    tracing::info!("admin {} (id {}) created new user {} as follows:\nname: {}\nadmin: {}\naddress: {}\nphone number: {}\nemail address: {}\nhourly rate (cents): {}\ndriving milage rate (cents): {}\ndriving hourly rate (cents): {}\nflat rate worker: {}",
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "created worker",
+        &format!("user {id} ({})", workerdata.Name),
+    )
+    .await?;
+    tx.commit().await?;
+    tracing::info!("admin {} (id {}) created new user {} as follows:\nname: {}\nrole: {}\naddress: {}\nphone number: {}\nemail address: {}\nhourly rate (cents): {}\ndriving milage rate (cents): {}\ndriving hourly rate (cents): {}\nflat rate worker: {}",
         my_name,
         my_id,
         id,
         workerdata.Name,
-        admin,
+        role,
         workerdata.Address,
         workerdata.Phone,
         workerdata.Email,
@@ -96,7 +120,5 @@ pub(crate) async fn create_worker(
         flatrate,
     );
 
-    Ok(Redirect::to(
-        format!("/admin/worker-edit?worker={}", id).as_str(),
-    ))
+    Ok(Redirect::to(format!("/admin/worker-edit?worker={}", id).as_str()).into_response())
 }
@@ -1,10 +1,10 @@
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed};
 use crate::AppState;
 
 use super::Worker;
 
-use anyhow::anyhow;
 use anyhow::bail;
 use axum::response::Html;
 use axum::response::IntoResponse;
@@ -32,28 +32,43 @@ pub struct DeactivateForm {
 }
 
 pub(crate) async fn deactivate(
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
     State(AppState { pool, .. }): State<AppState>,
+    from: Requester,
     Form(deactivate_form): Form<DeactivateForm>, //Extension(worker): Extension<Worker>
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    let (my_id, my_name) = get_admin(&auth)?;
     if deactivate_form.user == my_id {
         debug!(
             "admin {} (id {}) tried to deactivate themself",
             my_name, my_id
         );
-        return Err(CustomError(anyhow!("A user cannot deactivate themselves")));
+        return Err(CustomError::Forbidden(
+            "A user cannot deactivate themselves".to_string(),
+        ));
     }
 
-    let mut _conn = pool.acquire().await.unwrap();
+    let mut tx = pool.begin().await?;
 
     query!(
         "update users set deactivated = true where id = $1;",
         deactivate_form.user
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
 
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "deactivated worker",
+        &format!("user {}", deactivate_form.user),
+    )
+    .await?;
+    tx.commit().await?;
     info!("admin {} deactivated user {}", my_id, deactivate_form.user);
 
     Ok(Redirect::to("/admin/worker-edit"))
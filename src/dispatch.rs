@@ -0,0 +1,150 @@
+use std::convert::Infallible;
+
+use crate::conflicts::db_time;
+use crate::punches::db_datetime;
+use crate::roles::{can, Allowed};
+use crate::Backend;
+use crate::{errors::CustomError, now, AppState};
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use futures::{stream, Stream};
+use git_version::git_version;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query, types::time::Date};
+use time::{macros::format_description, Time};
+use tokio::sync::broadcast::{error::RecvError, Sender};
+use tracing::trace;
+
+/// Something changed that the dispatch board should show
+#[derive(Debug, Clone, Serialize)]
+pub struct DispatchEvent {
+    pub job: i64,
+    pub worker: Option<i64>,
+    pub what: &'static str,
+}
+
+/// Tells every open dispatch board about a change. Nobody listening is fine.
+pub(crate) fn publish(
+    events: &Sender<DispatchEvent>,
+    job: i64,
+    worker: Option<i64>,
+    what: &'static str,
+) {
+    let event = DispatchEvent { job, worker, what };
+    trace!("publishing dispatch event {:?}", event);
+    let _ = events.send(event);
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DispatchForm {
+    date: Option<Date>,
+}
+
+fn format_time(t: Option<Time>) -> String {
+    t.and_then(|t| t.format(format_description!("[hour]:[minute]")).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) async fn dispatchpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ViewAllJobs>,
+    Form(form): Form<DispatchForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let date = form.date.unwrap_or_else(|| now().date());
+
+    let rows = query!(
+        r#"
+        select jobs.id, jobs.sitename, jobs.address, jobs.starttime, jobs.endtime,
+            users.id as "worker?", users.name as "name?",
+            jobworkers.signin, jobworkers.signout,
+            exists(
+                select id from punches
+                where punches.job = jobs.id and punches.worker = users.id and punches.offsite
+            ) as "offsite!: bool"
+            from jobs left join jobworkers
+            on jobs.id = jobworkers.job
+            left join users
+            on jobworkers.worker = users.id
+        where jobs.date = $1
+        order by jobs.starttime asc, jobs.id asc, users.name asc;
+        "#,
+        date
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut jobs: Vec<serde_json::Value> = vec![];
+    let mut current: Option<i64> = None;
+    for r in rows {
+        if current != Some(r.id) {
+            current = Some(r.id);
+            jobs.push(json!({
+                "id": r.id,
+                "sitename": r.sitename,
+                "address": r.address,
+                "starttime": format_time(db_time(&r.starttime)),
+                "endtime": format_time(db_time(&r.endtime)),
+                "workers": [],
+            }));
+        }
+        if let (Some(worker), Some(name)) = (r.worker, r.name) {
+            let signin = db_datetime(&r.signin).map(|t| t.time());
+            let signout = db_datetime(&r.signout).map(|t| t.time());
+            let state = match (signin, signout) {
+                (_, Some(_)) => "done",
+                (Some(_), None) => "onsite",
+                (None, None) => "notstarted",
+            };
+            jobs.last_mut().unwrap()["workers"]
+                .as_array_mut()
+                .unwrap()
+                .push(json!({
+                    "id": worker,
+                    "name": name,
+                    "state": state,
+                    "signin": format_time(signin),
+                    "signout": format_time(signout),
+                    "offsite": r.offsite,
+                }));
+        }
+    }
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Dispatch",
+        "admin": true,
+        "logged_in": true,
+        "date": date.to_string(),
+        "jobs": jobs,
+    });
+
+    Ok(RenderHtml("dispatch.hbs", engine, data))
+}
+
+pub(crate) async fn dispatch_events(
+    State(AppState { events, .. }): State<AppState>,
+    _: Allowed<can::ViewAllJobs>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, CustomError> {
+    let rx = events.subscribe();
+
+    // a lagging board just reloads, so missed events don't matter
+    let stream = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(e) => Event::default().event("update").json_data(&e).ok()?,
+            Err(RecvError::Lagged(_)) => Event::default().event("update").data("lagged"),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
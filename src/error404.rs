@@ -27,7 +27,7 @@ pub(crate) async fn error404(
     }): State<AppState>,
     mut auth: AuthSession<Backend>,
 ) -> Result<impl IntoResponse, Infallible> {
-    let admin = auth.user.as_ref().map_or(false, |w| w.admin);
+    let admin = auth.user.as_ref().is_some_and(|w| w.role().is_staff());
     let logged_in = auth.user.is_some();
 
     if StaticAsset::iter().contains(uri.path().strip_prefix('/').unwrap_or(uri.path())) {
@@ -1,52 +1,147 @@
-use crate::{render, setup_handlebars};
+use crate::ENGINE;
 use axum::{
-    http::StatusCode,
-    response::{Html, IntoResponse, Response},
+    extract::Request,
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Json, Redirect, Response},
 };
-use axum_template::{engine::Engine, RenderHtml, TemplateEngine};
+use axum_template::RenderHtml;
 use git_version::git_version;
-use handlebars::Handlebars;
-use std::{borrow::Borrow, fmt};
-use thiserror::Error;
-use tracing::{debug, info, warn};
+use serde_json::json;
+use std::fmt;
+use tracing::{info, warn};
 
+/// What went wrong handling a request. Each kind is answered with its own
+/// status code, as a page for browsers and as JSON for API clients.
 #[derive(Debug)]
-pub struct CustomError(pub anyhow::Error);
+pub enum CustomError {
+    /// Nobody is logged in
+    NotAuthenticated,
+    /// Logged in, but not allowed to do this
+    Forbidden(String),
+    /// What the request is about doesn't exist
+    NotFound(String),
+    /// The request doesn't make sense as sent
+    Validation(String),
+    /// It clashes with how things are right now
+    Conflict(String),
+    /// Something on our end broke
+    Internal(anyhow::Error),
+}
 
+/// Anything `?` passes up is a fault on our end. Mistakes in what was sent are
+/// turned into [`CustomError::Validation`] or [`CustomError::NotFound`] where
+/// they're read, so they don't come back as a 500.
 impl<E> From<E> for CustomError
 where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err = err.into();
+        match err.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::RowNotFound) => Self::NotFound("that doesn't exist".to_string()),
+            _ => Self::Internal(err),
+        }
     }
 }
 
+impl fmt::Display for CustomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAuthenticated => write!(f, "Not logged in"),
+            Self::Forbidden(m) | Self::NotFound(m) | Self::Validation(m) | Self::Conflict(m) => {
+                write!(f, "{m}")
+            }
+            Self::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl CustomError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotAuthenticated => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Left on error responses so [`negotiate`] can answer API clients in JSON
+#[derive(Clone)]
+struct ErrorBody(String);
+
 impl IntoResponse for CustomError {
     fn into_response(self) -> Response {
-        let mut hbs = Handlebars::new();
-        hbs.set_strict_mode(true);
-        setup_handlebars(&mut hbs);
-
-        let data = serde_json::json!({
-        "git_ver": git_version!(),
-            "admin": false,
-            "logged_in": false,
-            "title": "CZ4R Error 404",
-            "cause": self.0.to_string() ,
-        });
-        info!("Client error\n{}\n{}", self.0, self.0.backtrace());
-
-        let html = hbs.render("errorauth.hbs", &data);
-
-        let mut html = if let Ok(html) = html {
-            html
-        } else {
-            format!("ERROR\n\n{}", self.0)
+        let status = self.status();
+        let cause = self.to_string();
+
+        match &self {
+            Self::Internal(e) => warn!("Server error\n{}\n{}", e, e.backtrace()),
+            _ => info!("Client error {status}\n{cause}"),
+        }
+
+        let mut res = match (&self, ENGINE.get()) {
+            (Self::NotAuthenticated, _) => Redirect::to("/loginpage").into_response(),
+            (_, Some(engine)) => {
+                let data = json!({
+                "git_ver": git_version!(),
+                    "admin": false,
+                    "logged_in": false,
+                    "title": format!("CZ4R Error {}", status.as_u16()),
+                    "cause": cause,
+                });
+                let mut res = RenderHtml("errorauth.hbs", engine.clone(), data).into_response();
+                *res.status_mut() = status;
+                res
+            }
+            (_, None) => (status, Html(format!("ERROR\n\n{cause}"))).into_response(),
         };
 
-        let mut res = Html(html).into_response();
-        *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        res.extensions_mut().insert(ErrorBody(cause));
+        res
+    }
+}
+
+/// API clients say so with JSON in or JSON accepted, browsers ask for pages
+fn wants_json(headers: &HeaderMap) -> bool {
+    let has = |name, what| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains(what))
+    };
+    has(header::CONTENT_TYPE, "application/json")
+        || (has(header::ACCEPT, "application/json") && !has(header::ACCEPT, "text/html"))
+}
+
+/// Answers errors the way the client can use them: JSON for API clients, and
+/// for htmx requests that aren't logged in, a redirect htmx will follow
+pub async fn negotiate(req: Request, next: Next) -> Response {
+    let json = wants_json(req.headers());
+    let htmx = req.headers().contains_key("hx-request");
+
+    let mut res = next.run(req).await;
+    let Some(ErrorBody(cause)) = res.extensions_mut().remove::<ErrorBody>() else {
+        return res;
+    };
+    let status = match res.status() {
+        StatusCode::SEE_OTHER => StatusCode::UNAUTHORIZED,
+        status => status,
+    };
+
+    if json {
+        (
+            status,
+            Json(json!({"error": cause, "status": status.as_u16()})),
+        )
+            .into_response()
+    } else if htmx && status == StatusCode::UNAUTHORIZED {
+        (status, [("HX-Redirect", "/loginpage")]).into_response()
+    } else {
         res
     }
 }
@@ -1,5 +1,6 @@
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed};
 use crate::AppState;
 
 use super::Worker;
@@ -19,15 +20,14 @@ use tokio::process::Command;
 use tracing::info;
 
 pub(crate) async fn export_db(
-    mut auth: AuthSession<Backend>,
-    State(AppState {
-        pool: _,
-        engine: _,
-        db_url,
-    }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ExportData>,
+    State(AppState { pool, db_url, .. }): State<AppState>,
+    from: Requester,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
-
     let url = url::Url::parse(&db_url)?;
     let path = url.path();
 
@@ -38,6 +38,14 @@ pub(crate) async fn export_db(
         .await?
         .stdout);
 
+    audit(
+        &mut *pool.acquire().await?,
+        &from,
+        my_id,
+        "exported database",
+        "database",
+    )
+    .await?;
     info!("admin {} (id {}) exported the database", my_name, my_id);
 
     res
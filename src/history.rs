@@ -0,0 +1,294 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::conflicts::db_time;
+use crate::punches::db_datetime;
+use crate::roles::{can, SelfOr};
+use crate::{errors::CustomError, AppState, TZ};
+use axum::{extract::State, response::IntoResponse, Form};
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, query_as, SqliteConnection};
+use time::{macros::format_description, PrimitiveDateTime, Time};
+use time_tz::OffsetDateTimeExt;
+
+/// A job's or an assignment's fields, by how they're shown in its history
+pub(crate) type Snapshot = Vec<(&'static str, String)>;
+
+fn show_time(t: Option<Time>) -> String {
+    t.and_then(|t| t.format(format_description!("[hour]:[minute]")).ok())
+        .unwrap_or_default()
+}
+
+fn show_datetime(t: Option<PrimitiveDateTime>) -> String {
+    t.and_then(|t| {
+        t.format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+            .ok()
+    })
+    .unwrap_or_default()
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_string()
+}
+
+pub(crate) async fn job_snapshot(
+    conn: &mut SqliteConnection,
+    job: i64,
+) -> Result<Option<Snapshot>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select sitename, workorder, servicecode, address, date as "date: String",
+            starttime, endtime, open, max_crew, needs_confirmation, notes
+            from jobs where id = $1;
+        "#,
+        job
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .map(|j| {
+        vec![
+            ("Site name", j.sitename),
+            ("Work order", j.workorder),
+            ("Service code", j.servicecode),
+            ("Address", j.address),
+            ("Date", j.date),
+            ("Start time", show_time(db_time(&j.starttime))),
+            ("End time", show_time(db_time(&j.endtime))),
+            ("Open for claiming", yes_no(j.open)),
+            ("Crew size", j.max_crew.to_string()),
+            ("Claims need confirmation", yes_no(j.needs_confirmation)),
+            ("Job notes", j.notes),
+        ]
+    }))
+}
+
+struct Assignment {
+    worker: i64,
+    signin: Option<String>,
+    signout: Option<String>,
+    miles_driven: f64,
+    hours_driven: f64,
+    extraexpcents: i64,
+    notes: String,
+    using_flat_rate: bool,
+    lead: bool,
+}
+
+impl Assignment {
+    fn snapshot(self) -> Snapshot {
+        vec![
+            ("Signed in", show_datetime(db_datetime(&self.signin))),
+            ("Signed out", show_datetime(db_datetime(&self.signout))),
+            ("Miles driven", format!("{:.2}", self.miles_driven)),
+            ("Hours driven", format!("{:.2}", self.hours_driven)),
+            (
+                "Extra expenses $",
+                format!("{:.2}", self.extraexpcents as f64 / 100.),
+            ),
+            ("Field notes", self.notes),
+            ("Flat rate", yes_no(self.using_flat_rate)),
+            ("Crew lead", yes_no(self.lead)),
+        ]
+    }
+}
+
+/// Snapshots of a job's assignments by worker, or just `worker`'s
+pub(crate) async fn crew_snapshots(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: Option<i64>,
+) -> Result<HashMap<i64, Snapshot>, sqlx::Error> {
+    Ok(query_as!(
+        Assignment,
+        r#"
+        select worker, signin as "signin?: String", signout as "signout?: String",
+            miles_driven, hours_driven, extraexpcents, notes, using_flat_rate, lead
+            from jobworkers where job = $1 and ($2 is null or worker = $2);
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|a| (a.worker, a.snapshot()))
+    .collect())
+}
+
+/// Records what changed between two snapshots of a job, or of one of its
+/// assignments when `worker` is given. No snapshot before means it was
+/// created, and none after that it was deleted.
+pub(crate) async fn record(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: Option<i64>,
+    actor: i64,
+    before: Option<Snapshot>,
+    after: Option<Snapshot>,
+) -> Result<(), sqlx::Error> {
+    let (action, fields) = match (before, after) {
+        (None, None) => return Ok(()),
+        (None, Some(after)) => (
+            "created",
+            after
+                .into_iter()
+                .filter(|(_, new)| !new.is_empty())
+                .map(|(field, new)| (field, None, Some(new)))
+                .collect::<Vec<_>>(),
+        ),
+        (Some(before), None) => (
+            "deleted",
+            before
+                .into_iter()
+                .filter(|(_, old)| !old.is_empty())
+                .map(|(field, old)| (field, Some(old), None))
+                .collect(),
+        ),
+        (Some(before), Some(after)) => (
+            "changed",
+            before
+                .into_iter()
+                .zip(after)
+                .filter(|((_, old), (_, new))| old != new)
+                .map(|((field, old), (_, new))| (field, Some(old), Some(new)))
+                .collect(),
+        ),
+    };
+    if fields.is_empty() && action == "changed" {
+        return Ok(());
+    }
+
+    let change = query!(
+        r#"
+        insert into changes (job, worker, actor, action) values ($1, $2, $3, $4)
+            returning id;
+        "#,
+        job,
+        worker,
+        actor,
+        action
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .id;
+
+    for (field, old, new) in fields {
+        query!(
+            "insert into change_fields (change, field, old, new) values ($1, $2, $3, $4);",
+            change,
+            field,
+            old,
+            new
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Records the changes to each assignment between two lots of
+/// [`crew_snapshots`]
+pub(crate) async fn record_crew(
+    conn: &mut SqliteConnection,
+    job: i64,
+    actor: i64,
+    mut before: HashMap<i64, Snapshot>,
+    mut after: HashMap<i64, Snapshot>,
+) -> Result<(), sqlx::Error> {
+    let workers = before
+        .keys()
+        .chain(after.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    for worker in workers {
+        record(
+            conn,
+            job,
+            Some(worker),
+            actor,
+            before.remove(&worker),
+            after.remove(&worker),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HistoryPage {
+    job: i64,
+    worker: Option<i64>,
+}
+
+/// The changes to a whole job for office staff, or to one time sheet
+pub(crate) async fn historypage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    user: SelfOr<can::ViewAllJobs>,
+    Form(form): Form<HistoryPage>,
+) -> Result<impl IntoResponse, CustomError> {
+    match form.worker {
+        None if !user.granted() => {
+            return Err(CustomError::Forbidden(
+                "Only office staff can see the history of a whole job".to_string(),
+            ))
+        }
+        Some(worker) => user.check(worker)?,
+        None => {}
+    }
+
+    let changes = query!(
+        r#"
+        select changes.id, changes.action, changes.at,
+            actor.name as actor_name, worker.name as "worker_name?",
+            change_fields.field, change_fields.old, change_fields.new
+            from changes
+            inner join change_fields on change_fields.change = changes.id
+            inner join users as actor on changes.actor = actor.id
+            left join users as worker on changes.worker = worker.id
+        where changes.job = $1 and ($2 is null or changes.worker = $2)
+        order by changes.id desc, change_fields.id asc;
+        "#,
+        form.job,
+        form.worker
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .chunk_by(|r| r.id)
+    .into_iter()
+    .map(|(_, fields)| {
+        let fields = fields.collect::<Vec<_>>();
+        let first = &fields[0];
+        let at = first.at.to_timezone(*TZ.get().unwrap());
+        json!({
+            "at": at.format(format_description!("[year]-[month]-[day] [hour]:[minute]:[second]")).unwrap_or_default(),
+            "actor": first.actor_name,
+            "action": first.action,
+            "what": match &first.worker_name {
+                Some(name) => format!("Time sheet of {name}"),
+                None => "Job".to_string(),
+            },
+            "fields": fields.iter().map(|f| json!({
+                "field": f.field,
+                "old": f.old,
+                "new": f.new,
+            })).collect::<Vec<_>>(),
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R History",
+        "admin": user.role.is_staff(),
+        "logged_in": true,
+        "job_id": form.job,
+        "worker_id": form.worker,
+        "changes": changes,
+    });
+
+    Ok(RenderHtml("history.hbs", engine, data))
+}
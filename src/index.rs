@@ -2,7 +2,7 @@ use std::convert::Infallible;
 use std::env;
 
 use crate::Backend;
-use crate::{errors::CustomError, AppEngine, AppState, Job, JobWorker};
+use crate::{errors::CustomError, now, AppEngine, AppState, Job, JobWorker};
 use axum::{
     extract::{Path, State},
     response::{Html, IntoResponse, Redirect},
@@ -23,7 +23,7 @@ pub(crate) async fn index(
 
     mut auth: AuthSession<Backend>,
 ) -> Result<impl IntoResponse, Infallible> {
-    let admin = auth.user.as_ref().map_or(false, |w| w.admin);
+    let admin = auth.user.as_ref().is_some_and(|w| w.role().is_staff());
     let logged_in = auth.user.is_some();
 
     let jobs = query_scalar!(
@@ -40,7 +40,7 @@ pub(crate) async fn index(
 
     let workers = query_scalar!(
         r#"
-        select count(*) from users where admin = false and deactivated = false;   
+        select count(*) from users where role in ('worker', 'crew_lead') and deactivated = false;
     "#
     )
     .fetch_one(&pool)
@@ -70,7 +70,7 @@ pub(crate) async fn index(
     .fetch_one(&pool)
     .await;
     let days = match earliest {
-        Ok(v) => (time::OffsetDateTime::now_utc().date() - v).whole_days(),
+        Ok(v) => (now().date() - v).whole_days(),
         _ => 0,
     };
 
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
+use anyhow::anyhow;
 use axum::{
     debug_handler,
     extract::State,
-    response::{Html, IntoResponse, Redirect},
+    response::{Html, IntoResponse, Redirect, Response},
     Form,
 };
 use axum_template::RenderHtml;
@@ -12,76 +13,219 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 use sqlx::{
     query, query_as, query_builder, types::time::Date, Execute, Pool, QueryBuilder, Sqlite,
+    SqliteConnection,
 };
 use std::result::Result::Ok;
+use time::{macros::format_description, Time};
 use tracing::{info, trace};
 
+use crate::attachments::{delete_files, detach_attachments, job_attachments};
+use crate::audit::{audit, Requester};
+use crate::conflicts::{db_time, find_conflicts, unavailable_workers};
+use crate::dispatch::publish;
+use crate::history::{crew_snapshots, job_snapshot, record, record_crew};
+use crate::roles::{can, Allowed, Permission, Role};
+use crate::teams::team_options;
+use crate::validation::{checked, rerender, FieldErrors};
+use crate::versions::{Field, Stale};
+use crate::Backend;
 use crate::{errors::CustomError, AppState, Job};
-use crate::{get_admin, Backend};
 use axum_login::AuthSession;
 use git_version::git_version;
 
 #[derive(Deserialize)]
 pub(crate) struct JobEditPage {
     id: Option<i64>,
+    blocked: Option<String>,
 }
 
-pub(crate) async fn jobeditpage(
-    State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
-    Form(form): Form<JobEditPage>,
-) -> Result<impl IntoResponse, CustomError> {
-    get_admin(&auth)?;
+fn parse_time(s: &Option<String>) -> Result<Option<Time>, time::error::Parse> {
+    match s.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(s) => Time::parse(s, format_description!("[hour]:[minute]")).map(Some),
+    }
+}
+
+fn format_time(t: Option<Time>) -> String {
+    t.and_then(|t| t.format(format_description!("[hour]:[minute]")).ok())
+        .unwrap_or_default()
+}
+
+fn parse_ids(s: &Option<String>) -> Vec<i64> {
+    s.as_deref()
+        .unwrap_or_default()
+        .split('-')
+        .filter_map(|n| n.parse::<i64>().ok())
+        .collect()
+}
+
+/// The inputs on the job form, by name
+const JOB_FIELDS: [&str; 5] = ["sitename", "date", "starttime", "endtime", "max_crew"];
 
-    let this_job = match form.id {
+/// A job form that didn't go through, to show again as it was typed
+struct Submitted {
+    values: Value,
+    assigned: HashMap<i64, bool>,
+    leads: Vec<i64>,
+    errors: FieldErrors,
+}
+
+async fn job_page(
+    pool: &Pool<Sqlite>,
+    my_id: i64,
+    job_id: Option<i64>,
+    blocked: Vec<i64>,
+    submitted: Option<Submitted>,
+) -> Result<Value, CustomError> {
+    let this_job = match job_id {
         Some(id) => Some(
             query_as!(Job, "select * from jobs where id = $1", id)
-                .fetch_one(&pool)
+                .fetch_one(pool)
                 .await?,
         ),
         None => None,
     };
 
-    let workers = query!("select id, name from users where users.deactivated = false;")
-        .fetch_all(&pool)
+    let workers = query!("select id, name, role from users where users.deactivated = false;")
+        .fetch_all(pool)
         .await?
         .into_iter()
-        .map(|r| (r.id, r.name))
+        .map(|r| {
+            let can_lead = r
+                .role
+                .parse::<Role>()
+                .is_ok_and(|role| role.can(Permission::LeadCrews));
+            (r.id, r.name, can_lead)
+        })
         .collect::<Vec<_>>();
 
-    let assigned_fr = match form.id {
-        Some(id) => query!(
-            r#"select users.id, jobworkers.using_flat_rate from users
+    let crew = match job_id {
+        Some(id) => {
+            query!(
+                r#"select users.id, jobworkers.using_flat_rate, jobworkers.lead from users
         inner join jobworkers
         on users.id = jobworkers.worker
         where jobworkers.job = $1
         and users.deactivated = false;
         "#,
-            id
-        )
-        .fetch_all(&pool)
-        .await?
-        .into_iter()
-        .fold(HashMap::new(), |mut acc, x| {
-            acc.entry(x.id).or_insert(x.using_flat_rate);
-            acc
-        }),
+                id
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        None => vec![],
+    };
+    let assigned_fr = crew.iter().fold(HashMap::new(), |mut acc, x| {
+        acc.entry(x.id).or_insert(x.using_flat_rate);
+        acc
+    });
+
+    let conflicts = match &this_job {
+        Some(job) => {
+            let mut to_check = assigned_fr.keys().copied().collect::<Vec<_>>();
+            to_check.extend(blocked.iter().filter(|w| !assigned_fr.contains_key(w)));
+            find_conflicts(
+                &mut *pool.acquire().await?,
+                Some(job.id),
+                job.date,
+                (db_time(&job.starttime), db_time(&job.endtime)),
+                &to_check,
+            )
+            .await?
+            .into_iter()
+            .map(|c| {
+                json!({
+                    "worker_id": c.worker_id,
+                    "description": c.description,
+                    "other_job": c.other_job,
+                    "blocked": blocked.contains(&c.worker_id),
+                })
+            })
+            .collect::<Vec<_>>()
+        }
+        None => vec![],
+    };
+
+    let unavailable = match &this_job {
+        Some(job) => {
+            unavailable_workers(
+                &mut *pool.acquire().await?,
+                job.date,
+                (db_time(&job.starttime), db_time(&job.endtime)),
+            )
+            .await?
+        }
         None => HashMap::new(),
     };
 
+    let attachments = match &this_job {
+        Some(job) => job_attachments(pool, job.id, None).await?,
+        None => vec![],
+    };
+
+    let (values, assigned_fr, leads, errors) = match submitted {
+        Some(Submitted {
+            values,
+            assigned,
+            leads,
+            errors,
+        }) => (values, assigned, leads, errors),
+        None => (
+            match &this_job {
+                Some(job) => json!({
+                    "sitename": job.sitename,
+                    "workorder": job.workorder,
+                    "servicecode": job.servicecode,
+                    "address": job.address,
+                    "date": job.date.to_string(),
+                    "starttime": format_time(db_time(&job.starttime)),
+                    "endtime": format_time(db_time(&job.endtime)),
+                    "notes": job.notes,
+                    "open": job.open,
+                    "max_crew": job.max_crew.to_string(),
+                    "needs_confirmation": job.needs_confirmation,
+                    "version": job.version,
+                }),
+                None => json!({
+                    "sitename": "",
+                    "workorder": "",
+                    "servicecode": "",
+                    "address": "",
+                    "date": "",
+                    "starttime": "",
+                    "endtime": "",
+                    "notes": "",
+                    "open": false,
+                    "max_crew": "1",
+                    "needs_confirmation": false,
+                    "version": null,
+                }),
+            },
+            assigned_fr,
+            crew.iter().filter(|x| x.lead).map(|x| x.id).collect(),
+            FieldErrors::default(),
+        ),
+    };
+
     let list_data = workers
         .into_iter()
-        .map(|(id, name)| {
+        .map(|(id, name, can_lead)| {
             (
                 id,
                 name,
                 assigned_fr.contains_key(&id),
-                assigned_fr.get(&id).map_or(false, |v| *v),
+                assigned_fr.get(&id).is_some_and(|v| *v),
+                unavailable
+                    .get(&id)
+                    .map(|(_, reason)| reason.clone())
+                    .unwrap_or_default(),
+                leads.contains(&id),
+                can_lead,
             )
         })
         .collect::<Vec<_>>();
 
-    let data = json!({
+    Ok(json!({
     "git_ver": git_version!(),
         "title": "Job Edit",
         "admin": true,
@@ -89,18 +233,26 @@ pub(crate) async fn jobeditpage(
         "job": ({if let Some(job) = this_job {
             json!({
                 "id": job.id,
-                "sitename": job.sitename,
-                "workorder": job.workorder,
-                "servicecode": job.servicecode,
-                "address": job.address,
-                "date": job.date.to_string(),
-                "notes": job.notes,
             })
         } else {
             Value::Null
         }}),
-        "list-data": list_data
-    });
+        "values": values,
+        "errors": errors.view(&JOB_FIELDS),
+        "list-data": list_data,
+        "teams": team_options(pool, None).await?,
+        "conflicts": conflicts,
+        "attachments": attachments,
+        "me": my_id,
+    }))
+}
+
+pub(crate) async fn jobeditpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    Allowed { id: my_id, .. }: Allowed<can::ScheduleJobs>,
+    Form(form): Form<JobEditPage>,
+) -> Result<impl IntoResponse, CustomError> {
+    let data = job_page(&pool, my_id, form.id, parse_ids(&form.blocked), None).await?;
 
     Ok(RenderHtml("jobedit.hbs", engine, data))
 }
@@ -111,20 +263,163 @@ pub(crate) struct JobEditForm {
     servcode: String,
     workorder: String,
     address: String,
-    date: Date,
+    date: String,
+    starttime: Option<String>,
+    endtime: Option<String>,
     assigned: String,
     flatrate: String,
+    #[serde(default)]
+    lead: String,
+    force: Option<String>,
     jobid: Option<i64>,
     notes: String,
+    open: Option<String>,
+    #[serde(default)]
+    max_crew: String,
+    needs_confirmation: Option<String>,
+    version: Option<i64>,
+}
+
+fn ids(s: &str) -> Vec<i64> {
+    s.split('-')
+        .filter_map(|n| n.parse::<i64>().ok())
+        .sorted()
+        .collect()
+}
+
+/// Makes `leads` the crew leads on a job, and nobody else
+async fn set_leads(
+    conn: &mut SqliteConnection,
+    job_id: i64,
+    leads: &[i64],
+) -> Result<(), sqlx::Error> {
+    query!("update jobworkers set lead = false where job = $1;", job_id)
+        .execute(&mut *conn)
+        .await?;
+    for lead in leads {
+        query!(
+            "update jobworkers set lead = true where job = $1 and worker = $2;",
+            job_id,
+            lead
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    trace!("crew leads on job {} are users {:?}", job_id, leads);
+    Ok(())
+}
+
+/// What's saved on a job now, next to what was sent from an older copy of
+/// its form
+async fn stale_job(
+    pool: &Pool<Sqlite>,
+    form: &JobEditForm,
+    job_id: i64,
+) -> Result<Stale, CustomError> {
+    let job = query_as!(Job, "select * from jobs where id = $1", job_id)
+        .fetch_one(pool)
+        .await?;
+
+    let crew = query!(
+        "select worker, using_flat_rate, lead from jobworkers where job = $1 order by worker;",
+        job_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let assigned = crew.iter().map(|r| r.worker).collect::<Vec<_>>();
+    let flatrate = crew
+        .iter()
+        .filter(|r| r.using_flat_rate)
+        .map(|r| r.worker)
+        .collect::<Vec<_>>();
+    let leads = crew
+        .iter()
+        .filter(|r| r.lead)
+        .map(|r| r.worker)
+        .collect::<Vec<_>>();
+
+    let names = query!("select id, name from users;")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.name))
+        .collect::<HashMap<_, _>>();
+    let crew_field = |name, label, mine: Vec<i64>, theirs: Vec<i64>| {
+        let shown = |ids: &[i64]| match ids {
+            [] => "Nobody".to_string(),
+            ids => ids
+                .iter()
+                .map(|id| names.get(id).cloned().unwrap_or_else(|| id.to_string()))
+                .join(", "),
+        };
+        Field::text(name, label, mine.iter().join("-"), theirs.iter().join("-"))
+            .shown(shown(&mine), shown(&theirs))
+    };
+
+    Ok(Stale {
+        what: format!("Job {job_id}"),
+        action: "/admin/api/v1/edit-job",
+        back: format!("/jobedit?id={job_id}"),
+        fields: vec![
+            Field::text("sitename", "Site name", &form.sitename, job.sitename),
+            Field::text("servcode", "Service code", &form.servcode, job.servicecode),
+            Field::text("workorder", "Work order", &form.workorder, job.workorder),
+            Field::text("address", "Address", &form.address, job.address),
+            Field::text("date", "Date", &form.date, job.date.to_string()),
+            Field::text(
+                "starttime",
+                "Start time",
+                form.starttime.clone().unwrap_or_default(),
+                format_time(db_time(&job.starttime)),
+            ),
+            Field::text(
+                "endtime",
+                "End time",
+                form.endtime.clone().unwrap_or_default(),
+                format_time(db_time(&job.endtime)),
+            ),
+            Field::flag("open", "Open for claiming", checked(&form.open), job.open),
+            Field::text(
+                "max_crew",
+                "Crew size",
+                &form.max_crew,
+                job.max_crew.to_string(),
+            ),
+            Field::flag(
+                "needs_confirmation",
+                "Claims need confirmation",
+                checked(&form.needs_confirmation),
+                job.needs_confirmation,
+            ),
+            crew_field("assigned", "Assigned", ids(&form.assigned), assigned),
+            crew_field("flatrate", "Flat rate", ids(&form.flatrate), flatrate),
+            crew_field("lead", "Crew leads", ids(&form.lead), leads),
+            Field::text("notes", "Job notes", &form.notes, job.notes),
+        ],
+        carried: vec![
+            ("jobid", job_id.to_string()),
+            ("force", form.force.clone().unwrap_or_default()),
+            ("version", job.version.to_string()),
+        ],
+    })
 }
 
 pub(crate) async fn jobedit(
-    State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    State(AppState {
+        pool,
+        engine,
+        events,
+        attachments,
+        ..
+    }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+    from: Requester,
     Form(form): Form<JobEditForm>,
-) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
-
+) -> Result<Response, CustomError> {
     let to_assign = form
         .assigned
         .split('-')
@@ -135,16 +430,154 @@ pub(crate) async fn jobedit(
         .split('-')
         .filter_map(|n| n.parse::<i64>().ok())
         .collect::<Vec<_>>();
-    let to_assign = to_assign
+    let mut to_assign = to_assign
         .iter()
         .map(|x| (*x, to_flatrt.contains(x)))
         .collect::<Vec<_>>();
+    let to_lead = ids(&form.lead);
+
+    let open = checked(&form.open);
+    let needs_confirmation = checked(&form.needs_confirmation);
+
+    let mut errors = FieldErrors::default();
+    errors.required("sitename", "The site name", &form.sitename);
+    let date = Date::parse(
+        form.date.trim(),
+        format_description!("[year]-[month]-[day]"),
+    )
+    .map_err(|_| errors.add("date", "Pick the day of the job"))
+    .ok();
+    let starttime = parse_time(&form.starttime)
+        .map_err(|_| errors.add("starttime", "Write the time like 08:30"))
+        .ok()
+        .flatten();
+    let endtime = parse_time(&form.endtime)
+        .map_err(|_| errors.add("endtime", "Write the time like 16:30"))
+        .ok()
+        .flatten();
+    if let (Some(s), Some(e)) = (starttime, endtime) {
+        if e <= s {
+            errors.add("endtime", "The job must end after it starts");
+        }
+    }
+    let max_crew = match form.max_crew.trim() {
+        "" => 1,
+        crew => errors.number::<i64>("max_crew", crew).max(1),
+    };
+
+    let Some(date) = date.filter(|_| errors.is_empty()) else {
+        let values = json!({
+            "sitename": form.sitename,
+            "workorder": form.workorder,
+            "servicecode": form.servcode,
+            "address": form.address,
+            "date": form.date,
+            "starttime": form.starttime.unwrap_or_default(),
+            "endtime": form.endtime.unwrap_or_default(),
+            "notes": form.notes,
+            "open": open,
+            "max_crew": form.max_crew,
+            "needs_confirmation": needs_confirmation,
+            "version": form.version,
+        });
+        let data = job_page(
+            &pool,
+            my_id,
+            form.jobid,
+            vec![],
+            Some(Submitted {
+                values,
+                assigned: to_assign.into_iter().collect(),
+                leads: to_lead,
+                errors,
+            }),
+        )
+        .await?;
+        return Ok(rerender("jobedit.hbs", engine, data));
+    };
+
+    // only new assignments are checked, existing ones are shown as warnings on the page
+    let already_assigned = match form.jobid {
+        Some(job_id) => query!("select worker from jobworkers where job = $1;", job_id)
+            .fetch_all(&pool)
+            .await?
+            .into_iter()
+            .map(|r| r.worker)
+            .collect::<Vec<_>>(),
+        None => vec![],
+    };
+    let forced = parse_ids(&form.force);
+    let newly_assigned = to_assign
+        .iter()
+        .map(|x| x.0)
+        .filter(|w| !already_assigned.contains(w))
+        .collect::<Vec<_>>();
+
+    let conflicts = find_conflicts(
+        &mut *pool.acquire().await?,
+        form.jobid,
+        date,
+        (starttime, endtime),
+        &newly_assigned,
+    )
+    .await?;
+
+    let blocked = conflicts
+        .iter()
+        .map(|c| c.worker_id)
+        .filter(|w| !forced.contains(w))
+        .unique()
+        .collect::<Vec<_>>();
+
+    for c in conflicts.iter().filter(|c| forced.contains(&c.worker_id)) {
+        info!(
+            "admin {my_name} (id {my_id}) overrode a scheduling conflict: {}",
+            c.description
+        );
+    }
+
+    to_assign.retain(|x| !blocked.contains(&x.0));
+
+    // only someone on the crew whose role allows it can lead it
+    let can_lead = query!("select id, role from users;")
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .filter(|r| {
+            r.role
+                .parse::<Role>()
+                .is_ok_and(|role| role.can(Permission::LeadCrews))
+        })
+        .map(|r| r.id)
+        .collect::<Vec<_>>();
+    let to_lead = to_lead
+        .into_iter()
+        .filter(|w| can_lead.contains(w) && to_assign.iter().any(|x| x.0 == *w))
+        .collect::<Vec<_>>();
+
+    let redirect = |job_id: i64| {
+        if blocked.is_empty() {
+            Redirect::to(format!("/jobedit?id={}", job_id).as_str())
+        } else {
+            Redirect::to(
+                format!(
+                    "/jobedit?id={}&blocked={}",
+                    job_id,
+                    blocked.iter().join("-")
+                )
+                .as_str(),
+            )
+        }
+    };
 
     if let Some(job_id) = form.jobid {
         let mut tx = pool.begin().await?;
 
-        //update job itself
-        query!(
+        let job_before = job_snapshot(&mut tx, job_id).await?;
+        let crew_before = crew_snapshots(&mut tx, job_id, None).await?;
+
+        //update job itself, unless it's been saved since this form was loaded
+        let updated = query!(
             r#"
         update jobs set 
             sitename = $2,
@@ -152,18 +585,37 @@ pub(crate) async fn jobedit(
             servicecode = $4,
             address = $5,
             date = $6,
-            notes = $7
-        where id = $1;"#,
+            notes = $7,
+            starttime = $8,
+            endtime = $9,
+            open = $10,
+            max_crew = $11,
+            needs_confirmation = $12,
+            version = version + 1
+        where id = $1 and version = $13;"#,
             job_id,
             form.sitename,
             form.workorder,
             form.servcode,
             form.address,
-            form.date,
-            form.notes
+            date,
+            form.notes,
+            starttime,
+            endtime,
+            open,
+            max_crew,
+            needs_confirmation,
+            form.version
         )
         .execute(&mut *tx)
-        .await?;
+        .await?
+        .rows_affected();
+
+        if updated == 0 {
+            drop(tx);
+            info!("admin {my_name} (id {my_id}) tried to save job {job_id} from a stale copy of its form");
+            return Ok(stale_job(&pool, &form, job_id).await?.render(engine, true));
+        }
 
         let currently_assigned = query!(
             r#"
@@ -180,53 +632,69 @@ pub(crate) async fn jobedit(
         .map(|v| (v.worker, v.using_flat_rate))
         .collect::<Vec<_>>();
 
-        let flatrates_to_remove = currently_assigned
+        // workers who stay on the job keep their row, so their time sheet and
+        // its version are left alone
+        let is_assigned = |w: i64| currently_assigned.iter().any(|x| x.0 == w);
+
+        let flatrates_to_change = to_assign
             .iter()
-            .filter(|x| x.1)
-            .filter(|x| to_assign.contains(&(x.0, false)))
-            .map(|x| x.0)
+            .filter(|x| currently_assigned.iter().any(|c| c.0 == x.0 && c.1 != x.1))
             .collect::<Vec<_>>();
 
         let assignments_to_remove = currently_assigned
             .iter()
-            .filter(|x| !to_assign.contains(&(x.0, false)) || !to_assign.contains(&(x.0, true)))
             .map(|x| x.0)
+            .filter(|w| !to_assign.iter().any(|x| x.0 == *w))
             .collect::<Vec<_>>();
 
         let assignments_to_add = to_assign
             .iter()
-            .filter(|x| {
-                !currently_assigned.contains(&(x.0, false))
-                    || !currently_assigned.contains(&(x.0, true))
-            })
+            .filter(|x| !is_assigned(x.0))
             .collect::<Vec<_>>();
 
-        //remove flatrates
-        let csl = flatrates_to_remove.iter().join(",");
-        let query = QueryBuilder::new("update jobworkers set using_flat_rate = false where job = ")
-            .push_bind(job_id)
-            .push(" and worker in (")
-            .push_bind(csl)
-            .push(")")
-            .build()
+        //change flatrates
+        for (worker, flat_rate) in &flatrates_to_change {
+            query!(
+                "update jobworkers set using_flat_rate = $1 where job = $2 and worker = $3;",
+                flat_rate,
+                job_id,
+                worker
+            )
             .execute(&mut *tx)
             .await?;
+        }
         trace!(
-            "removed flat-rate flags on job {} for users {:?}",
+            "changed flat-rate flags on job {} for users {:?}",
             job_id,
-            &flatrates_to_remove
+            &flatrates_to_change
         );
 
-        //remove assignments
-        let csl = assignments_to_remove.iter().join(",");
-        let query = QueryBuilder::new("delete from jobworkers where job = ")
-            .push_bind(job_id)
-            .push(" and worker in (")
-            .push_bind(csl)
-            .push(")")
-            .build()
+        //remove assignments, along with the time, checklist and files recorded on them
+        let mut orphaned = vec![];
+        for worker in &assignments_to_remove {
+            orphaned.extend(detach_attachments(&mut tx, job_id, Some(*worker)).await?);
+            query!(
+                "delete from punches where job = $1 and worker = $2;",
+                job_id,
+                worker
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                "delete from assignment_checks where job = $1 and worker = $2;",
+                job_id,
+                worker
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!(
+                "delete from jobworkers where job = $1 and worker = $2;",
+                job_id,
+                worker
+            )
             .execute(&mut *tx)
             .await?;
+        }
         trace!(
             "removed assignments on job {} for users {:?}",
             job_id,
@@ -256,8 +724,24 @@ pub(crate) async fn jobedit(
             );
         }
 
-        tx.commit().await?;
+        set_leads(&mut tx, job_id, &to_lead).await?;
 
+        let job_after = job_snapshot(&mut tx, job_id).await?;
+        record(&mut tx, job_id, None, my_id, job_before, job_after).await?;
+        let crew_after = crew_snapshots(&mut tx, job_id, None).await?;
+        record_crew(&mut tx, job_id, my_id, crew_before, crew_after).await?;
+
+        audit(
+            &mut tx,
+            &from,
+            my_id,
+            "changed job",
+            &format!("job {job_id} ({} on {date})", form.sitename),
+        )
+        .await?;
+        tx.commit().await?;
+        delete_files(&attachments, orphaned).await;
+        publish(&events, job_id, None, "edit");
         info!(
             "admin {my_name} (id {my_id}) updated job {job_id}:\n
 site name: {}\n
@@ -265,26 +749,47 @@ workorder: {}\n
 service code: {}\n
 address: {}\n
 date: {}\n
+time: {} - {}\n
+open: {} (crew of {}, confirmation {})\n
 notes: {}",
-            form.sitename, form.workorder, form.servcode, form.address, form.date, form.notes
+            form.sitename,
+            form.workorder,
+            form.servcode,
+            form.address,
+            date,
+            format_time(starttime),
+            format_time(endtime),
+            open,
+            max_crew,
+            if needs_confirmation {
+                "required"
+            } else {
+                "not required"
+            },
+            form.notes
         );
 
-        return Ok(Redirect::to(format!("/jobedit?id={}", job_id).as_str()));
+        Ok(redirect(job_id).into_response())
     } else {
         let mut tx = pool.begin().await?;
 
         //create job
         let job_id: i64 = query!(
             r#"
-        insert into jobs (sitename, workorder, servicecode, address, date, notes) values
-                ($1, $2, $3, $4, $5, $6)
+        insert into jobs (sitename, workorder, servicecode, address, date, notes, starttime, endtime, open, max_crew, needs_confirmation) values
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
             returning id;"#,
             form.sitename,
             form.workorder,
             form.servcode,
             form.address,
-            form.date,
-            form.notes
+            date,
+            form.notes,
+            starttime,
+            endtime,
+            open,
+            max_crew,
+            needs_confirmation
         )
         .fetch_one(&mut *tx)
         .await?
@@ -297,8 +802,24 @@ workorder: {}\n
 service code: {}\n
 address: {}\n
 date: {}\n
+time: {} - {}\n
+open: {} (crew of {}, confirmation {})\n
 notes: {}",
-            form.sitename, form.workorder, form.servcode, form.address, form.date, form.notes
+            form.sitename,
+            form.workorder,
+            form.servcode,
+            form.address,
+            date,
+            format_time(starttime),
+            format_time(endtime),
+            open,
+            max_crew,
+            if needs_confirmation {
+                "required"
+            } else {
+                "not required"
+            },
+            form.notes
         );
 
         //create assignments w/ flatrates
@@ -315,8 +836,24 @@ notes: {}",
             query.execute(&mut *tx).await?;
         }
 
+        set_leads(&mut tx, job_id, &to_lead).await?;
+
+        let job_after = job_snapshot(&mut tx, job_id).await?;
+        record(&mut tx, job_id, None, my_id, None, job_after).await?;
+        let crew_after = crew_snapshots(&mut tx, job_id, None).await?;
+        record_crew(&mut tx, job_id, my_id, HashMap::new(), crew_after).await?;
+
+        audit(
+            &mut tx,
+            &from,
+            my_id,
+            "created job",
+            &format!("job {job_id} ({} on {date})", form.sitename),
+        )
+        .await?;
         tx.commit().await?;
-        return Ok(Redirect::to(format!("/jobedit?id={}", job_id).as_str()));
+        publish(&events, job_id, None, "create");
+        Ok(redirect(job_id).into_response())
     }
 }
 
@@ -326,11 +863,23 @@ pub(crate) struct JobDeleteForm {
 }
 
 pub(crate) async fn jobdelete(
-    State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    State(AppState {
+        pool,
+        events,
+        attachments,
+        ..
+    }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+    from: Requester,
     Form(form): Form<JobDeleteForm>,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
+    let mut tx = pool.begin().await?;
+    let job_before = job_snapshot(&mut tx, form.jobid).await?;
+    let crew_before = crew_snapshots(&mut tx, form.jobid, None).await?;
 
     query!(
         r#"
@@ -340,7 +889,53 @@ pub(crate) async fn jobdelete(
     "#,
         form.jobid
     )
-    .execute(&pool)
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+    delete from jobclaims
+        where
+        job = $1;
+    "#,
+        form.jobid
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+    delete from punches
+        where
+        job = $1;
+    "#,
+        form.jobid
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let orphaned = detach_attachments(&mut tx, form.jobid, None).await?;
+
+    query!(
+        r#"
+    delete from assignment_checks
+        where
+        job = $1;
+    "#,
+        form.jobid
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+    delete from signatures
+        where
+        job = $1;
+    "#,
+        form.jobid
+    )
+    .execute(&mut *tx)
     .await?;
 
     query!(
@@ -351,10 +946,24 @@ pub(crate) async fn jobdelete(
     "#,
         form.jobid
     )
-    .execute(&pool)
-    .await
-    .unwrap();
+    .execute(&mut *tx)
+    .await?;
+
+    record(&mut tx, form.jobid, None, my_id, job_before, None).await?;
+    record_crew(&mut tx, form.jobid, my_id, crew_before, HashMap::new()).await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "deleted job",
+        &format!("job {}", form.jobid),
+    )
+    .await?;
+    tx.commit().await?;
 
+    delete_files(&attachments, orphaned).await;
+    publish(&events, form.jobid, None, "delete");
     info!(
         "admin {} (id {}) deleted job {}",
         my_name, my_id, form.jobid
@@ -1,6 +1,8 @@
 use std::collections::BTreeMap;
 
-use crate::{empty_string_as_none, errors::CustomError, now, AppState, TZ_OFFSET};
+use crate::roles::Permission;
+use crate::teams::team_options;
+use crate::{empty_string_as_none, errors::CustomError, now, AppState};
 use crate::{get_user, Backend};
 use axum::{
     extract::State,
@@ -38,6 +40,7 @@ struct JobQueryOutput {
     miles_driven: Option<f64>,
     hours_driven: Option<f64>,
     extraexpcents: Option<i64>,
+    open: bool,
 }
 
 #[derive(Serialize, Deserialize, FromRow)]
@@ -52,6 +55,7 @@ pub struct JobData {
     pub work_order: String,
     pub service_code: String,
     pub status: String,
+    pub open: bool,
 }
 
 impl JobData {
@@ -72,6 +76,7 @@ impl JobData {
                 notes: j.notes,
                 work_order: j.workorder,
                 service_code: j.servicecode,
+                open: j.open,
                 status: {
                     match (j.signin, j.signout) {
                         (None, None) => {
@@ -119,6 +124,8 @@ pub(crate) struct JobListForm {
     pub completed: Option<bool>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub workers: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub team: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -143,7 +150,7 @@ pub(crate) async fn joblistpage(
     mut auth: AuthSession<Backend>,
     Form(form): Form<JobListForm>,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (id, _my_name, admin) = get_user(&auth)?;
+    let (id, _my_name, role) = get_user(&auth)?;
 
     let start_date = if let Some(d) = form.start_date {
         d
@@ -186,7 +193,7 @@ pub(crate) async fn joblistpage(
         jobworkers.notes as workernotes, jobworkers.signin, 
         jobworkers.miles_driven, jobworkers.hours_driven,
         jobworkers.extraexpcents, jobworkers.signout, jobs.sitename, jobs.address, 
-        jobs.date, jobs.notes, jobs.workorder, jobs.servicecode
+        jobs.date, jobs.notes, jobs.workorder, jobs.servicecode, jobs.open
         from jobs inner join jobworkers
                 on jobs.id = jobworkers.job
                 inner join users
@@ -199,7 +206,7 @@ pub(crate) async fn joblistpage(
     query_builder.push(" and date(jobs.date) <= ");
     query_builder.push_bind(&end_date);
 
-    if admin && form.workers.is_some() {
+    if role.can(Permission::ViewAllJobs) && form.workers.is_some() {
         query_builder.push(" and jobworkers.worker in (");
         for (idx, id) in parsed_workers.iter().enumerate() {
             query_builder.push_bind(id);
@@ -213,6 +220,13 @@ pub(crate) async fn joblistpage(
         query_builder.push_bind(id);
     }
 
+    if let Some(team) = form.team.filter(|_| role.can(Permission::ViewAllJobs)) {
+        query_builder
+            .push(" and jobworkers.worker in (select worker from team_members where team = ");
+        query_builder.push_bind(team);
+        query_builder.push(") ");
+    }
+
     if let Some(site_name) = &form.site_name {
         query_builder.push(" and jobs.sitename ilike concat('%', ");
         query_builder.push_bind(site_name);
@@ -258,7 +272,7 @@ pub(crate) async fn joblistpage(
             jobs.sitename, jobs.address, jobs.date, time(0) as signin, 
             time(0) as signout, '' as workernotes,
             jobs.notes, jobs.workorder, jobs.servicecode, 0.0 as miles_driven,
-            0.0 as hours_driven, 0 as extraexpcents from jobs 
+            0.0 as hours_driven, 0 as extraexpcents, jobs.open from jobs 
 
             where not exists (
                 select *
@@ -275,11 +289,14 @@ pub(crate) async fn joblistpage(
         .await;
         if let Ok(mut orphans) = query {
             r = {
-                if !orphans.is_empty() {
-                    warn!(
-                        "orphan jobs returned in search: {:?}",
-                        orphans.iter().map(|j| j.id).collect::<Vec<_>>()
-                    );
+                // open jobs are waiting to be claimed, so they aren't really orphans
+                let unclaimable = orphans
+                    .iter()
+                    .filter(|j| !j.open)
+                    .map(|j| j.id)
+                    .collect::<Vec<_>>();
+                if !unclaimable.is_empty() {
+                    warn!("orphan jobs returned in search: {:?}", unclaimable);
                 }
                 orphans.append(&mut r);
                 orphans
@@ -313,8 +330,9 @@ pub(crate) async fn joblistpage(
     let data = serde_json::json!({
     "git_ver": git_version!(),
         "title": "CZ4R Job List",
-        "admin": admin,
+        "admin": role.is_staff(),
         "logged_in": true,
+        "user_id": id,
         "count": &job_datas.len(),
         "job_datas": job_datas,
         "params": SearchParams {
@@ -326,6 +344,7 @@ pub(crate) async fn joblistpage(
             fieldnotes: form.notes.unwrap_or_default(),
             workers
         },
+        "teams": team_options(&pool, form.team).await?,
         "order": form.order.unwrap_or(Order::Latest),
         "assigned": assigned,
         "started": started,
@@ -0,0 +1,680 @@
+use crate::audit::{audit, Requester};
+use crate::dispatch::publish;
+use crate::punches::{record_clock, ClockAction};
+use crate::roles::{can, Allowed};
+use crate::sites::site_tz;
+use crate::validation::{rerender, FieldErrors};
+use crate::{errors::CustomError, now_in, AppState};
+use crate::{get_user, Backend};
+use axum::{
+    extract::State,
+    http::{
+        header::{COOKIE, SET_COOKIE},
+        HeaderMap,
+    },
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use base64::Engine;
+use git_version::git_version;
+use itertools::Itertools;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::{thread_rng, RngCore};
+use scrypt::{Params, Scrypt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, Pool, Sqlite};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+const KIOSK_COOKIE: &str = "cz4r_kiosk";
+
+/// Wrong PINs allowed for one worker in `LOCKOUT_MINUTES` before their PIN
+/// stops being taken, on any kiosk
+const MAX_FAILURES: i64 = 5;
+const LOCKOUT_MINUTES: i64 = 10;
+
+/// A site's shared device
+struct Kiosk {
+    id: i64,
+    name: String,
+    sitename: String,
+}
+
+/// Device tokens are long and random so they don't need a slow hash
+fn token_params() -> Params {
+    Params::new(10, 8, 1, Params::RECOMMENDED_LEN).unwrap()
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v)
+}
+
+/// Checks a `{id}.{secret}` device token against the kiosk it names
+async fn kiosk_for_token(pool: &Pool<Sqlite>, token: &str) -> Result<Option<Kiosk>, CustomError> {
+    let Some((id, secret)) = token.split_once('.') else {
+        return Ok(None);
+    };
+    let Ok(id) = id.parse::<i64>() else {
+        return Ok(None);
+    };
+
+    let Some(row) = query!(
+        r#"
+        select id, name, sitename, token_hash from kiosks
+            where id = $1 and revoked = false;
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let hash = PasswordHash::new(&row.token_hash)?;
+    if Scrypt.verify_password(secret.as_bytes(), &hash).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some(Kiosk {
+        id: row.id,
+        name: row.name,
+        sitename: row.sitename,
+    }))
+}
+
+/// The kiosk this device was authorized as, anything else gets nothing
+async fn kiosk_from(pool: &Pool<Sqlite>, headers: &HeaderMap) -> Result<Kiosk, CustomError> {
+    let kiosk = match cookie_value(headers, KIOSK_COOKIE) {
+        Some(token) => kiosk_for_token(pool, token).await?,
+        None => None,
+    };
+    kiosk
+        .ok_or_else(|| CustomError::Forbidden("This device is not an authorized kiosk".to_string()))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ActivateForm {
+    token: String,
+}
+
+/// Opened once on the device with the link an admin made for it
+pub(crate) async fn activate(
+    State(AppState { pool, .. }): State<AppState>,
+    Form(form): Form<ActivateForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let Some(kiosk) = kiosk_for_token(&pool, &form.token).await? else {
+        warn!("a device tried to activate a kiosk with a bad token");
+        return Err(CustomError::Forbidden(
+            "This kiosk link is not valid, ask an admin for a new one".to_string(),
+        ));
+    };
+
+    info!(
+        "kiosk {} (id {}) at {} was activated",
+        kiosk.name, kiosk.id, kiosk.sitename
+    );
+
+    let cookie = format!(
+        "{KIOSK_COOKIE}={}; Path=/kiosk; Max-Age=31536000; HttpOnly; Secure; SameSite=Strict",
+        form.token
+    );
+    Ok(([(SET_COOKIE, cookie)], Redirect::to("/kiosk")))
+}
+
+/// Today's jobs at the kiosk's site, with everyone assigned to them
+pub(crate) async fn kioskpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, CustomError> {
+    let kiosk = kiosk_from(&pool, &headers).await?;
+
+    let today = now_in(site_tz(&pool, &kiosk.sitename).await?).date();
+
+    let rows = query!(
+        r#"
+        select jobs.id, jobs.workorder, users.id as worker, users.name,
+            jobworkers.signin,
+            (select kind from punches
+                where punches.job = jobs.id and punches.worker = users.id
+                and punches.punchout is null) as "open_kind?: String"
+            from jobs inner join jobworkers
+            on jobs.id = jobworkers.job
+            inner join users
+            on jobworkers.worker = users.id
+        where jobs.sitename = $1 and jobs.date = $2
+        order by jobs.starttime asc, jobs.id asc, users.name asc;
+        "#,
+        kiosk.sitename,
+        today
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let jobs = rows
+        .into_iter()
+        .chunk_by(|r| (r.id, r.workorder.clone()))
+        .into_iter()
+        .map(|((id, workorder), workers)| {
+            let workers = workers
+                .map(|w| {
+                    let state = match (w.open_kind.as_deref(), &w.signin) {
+                        (Some("break"), _) => "on break",
+                        (Some(_), _) => "working",
+                        (None, Some(_)) => "done",
+                        (None, None) => "not started",
+                    };
+                    json!({"id": w.worker, "name": w.name, "state": state})
+                })
+                .collect::<Vec<_>>();
+            json!({"id": id, "workorder": workorder, "workers": workers})
+        })
+        .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Kiosk",
+        "kiosk": kiosk.name,
+        "sitename": kiosk.sitename,
+        "jobs": jobs,
+    });
+
+    Ok(RenderHtml("kiosk.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct KioskPinForm {
+    job: i64,
+    worker: i64,
+}
+
+/// The PIN pad for one worker on one job
+pub(crate) async fn kioskpinpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<KioskPinForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let kiosk = kiosk_from(&pool, &headers).await?;
+
+    let Some(row) = query!(
+        r#"
+        select users.name,
+            (select kind from punches
+                where punches.job = jobs.id and punches.worker = users.id
+                and punches.punchout is null) as "open_kind?: String"
+            from jobs inner join jobworkers
+            on jobs.id = jobworkers.job
+            inner join users
+            on jobworkers.worker = users.id
+        where jobs.id = $1 and users.id = $2 and jobs.sitename = $3;
+        "#,
+        form.job,
+        form.worker,
+        kiosk.sitename
+    )
+    .fetch_optional(&pool)
+    .await?
+    else {
+        return Ok(Redirect::to("/kiosk").into_response());
+    };
+
+    let actions = match row.open_kind.as_deref() {
+        Some("break") => vec![("resume", "End break"), ("out", "Clock out")],
+        Some(_) => vec![("break", "Start break"), ("out", "Clock out")],
+        None => vec![("in", "Clock in")],
+    };
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Kiosk",
+        "kiosk": kiosk.name,
+        "sitename": kiosk.sitename,
+        "job": form.job,
+        "worker": form.worker,
+        "name": row.name,
+        "actions": actions
+            .into_iter()
+            .map(|(value, label)| json!({"value": value, "label": label}))
+            .collect::<Vec<_>>(),
+    });
+
+    Ok(RenderHtml("kioskpin.hbs", engine, data).into_response())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct KioskPunchForm {
+    job: i64,
+    worker: i64,
+    pin: String,
+    action: ClockAction,
+}
+
+async fn pin_matches(pool: &Pool<Sqlite>, worker: i64, pin: &str) -> Result<bool, CustomError> {
+    let row = query!(
+        "select pin_hash from users where id = $1 and deactivated = false;",
+        worker
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(Some(hash)) = row.map(|r| r.pin_hash) else {
+        return Ok(false);
+    };
+    let hash = PasswordHash::new(&hash)?;
+    Ok(Scrypt.verify_password(pin.as_bytes(), &hash).is_ok())
+}
+
+pub(crate) async fn kiosk_punch(
+    State(AppState {
+        pool,
+        engine,
+        events,
+        ..
+    }): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<KioskPunchForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let kiosk = kiosk_from(&pool, &headers).await?;
+
+    let done = |message: String, ok: bool| {
+        json!({
+        "git_ver": git_version!(),
+            "title": "CZ4R Kiosk",
+            "kiosk": kiosk.name,
+            "sitename": kiosk.sitename,
+            "message": message,
+            "ok": ok,
+        })
+    };
+
+    // every try is counted as a failure up front, in the same statement that
+    // checks there are tries left, so guesses sent at once can't all get in
+    // before the lockout. It's taken back if the PIN matches.
+    let window = format!("-{LOCKOUT_MINUTES} minutes");
+    let attempt = query!(
+        r#"
+        insert into kiosk_failures (kiosk, worker)
+            select $1, users.id from users
+            where users.id = $2
+                and (select count(*) from kiosk_failures
+                    where worker = $2 and at > datetime('now', $3)) < $4
+            returning id;
+        "#,
+        kiosk.id,
+        form.worker,
+        window,
+        MAX_FAILURES
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(attempt) = attempt else {
+        warn!(
+            "kiosk {} (id {}) turned away a PIN for user {}, who is locked out or doesn't exist",
+            kiosk.name, kiosk.id, form.worker
+        );
+        let data = done(
+            format!("Too many wrong PINs, try again in {LOCKOUT_MINUTES} minutes"),
+            false,
+        );
+        return Ok(RenderHtml("kioskdone.hbs", engine, data));
+    };
+
+    let on_site = query!(
+        r#"
+        select jobs.id from jobs inner join jobworkers
+            on jobs.id = jobworkers.job
+        where jobs.id = $1 and jobworkers.worker = $2 and jobs.sitename = $3;
+        "#,
+        form.job,
+        form.worker,
+        kiosk.sitename
+    )
+    .fetch_optional(&pool)
+    .await?
+    .is_some();
+
+    if !on_site || !pin_matches(&pool, form.worker, &form.pin).await? {
+        info!(
+            "kiosk {} (id {}) got a wrong PIN for user {}",
+            kiosk.name, kiosk.id, form.worker
+        );
+        let data = done("That PIN didn't match".into(), false);
+        return Ok(RenderHtml("kioskdone.hbs", engine, data));
+    }
+
+    query!("delete from kiosk_failures where id = $1;", attempt.id)
+        .execute(&pool)
+        .await?;
+    query!(
+        "update kiosks set last_used = current_timestamp where id = $1;",
+        kiosk.id
+    )
+    .execute(&pool)
+    .await?;
+
+    let data = match record_clock(
+        &pool,
+        form.job,
+        form.worker,
+        form.worker,
+        None,
+        form.action,
+        None,
+        OffsetDateTime::now_utc(),
+        false,
+    )
+    .await
+    {
+        Ok(stamp) => {
+            publish(&events, form.job, Some(form.worker), "checkinout");
+            info!(
+                "kiosk {} (id {}) clocked {:?} for user {} on job {} at {stamp}",
+                kiosk.name, kiosk.id, form.action, form.worker, form.job
+            );
+            let time = format!("{:02}:{:02}", stamp.hour(), stamp.minute());
+            let message = match form.action {
+                ClockAction::In => format!("Clocked in at {time}"),
+                ClockAction::Break => format!("Break started at {time}"),
+                ClockAction::Resume => format!("Break ended at {time}"),
+                ClockAction::Out => format!("Clocked out at {time}"),
+            };
+            done(message, true)
+        }
+        Err(e) => done(e.to_string(), false),
+    };
+
+    Ok(RenderHtml("kioskdone.hbs", engine, data))
+}
+
+pub(crate) async fn kiosksadminpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ManageSettings>,
+) -> Result<impl IntoResponse, CustomError> {
+    Ok(RenderHtml(
+        "kiosks.hbs",
+        engine,
+        kiosks_data(&pool, None, None).await?,
+    ))
+}
+
+/// The inputs on the new kiosk form, by name
+const KIOSK_FIELDS: [&str; 2] = ["name", "sitename"];
+
+/// The kiosks page. When a new kiosk didn't go through, `submitted` is shown
+/// in the form again with its errors.
+async fn kiosks_data(
+    pool: &Pool<Sqlite>,
+    activation_link: Option<String>,
+    submitted: Option<(&CreateKioskForm, &FieldErrors)>,
+) -> Result<Value, CustomError> {
+    let kiosks = query!(
+        r#"
+        select kiosks.id, kiosks.name, kiosks.sitename, kiosks.created_at, kiosks.last_used,
+            users.name as created_by
+            from kiosks inner join users
+            on kiosks.created_by = users.id
+        where kiosks.revoked = false
+        order by kiosks.sitename asc, kiosks.name asc;
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|k| {
+        json!({
+            "id": k.id,
+            "name": k.name,
+            "sitename": k.sitename,
+            "created_at": k.created_at.to_string(),
+            "last_used": k.last_used.map(|t| t.to_string()).unwrap_or_default(),
+            "created_by": k.created_by,
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let sites = query!("select distinct sitename from jobs order by sitename asc;")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| r.sitename)
+        .collect::<Vec<_>>();
+
+    let (values, errors) = match submitted {
+        Some((form, errors)) => (
+            json!({ "name": form.name, "sitename": form.sitename }),
+            errors.view(&KIOSK_FIELDS),
+        ),
+        None => (
+            json!({ "name": "", "sitename": "" }),
+            FieldErrors::default().view(&KIOSK_FIELDS),
+        ),
+    };
+
+    Ok(json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Kiosks",
+        "admin": true,
+        "logged_in": true,
+        "kiosks": kiosks,
+        "sites": sites,
+        "activation_link": activation_link,
+        "values": values,
+        "errors": errors,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateKioskForm {
+    name: String,
+    sitename: String,
+}
+
+/// Makes a kiosk and shows its activation link, the only time the token is shown
+pub(crate) async fn create_kiosk(
+    State(AppState {
+        pool,
+        engine,
+        site_url,
+        ..
+    }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<CreateKioskForm>,
+) -> Result<Response, CustomError> {
+    let mut errors = FieldErrors::default();
+    let name = errors.required("name", "A kiosk's name", &form.name);
+    let sitename = errors.required("sitename", "The site", &form.sitename);
+    if !errors.is_empty() {
+        let data = kiosks_data(&pool, None, Some((&form, &errors))).await?;
+        return Ok(rerender("kiosks.hbs", engine, data));
+    }
+
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    let secret = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret);
+    let salt = SaltString::generate(&mut thread_rng());
+    let token_hash = Scrypt
+        .hash_password_customized(secret.as_bytes(), None, None, token_params(), &salt)?
+        .to_string();
+
+    let mut tx = pool.begin().await?;
+
+    let id = query!(
+        r#"
+        insert into kiosks (name, sitename, token_hash, created_by)
+            values ($1, $2, $3, $4)
+            returning id;
+        "#,
+        name,
+        sitename,
+        token_hash,
+        my_id
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .id;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "created kiosk",
+        &format!("kiosk {id} ({name}) at {sitename}"),
+    )
+    .await?;
+    tx.commit().await?;
+    info!("admin {my_name} (id {my_id}) made kiosk {name} (id {id}) for {sitename}");
+
+    let link = format!("https://{site_url}/kiosk/activate?token={id}.{secret}");
+
+    Ok(RenderHtml(
+        "kiosks.hbs",
+        engine,
+        kiosks_data(&pool, Some(link), None).await?,
+    )
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeKioskForm {
+    id: i64,
+}
+
+pub(crate) async fn revoke_kiosk(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<RevokeKioskForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut tx = pool.begin().await?;
+
+    query!("update kiosks set revoked = true where id = $1;", form.id)
+        .execute(&mut *tx)
+        .await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "revoked kiosk",
+        &format!("kiosk {}", form.id),
+    )
+    .await?;
+    tx.commit().await?;
+    info!("admin {my_name} (id {my_id}) revoked kiosk {}", form.id);
+
+    Ok(Redirect::to("/admin/kiosks"))
+}
+
+pub(crate) async fn pinpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<PinPageForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, _, role) = get_user(&auth)?;
+
+    let data = pin_data(
+        &pool,
+        my_id,
+        role.is_staff(),
+        form.saved == Some(true),
+        &FieldErrors::default(),
+    )
+    .await?;
+
+    Ok(RenderHtml("pin.hbs", engine, data))
+}
+
+/// The inputs on the PIN form, by name
+const PIN_FIELDS: [&str; 2] = ["pin1", "pin2"];
+
+async fn pin_data(
+    pool: &Pool<Sqlite>,
+    my_id: i64,
+    admin: bool,
+    saved: bool,
+    errors: &FieldErrors,
+) -> Result<Value, CustomError> {
+    let has_pin = query!("select pin_hash from users where id = $1;", my_id)
+        .fetch_one(pool)
+        .await?
+        .pin_hash
+        .is_some();
+
+    Ok(json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Kiosk PIN",
+        "admin": admin,
+        "logged_in": true,
+        "has_pin": has_pin,
+        "saved": saved,
+        "errors": errors.view(&PIN_FIELDS),
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PinPageForm {
+    saved: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PinForm {
+    pin1: String,
+    pin2: String,
+}
+
+pub(crate) async fn set_pin(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<PinForm>,
+) -> Result<Response, CustomError> {
+    let (my_id, my_name, role) = get_user(&auth)?;
+
+    // the PINs aren't sent back, they have to be typed again
+    let mut errors = FieldErrors::default();
+    if !(4..=8).contains(&form.pin1.len()) || !form.pin1.chars().all(|c| c.is_ascii_digit()) {
+        errors.add("pin1", "A PIN has to be 4 to 8 digits");
+    } else if form.pin1 != form.pin2 {
+        errors.add("pin2", "The PINs don't match");
+    }
+    if !errors.is_empty() {
+        let data = pin_data(&pool, my_id, role.is_staff(), false, &errors).await?;
+        return Ok(rerender("pin.hbs", engine, data));
+    }
+
+    let salt = SaltString::generate(&mut thread_rng());
+    let hash = Scrypt
+        .hash_password(form.pin1.as_bytes(), salt.as_salt())?
+        .to_string();
+    let salt = salt.as_str();
+
+    query!(
+        "update users set pin_hash = $1, pin_salt = $2 where id = $3;",
+        hash,
+        salt,
+        my_id
+    )
+    .execute(&pool)
+    .await?;
+
+    info!("user {my_name} (id {my_id}) set their kiosk PIN");
+
+    Ok(Redirect::to("/pin?saved=true").into_response())
+}
@@ -1,7 +1,8 @@
 use std::convert::Infallible;
 
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed};
 use crate::AppState;
 
 use super::Worker;
@@ -35,11 +36,35 @@ use tracing::warn;
 pub struct LoginForm {
     pub username: String,
     pub password: String,
+    /// The page to go back to after logging in
+    #[serde(default)]
+    pub next: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct LoginPageForm {
     failure: Option<bool>,
+    next: Option<String>,
+}
+
+/// Only a page on this site can be gone back to after logging in
+fn local_path(next: Option<String>) -> Option<String> {
+    next.filter(|n| n.starts_with('/') && !n.starts_with("//") && !n.contains('\\'))
+}
+
+/// The login page, going on to `next` once logged in
+pub(crate) fn to_login(next: Option<&str>, failure: bool) -> Redirect {
+    let mut params = vec![];
+    if failure {
+        params.push(("failure", "true"));
+    }
+    if let Some(next) = next {
+        params.push(("next", next));
+    }
+    match serde_urlencoded::to_string(&params) {
+        Ok(query) if !query.is_empty() => Redirect::to(&format!("/loginpage?{query}")),
+        _ => Redirect::to("/loginpage"),
+    }
 }
 
 pub async fn loginpage(
@@ -50,14 +75,15 @@ pub async fn loginpage(
     Form(form): Form<LoginPageForm>,
 ) -> Result<impl IntoResponse, Infallible> {
     let logged_in = auth.user.is_some();
-    let admin = auth.user.as_ref().map_or(false, |w| w.admin);
+    let admin = auth.user.as_ref().is_some_and(|w| w.role().is_staff());
 
     let data = serde_json::json!({
     "git_ver": git_version!(),
         "title": "CZ4R Login",
         "admin": admin,
         "logged_in": logged_in,
-        "failure": form.failure == Some(true)
+        "failure": form.failure == Some(true),
+        "next": local_path(form.next),
     });
 
     Ok(RenderHtml("login.hbs", engine, data))
@@ -71,7 +97,13 @@ pub(crate) async fn login(
     }): State<AppState>,
     Form(login_form): Form<LoginForm>, //Extension(worker): Extension<Worker>
 ) -> Redirect {
-    let LoginForm { username, password } = login_form;
+    let LoginForm {
+        username,
+        password,
+        next,
+    } = login_form;
+    let next = local_path(next);
+    let failed = || to_login(next.as_deref(), true);
 
     let mut conn = pool.acquire().await.unwrap();
 
@@ -86,7 +118,7 @@ pub(crate) async fn login(
             "user {} can't log in because there is nobody with that name in the database",
             &username
         );
-        return Redirect::to("/loginpage?failure=true");
+        return failed();
     };
 
     if worker.deactivated {
@@ -94,7 +126,7 @@ pub(crate) async fn login(
             "user {} (id {}) can't log in because they are deactivated",
             &worker.name, &worker.id
         );
-        return Redirect::to("/loginpage?failure=true");
+        return failed();
     }
 
     if worker.must_change_pw {
@@ -114,7 +146,7 @@ pub(crate) async fn login(
             "user {} (id {}) can't log in because they have an invalid salt",
             &worker.name, &worker.id
         );
-        return Redirect::to("/loginpage?failure=true");
+        return failed();
     };
 
     let hash = Scrypt
@@ -157,9 +189,9 @@ pub(crate) async fn login(
     }
 
     if failure {
-        Redirect::to("/loginpage?failure=true")
+        failed()
     } else {
-        Redirect::to("/")
+        Redirect::to(next.as_deref().unwrap_or("/"))
     }
 }
 
@@ -171,15 +203,20 @@ pub struct LogoutForm {
 #[axum::debug_handler]
 pub async fn logout_user(
     State(AppState { pool, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
+    from: Requester,
     Form(form): Form<LogoutForm>,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
-
-    let me = (&auth).user.clone().unwrap();
-    let user = &auth.backend.get_user(&form.id).await?;
+    let user = query!("select id, name from users where id = $1;", form.id)
+        .fetch_optional(&pool)
+        .await?;
 
     if let Some(u) = user {
+        let mut tx = pool.begin().await?;
         query!(
             r#"
         update users
@@ -188,8 +225,21 @@ pub async fn logout_user(
         "#,
             u.id
         )
-        .execute(&pool)
+        .execute(&mut *tx)
+        .await?;
+        audit(
+            &mut tx,
+            &from,
+            my_id,
+            "logged out worker",
+            &format!("user {} ({})", u.id, u.name),
+        )
         .await?;
+        tx.commit().await?;
+        info!(
+            "admin {my_name} (id {my_id}) logged out user {} (id {})",
+            u.name, u.id
+        );
     }
 
     Ok(())
@@ -7,7 +7,7 @@ use async_trait::async_trait;
 use axum::{
     debug_handler,
     error_handling::HandleErrorLayer,
-    extract::{Extension, FromRef, Path, State},
+    extract::{DefaultBodyLimit, Extension, FromRef, Path, State},
     http::StatusCode,
     response::{Html, IntoResponse, Redirect},
     routing::{get, post, put},
@@ -28,6 +28,7 @@ use login::{loginpage, LoginForm};
 use password_hash::{PasswordHasher, Salt, SaltString};
 use r#static::static_handler;
 use rand::{thread_rng, Rng};
+use roles::{Permission, Role};
 use rust_embed::RustEmbed;
 use scrypt::Scrypt;
 use serde::{de, Deserialize, Deserializer, Serialize};
@@ -44,35 +45,58 @@ use std::{
     sync::{Arc, OnceLock},
 };
 use std::{fs::File, future::IntoFuture};
-use time::{OffsetDateTime, Time, UtcOffset};
+use time::{OffsetDateTime, Time};
+use time_tz::{timezones, OffsetDateTimeExt, TimeZone, Tz};
 use tokio::runtime::Builder;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use tower::ServiceBuilder;
 use tower_http::trace::{self, TraceLayer};
 use tower_sessions_sqlx_store::{sqlx::SqlitePool, SqliteStore};
 use tracing::Level;
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 use tracing_subscriber::{filter, EnvFilter, Layer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod admin;
+mod attachments;
+mod audit;
+mod calendar;
 mod change_pw;
 mod change_worker;
 mod checkinout;
+mod checklists;
 mod config;
+mod conflicts;
 mod create_worker;
 mod deactivate;
+mod dispatch;
 mod error404;
 mod errors;
 mod export_db;
+mod history;
 mod index;
 mod jobedit;
 mod joblist;
+mod kiosk;
 mod login;
+mod mileage;
+mod offline;
+mod openjobs;
+mod punches;
+mod qr;
 mod reset_pw;
 mod restore;
+mod roles;
+mod servicecodes;
+mod settings;
 mod shutdown;
+mod signatures;
+mod sites;
 mod r#static;
+mod teams;
+mod timeoff;
+mod validation;
+mod versions;
 mod workerdata;
 mod workeredit;
 
@@ -85,6 +109,12 @@ pub struct Job {
     address: String,
     date: Date,
     notes: String,
+    starttime: Option<String>,
+    endtime: Option<String>,
+    open: bool,
+    max_crew: i64,
+    needs_confirmation: bool,
+    version: i64,
 }
 
 #[derive(Debug, Default, Clone, sqlx::FromRow, Serialize)]
@@ -93,7 +123,7 @@ pub struct Worker {
     name: String,
     hash: String,
     salt: String,
-    admin: bool,
+    role: String,
     address: String,
     phone: String,
     email: String,
@@ -104,6 +134,9 @@ pub struct Worker {
     must_change_pw: bool,
     deactivated: bool,
     logged_out: bool,
+    pin_hash: Option<String>,
+    pin_salt: Option<String>,
+    version: i64,
 }
 
 #[derive(Debug, Default, Clone, sqlx::FromRow)]
@@ -118,6 +151,7 @@ pub struct JobWorker {
     extraexpcents: i64,
     notes: String,
     using_flat_rate: bool,
+    version: i64,
 }
 
 type AppEngine = Engine<Handlebars<'static>>;
@@ -127,6 +161,17 @@ pub struct AppState {
     pool: Pool<Sqlite>,
     engine: AppEngine,
     db_url: String,
+    events: broadcast::Sender<dispatch::DispatchEvent>,
+    attachments: attachments::Store,
+    /// The host links sent out of the app point at
+    #[from_ref(skip)]
+    site_url: String,
+}
+
+impl Worker {
+    fn role(&self) -> Role {
+        self.role.parse().unwrap_or(Role::Worker)
+    }
 }
 
 impl AuthUser for Worker {
@@ -230,7 +275,24 @@ impl AuthnBackend for Backend {
     }
 }
 
-pub static TZ_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+/// Key for signing links the server hands out, like QR codes
+pub static SIGNING_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// The server's time zone, used for sites without one of their own
+pub static TZ: OnceLock<&'static Tz> = OnceLock::new();
+
+/// The time zone the machine is set to, by the name /etc/localtime links to
+/// or /etc/timezone holds
+fn system_zone() -> Option<&'static Tz> {
+    let name = std::fs::read_link("/etc/localtime")
+        .ok()
+        .and_then(|p| Some(p.to_str()?.split_once("zoneinfo/")?.1.to_string()))
+        .or_else(|| std::fs::read_to_string("/etc/timezone").ok())?;
+    timezones::get_by_name(name.trim())
+}
+
+/// The templates, for responses made outside a handler, like error pages
+pub static ENGINE: OnceLock<AppEngine> = OnceLock::new();
 
 fn main() {
     let _ = dotenvy::dotenv();
@@ -243,8 +305,22 @@ fn main() {
 
     debug!("logging initialized");
 
-    let tz_offset = TZ_OFFSET.get_or_init(|| OffsetDateTime::now_local().unwrap().offset());
-    info!("The timezone offset is {tz_offset}");
+    // every date and time the app shows depends on it, so it doesn't start
+    // without one it can trust
+    let tz = TZ.get_or_init(|| match env::var("TZ") {
+        Ok(name) => {
+            let name = name.trim_start_matches(':');
+            timezones::get_by_name(name).unwrap_or_else(|| {
+                error!("TZ {name} is not a time zone in the tz database, set it to one like America/New_York");
+                std::process::exit(1)
+            })
+        }
+        Err(_) => system_zone().unwrap_or_else(|| {
+            error!("TZ is not set and the system's time zone can't be found, set TZ to one like America/New_York");
+            std::process::exit(1)
+        }),
+    });
+    info!("The time zone is {}", tz.name());
 
     let rt = Builder::new_multi_thread().enable_all().build().unwrap();
 
@@ -283,6 +359,8 @@ async fn app() {
     handlebars_helper!(neq: |a: Value, b: Value| a != b);
     hbs.register_helper("eq", Box::new(eq));
     hbs.register_helper("neq", Box::new(neq));
+    let engine: AppEngine = Engine::from(hbs);
+    ENGINE.set(engine.clone()).ok();
 
     let config = config::Config::new().await;
 
@@ -293,14 +371,16 @@ async fn app() {
 
     let Config {
         database_url,
-        login_secret: _,
+        login_secret,
         port,
-        site_url: _,
+        site_url,
         backup_task,
         session_ttl,
         session_check_time,
     } = config;
 
+    SIGNING_KEY.set(login_secret).unwrap();
+
     let backend = Backend::new(backend_pool);
 
     let session_store = SqliteStore::new(auth_pool);
@@ -318,11 +398,44 @@ async fn app() {
 
     let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer.clone()).build();
 
+    let (events, _) = broadcast::channel(64);
+
+    let attachments = attachments::Store::from_env().await;
+
     let admin_only = Router::new()
         .route("/admin", get(admin::admin))
         .route("/admin/worker-edit", get(workeredit::workeredit))
         .route("/admin/worker-data", get(workerdata::workerdatapage))
         .route("/admin/restore", get(restore::restorepage))
+        .route("/admin/conflicts", get(conflicts::conflictspage))
+        .route("/admin/timeoff", get(timeoff::timeoffadminpage))
+        .route("/admin/dispatch", get(dispatch::dispatchpage))
+        .route("/admin/settings", get(settings::settingspage))
+        .route("/admin/api/v1/settings", post(settings::settings))
+        .route("/admin/sites", get(sites::sitespage))
+        .route("/admin/api/v1/edit-site", post(sites::edit_site))
+        .route("/admin/servicecodes", get(servicecodes::servicecodespage))
+        .route(
+            "/admin/api/v1/edit-servicecode",
+            post(servicecodes::edit_servicecode),
+        )
+        .route("/admin/checklists", get(checklists::checklistreport))
+        .route(
+            "/admin/api/v1/add-checklist-item",
+            post(checklists::add_item),
+        )
+        .route(
+            "/admin/api/v1/retire-checklist-item",
+            post(checklists::retire_item),
+        )
+        .route("/admin/qr", get(qr::qrpage))
+        .route("/admin/kiosks", get(kiosk::kiosksadminpage))
+        .route("/admin/api/v1/create-kiosk", post(kiosk::create_kiosk))
+        .route("/admin/api/v1/revoke-kiosk", post(kiosk::revoke_kiosk))
+        .route(
+            "/admin/api/v1/dispatch-events",
+            get(dispatch::dispatch_events),
+        )
         .route(
             "/admin/api/v1/create-worker",
             post(create_worker::create_worker),
@@ -343,7 +456,17 @@ async fn app() {
             "/admin/api/v1/export-database.sql",
             get(export_db::export_db),
         )
-        .route("/admin/api/v1/reset-pw", post(reset_pw::reset_pw));
+        .route("/admin/api/v1/reset-pw", post(reset_pw::reset_pw))
+        .route(
+            "/admin/api/v1/decide-timeoff",
+            post(timeoff::decide_timeoff),
+        )
+        .route("/admin/api/v1/decide-claim", post(openjobs::decide_claim))
+        .route("/admin/teams", get(teams::teamspage))
+        .route("/admin/api/v1/edit-team", post(teams::edit_team))
+        .route("/admin/api/v1/delete-team", post(teams::delete_team))
+        .route("/admin/audit", get(audit::auditpage))
+        .route("/admin/api/v1/audit-log.csv", get(audit::export_audit));
 
     let app = Router::new()
         .route("/", get(index::index))
@@ -353,17 +476,54 @@ async fn app() {
         .route("/login", post(login::login))
         .route("/logout", post(login::logout))
         .route("/checkinout", get(checkinout::checkinoutpage))
+        .route("/history", get(history::historypage))
         .route("/change-pw", get(change_pw::change_pw_page))
         .route("/api/v1/change-pw", post(change_pw::change_pw))
         .route("/api/v1/checkinout", post(checkinout::checkinout))
+        .route("/api/v1/clock", post(punches::clock))
+        .route("/api/v1/punch", post(punches::punch))
+        .route("/api/v1/signature", post(signatures::sign))
+        .route("/api/v1/checklist", post(checklists::check_item))
+        .route("/signature", get(signatures::signature))
+        .route(
+            "/api/v1/attachments",
+            post(attachments::upload).layer(DefaultBodyLimit::max(
+                attachments::MAX_ATTACHMENT_BYTES + 64 * 1024,
+            )),
+        )
+        .route("/attachment", get(attachments::attachment))
+        .route(
+            "/api/v1/delete-attachment",
+            post(attachments::delete_attachment),
+        )
+        .route("/api/v1/offline-sync", post(offline::offline_sync))
+        .route("/calendar", get(calendar::calendarpage))
+        .route("/timeoff", get(timeoff::timeoffpage))
+        .route("/api/v1/request-timeoff", post(timeoff::request_timeoff))
+        .route("/api/v1/cancel-timeoff", post(timeoff::cancel_timeoff))
+        .route("/api/v1/set-availability", post(timeoff::set_availability))
+        .route("/openjobs", get(openjobs::openjobspage))
+        .route("/api/v1/claim-job", post(openjobs::claim_job))
+        .route("/api/v1/release-job", post(openjobs::release_job))
+        .route("/scan", get(qr::scan))
+        .route("/pin", get(kiosk::pinpage))
+        .route("/api/v1/pin", post(kiosk::set_pin))
+        .route("/kiosk", get(kiosk::kioskpage))
+        .route("/kiosk/activate", get(kiosk::activate))
+        .route("/kiosk/punch", get(kiosk::kioskpinpage))
+        .route("/kiosk/api/v1/punch", post(kiosk::kiosk_punch))
         .merge(admin_only)
         .fallback(error404::error404)
+        .layer(axum::middleware::from_fn(errors::negotiate))
         .layer(auth_layer)
         //.layer(session_layer)
         .with_state(AppState {
             pool: app_pool,
-            engine: Engine::from(hbs),
+            engine,
             db_url: database_url,
+            events,
+            attachments,
+            site_url,
         });
 
     // run it
@@ -373,9 +533,12 @@ async fn app() {
     info!("listening on {}", addr);
 
     let backup_handle = backup_task.as_ref().map(|t| t.abort_handle());
-    let server = axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle(), backup_handle))
-        .into_future();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle(), backup_handle))
+    .into_future();
     if let Some(backup_task) = backup_task {
         let (_, _, _) = join!(server, backup_task, deletion_task);
     } else {
@@ -394,7 +557,12 @@ where
 }
 
 pub fn now() -> OffsetDateTime {
-    OffsetDateTime::now_utc().to_offset(*TZ_OFFSET.get().unwrap())
+    now_in(TZ.get().unwrap())
+}
+
+/// The current time in `tz`, converted for this instant so DST changes apply
+pub fn now_in(tz: &Tz) -> OffsetDateTime {
+    OffsetDateTime::now_utc().to_timezone(tz)
 }
 
 pub fn empty_string_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
@@ -411,22 +579,10 @@ where
     }
 }
 
-pub fn get_user(auth: &AuthSession<Backend>) -> Result<(i64, &str, bool), CustomError> {
-    if let Some((id, name, admin)) = auth.user.iter().map(|u| (u.id, &u.name, u.admin)).next() {
-        Ok((id, name, admin))
-    } else {
-        Err(CustomError(anyhow!("Not logged in")))
-    }
-}
-
-pub fn get_admin(auth: &AuthSession<Backend>) -> Result<(i64, &str), CustomError> {
-    let (id, name, admin) = get_user(&auth)?;
-    if admin {
-        Ok((id, name))
+pub fn get_user(auth: &AuthSession<Backend>) -> Result<(i64, &str, Role), CustomError> {
+    if let Some((id, name, role)) = auth.user.iter().map(|u| (u.id, &u.name, u.role())).next() {
+        Ok((id, name, role))
     } else {
-        Err(CustomError(anyhow!(
-            "User {} does not have administrator privileges",
-            id
-        )))
+        Err(CustomError::NotAuthenticated)
     }
 }
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use crate::punches::Punch;
+use crate::sites::{distance_m, instant};
+use itertools::Itertools;
+use sqlx::types::time::Date;
+use time::OffsetDateTime;
+
+const METERS_PER_MILE: f64 = 1609.344;
+
+/// Straight-line travel to a job from the worker's previous job that day
+#[derive(Debug, Clone, Copy)]
+pub struct Travel {
+    pub miles: f64,
+    pub hours: f64,
+}
+
+impl Travel {
+    /// Entered driving more than `ratio` times the suggestion needs a look
+    pub fn outlier(&self, miles: f64, hours: f64, ratio: f64) -> bool {
+        miles > self.miles * ratio || hours > self.hours * ratio
+    }
+}
+
+/// Where and when a worker started and finished a job
+#[derive(Clone, Copy)]
+struct Visit {
+    job: i64,
+    date: Date,
+    start: OffsetDateTime,
+    start_at: Option<(f64, f64)>,
+    end: Option<OffsetDateTime>,
+    end_at: Option<(f64, f64)>,
+}
+
+fn visit(job: i64, segments: &[Punch]) -> Option<Visit> {
+    let first = segments.iter().min_by_key(|p| p.punchin)?;
+    let last = segments
+        .iter()
+        .filter(|p| p.punchout.is_some())
+        .max_by_key(|p| p.punchout);
+    Some(Visit {
+        job,
+        date: first.job_date,
+        start: instant(first.punchin, first.tz),
+        start_at: first.in_lat.zip(first.in_lon),
+        end: last.and_then(|p| p.punchout.map(|out| instant(out, p.tz))),
+        end_at: last.and_then(|p| p.out_lat.zip(p.out_lon)),
+    })
+}
+
+/// Suggested travel for each job that follows another on the same day, from
+/// where the worker clocked out of one job to where they clocked in at the
+/// next. Jobs without both locations get no suggestion.
+pub(crate) fn suggested_travel(punches: &HashMap<i64, Vec<Punch>>) -> HashMap<i64, Travel> {
+    punches
+        .iter()
+        .filter_map(|(job, segments)| visit(*job, segments))
+        .sorted_by_key(|v| v.start)
+        .tuple_windows()
+        .filter(|(from, to)| from.date == to.date)
+        .filter_map(|(from, to)| {
+            let end = from.end.filter(|end| *end <= to.start)?;
+            let meters = distance_m(from.end_at?, to.start_at?);
+            Some((
+                to.job,
+                Travel {
+                    miles: meters / METERS_PER_MILE,
+                    hours: (to.start - end).as_seconds_f64() / 3600.,
+                },
+            ))
+        })
+        .collect()
+}
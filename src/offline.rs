@@ -0,0 +1,271 @@
+use crate::checkinout::{save_time_sheet, CheckInOutForm, Saved};
+use crate::checklists::check_item;
+use crate::punches::{clock_at, save_punch, Punched};
+use crate::roles::{can, SelfOr};
+use crate::signatures::sign_at;
+use crate::sites::job_tz;
+use crate::{errors::CustomError, AppState};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Form, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::query;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::{info, warn};
+
+/// How far ahead of the server's clock a phone's clock can be
+const MAX_CLOCK_SKEW: Duration = Duration::minutes(5);
+/// How long a phone can hold on to a form before it's too old to apply
+const MAX_QUEUED_AGE: Duration = Duration::days(7);
+/// How long a replay can take before it's taken to have died with the server,
+/// and the next one gets to apply it
+const PENDING_TIMEOUT: Duration = Duration::minutes(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum QueuedKind {
+    Clock,
+    Checkinout,
+    Punch,
+    Signature,
+    Checklist,
+}
+
+impl QueuedKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QueuedKind::Clock => "clock",
+            QueuedKind::Checkinout => "checkinout",
+            QueuedKind::Punch => "punch",
+            QueuedKind::Signature => "signature",
+            QueuedKind::Checklist => "checklist",
+        }
+    }
+}
+
+/// A form the service worker held on to while the phone was offline
+#[derive(Deserialize)]
+pub(crate) struct QueuedSubmission {
+    /// Made by the phone once per submission, replays reuse it
+    key: String,
+    kind: QueuedKind,
+    /// When the worker pressed the button, by the phone's clock
+    captured_at: String,
+    /// The form as it would have been posted
+    body: String,
+    /// Who was logged in on the phone when it was saved
+    owner: Option<i64>,
+}
+
+/// The job every queued form is about
+#[derive(Deserialize)]
+struct QueuedJob {
+    JobId: i64,
+}
+
+/// A queued form that doesn't parse now won't on a retry either
+fn unreadable(e: serde_urlencoded::de::Error) -> CustomError {
+    CustomError::Validation(format!("The queued form couldn't be read: {e}"))
+}
+
+/// Applies a queued form, answering with the version a time sheet was saved as
+async fn apply(
+    state: &AppState,
+    user: &SelfOr<can::EditTimesheets>,
+    sub: &QueuedSubmission,
+    captured_at: OffsetDateTime,
+) -> Result<Option<i64>, CustomError> {
+    let now = OffsetDateTime::now_utc();
+    if captured_at > now + MAX_CLOCK_SKEW {
+        return Err(CustomError::Validation(
+            "It was captured in the future, check the phone's clock".to_string(),
+        ));
+    }
+    if captured_at < now - MAX_QUEUED_AGE {
+        return Err(CustomError::Validation(format!(
+            "It was captured more than {} days ago, too long to still be applied",
+            MAX_QUEUED_AGE.whole_days()
+        )));
+    }
+
+    let QueuedJob { JobId: job } = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+    let date = query!("select date from jobs where id = $1;", job)
+        .fetch_optional(&state.pool)
+        .await?
+        .ok_or_else(|| CustomError::NotFound(format!("There is no job {job}")))?
+        .date;
+    if captured_at
+        .to_timezone(job_tz(&state.pool, job).await?)
+        .date()
+        < date
+    {
+        return Err(CustomError::Validation(format!(
+            "It was captured before the day of the job, {date}"
+        )));
+    }
+
+    match sub.kind {
+        QueuedKind::Clock => {
+            let form = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+            clock_at(state, user, form, captured_at, true).await?;
+        }
+        QueuedKind::Checkinout => {
+            let form: CheckInOutForm = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+            match save_time_sheet(state, user, form).await? {
+                Saved::Yes(_, version) => return Ok(Some(version)),
+                Saved::Unreadable(errors) | Saved::Blocked(errors) => {
+                    return Err(CustomError::Validation(errors.summary()));
+                }
+                Saved::Stale(stale) => {
+                    return Err(CustomError::Conflict(format!(
+                        "{} was changed by someone else",
+                        stale.what
+                    )));
+                }
+            }
+        }
+        QueuedKind::Punch => {
+            let form = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+            if let Punched::Rejected(errors) = save_punch(state, user, &form).await? {
+                return Err(CustomError::Validation(errors.summary()));
+            }
+        }
+        QueuedKind::Signature => {
+            let form = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+            sign_at(state, user, form, captured_at, true).await?;
+        }
+        QueuedKind::Checklist => {
+            let form = serde_urlencoded::from_str(&sub.body).map_err(unreadable)?;
+            check_item(State(state.clone()), user.with(), Form(form)).await?;
+        }
+    }
+    Ok(None)
+}
+
+/// Replays one queued submission. Each key is only ever applied once, so the
+/// service worker can safely retry anything it didn't hear back about. A key
+/// that's still pending is being applied, unless it's been pending so long
+/// that the server must have stopped partway, then this replay takes it over.
+pub(crate) async fn offline_sync(
+    State(state): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Json(sub): Json<QueuedSubmission>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, my_name) = (user.id, user.name.as_str());
+
+    let captured_at = OffsetDateTime::parse(&sub.captured_at, &Rfc3339).map_err(|e| {
+        CustomError::Validation(format!("Bad capture time {}: {e}", sub.captured_at))
+    })?;
+    let kind = sub.kind.as_str();
+
+    if sub.owner.is_some_and(|owner| owner != my_id) {
+        warn!(
+            "user {my_name} (id {my_id}) tried to replay offline {kind} {} saved by user {:?}",
+            sub.key, sub.owner
+        );
+        return Err(CustomError::Forbidden(
+            "It was saved on this device by someone else".to_string(),
+        ));
+    }
+
+    let stale = OffsetDateTime::now_utc() - PENDING_TIMEOUT;
+    let claimed = query!(
+        r#"
+        insert into offline_submissions (key, worker, kind, captured_at, status)
+            values ($1, $2, $3, $4, 'pending')
+            on conflict (key) do update set received_at = current_timestamp
+            where offline_submissions.status = 'pending'
+                and offline_submissions.worker = excluded.worker
+                and datetime(offline_submissions.received_at) < datetime($5);
+        "#,
+        sub.key,
+        my_id,
+        kind,
+        captured_at,
+        stale
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected()
+        == 1;
+
+    if !claimed {
+        let status = query!(
+            "select status from offline_submissions where key = $1;",
+            sub.key
+        )
+        .fetch_optional(&state.pool)
+        .await?
+        .map(|r| r.status);
+        if status.as_deref() == Some("pending") {
+            info!(
+                "user {my_name} (id {my_id}) replayed offline {kind} {} while it's still being applied",
+                sub.key
+            );
+            return Ok((
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"status": "pending"})),
+            ));
+        }
+        info!(
+            "user {my_name} (id {my_id}) replayed offline {kind} {} again, skipping it",
+            sub.key
+        );
+        return Ok((StatusCode::CONFLICT, Json(json!({"status": "duplicate"}))));
+    }
+
+    let (code, status, error, version) = match apply(&state, &user, &sub, captured_at).await {
+        Ok(version) => (StatusCode::OK, "applied", None, version),
+        Err(
+            e @ (CustomError::Validation(_)
+            | CustomError::Forbidden(_)
+            | CustomError::Conflict(_)
+            | CustomError::NotFound(_)),
+        ) => {
+            warn!(
+                "user {my_name} (id {my_id}) offline {kind} {} captured at {captured_at} was rejected: {}",
+                sub.key, e
+            );
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "rejected",
+                Some(e.to_string()),
+                None,
+            )
+        }
+        Err(e) => {
+            // it might go through next time, so the key is freed for the
+            // service worker to send it again
+            query!(
+                "delete from offline_submissions where key = $1 and status = 'pending';",
+                sub.key
+            )
+            .execute(&state.pool)
+            .await?;
+            warn!(
+                "user {my_name} (id {my_id}) offline {kind} {} captured at {captured_at} failed, leaving it to be retried: {}",
+                sub.key, e
+            );
+            return Err(e);
+        }
+    };
+
+    query!(
+        "update offline_submissions set status = $1, error = $2 where key = $3;",
+        status,
+        error,
+        sub.key
+    )
+    .execute(&state.pool)
+    .await?;
+
+    info!(
+        "user {my_name} (id {my_id}) synced offline {kind} {} captured at {captured_at}: {status}",
+        sub.key
+    );
+
+    Ok((
+        code,
+        Json(json!({"status": status, "error": error, "version": version})),
+    ))
+}
@@ -0,0 +1,473 @@
+use crate::audit::{audit, Requester};
+use crate::conflicts::{db_time, find_conflicts};
+use crate::dispatch::publish;
+use crate::history::{crew_snapshots, record};
+use crate::roles::{can, Allowed, Permission};
+use crate::{errors::CustomError, now, AppState, TZ};
+use crate::{get_user, Backend};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, SqliteConnection};
+use time::macros::format_description;
+use time_tz::OffsetDateTimeExt;
+use tracing::info;
+
+async fn record_event(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: i64,
+    actor: i64,
+    action: &str,
+) -> Result<(), sqlx::Error> {
+    query!(
+        "insert into claimevents (job, worker, actor, action) values ($1, $2, $3, $4);",
+        job,
+        worker,
+        actor,
+        action
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Assigns `worker` to an open job in a single statement, so two workers
+/// claiming the last spot at the same time can't both get it.
+/// Returns false if the job is closed, full, or already has this worker.
+async fn assign_if_room(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: i64,
+    actor: i64,
+) -> Result<bool, sqlx::Error> {
+    let res = query!(
+        r#"
+        insert into jobworkers (job, worker)
+            select $1, $2
+            where (select count(*) from jobworkers where job = $1)
+                < (select max_crew from jobs where id = $1 and open = true)
+            and not exists (
+                select * from jobworkers where job = $1 and worker = $2
+            );
+        "#,
+        job,
+        worker
+    )
+    .execute(&mut *conn)
+    .await?;
+    if res.rows_affected() == 1 {
+        bump_job_version(conn, job).await?;
+        let assignment = crew_snapshots(conn, job, Some(worker))
+            .await?
+            .remove(&worker);
+        record(conn, job, Some(worker), actor, None, assignment).await?;
+    }
+    Ok(res.rows_affected() == 1)
+}
+
+/// The crew is on the job form, so a change to it makes open copies of the
+/// form stale
+async fn bump_job_version(conn: &mut SqliteConnection, job: i64) -> Result<(), sqlx::Error> {
+    query!("update jobs set version = version + 1 where id = $1;", job)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn openjobspage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, _my_name, role) = get_user(&auth)?;
+
+    let today = now().date();
+
+    let jobs = query!(
+        r#"
+        select jobs.id, jobs.sitename, jobs.address, jobs.date, jobs.starttime, jobs.endtime,
+            jobs.servicecode, jobs.notes, jobs.max_crew, jobs.needs_confirmation,
+            (select count(*) from jobworkers where job = jobs.id) as "crew!: i64",
+            exists (
+                select * from jobworkers where job = jobs.id and worker = $2
+            ) as "assigned!: bool",
+            exists (
+                select * from jobclaims
+                where job = jobs.id and worker = $2 and status = 'pending'
+            ) as "pending!: bool"
+            from jobs
+        where jobs.open = true
+            and jobs.date >= $1
+        order by jobs.date asc, jobs.starttime asc;
+        "#,
+        today,
+        my_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let time_fmt = format_description!("[hour]:[minute]");
+    let mut board = vec![];
+    for j in jobs {
+        if j.crew >= j.max_crew && !j.assigned && !j.pending {
+            continue;
+        }
+        let (start, end) = (db_time(&j.starttime), db_time(&j.endtime));
+        let conflicts = if j.assigned || j.pending {
+            vec![]
+        } else {
+            find_conflicts(
+                &mut *pool.acquire().await?,
+                Some(j.id),
+                j.date,
+                (start, end),
+                &[my_id],
+            )
+            .await?
+        };
+        board.push(json!({
+            "id": j.id,
+            "sitename": j.sitename,
+            "address": j.address,
+            "date": format!("{} {}, {}", j.date.month(), j.date.day(), j.date.year()),
+            "window": match (start, end) {
+                (Some(s), Some(e)) => format!("{} - {}", s.format(time_fmt)?, e.format(time_fmt)?),
+                _ => String::new(),
+            },
+            "servicecode": j.servicecode,
+            "notes": j.notes,
+            "crew": j.crew,
+            "max_crew": j.max_crew,
+            "needs_confirmation": j.needs_confirmation,
+            "assigned": j.assigned,
+            "pending": j.pending,
+            "problems": conflicts.iter().map(|c| c.description.clone()).join("; "),
+        }));
+    }
+
+    let (claims, events) = if role.can(Permission::ScheduleJobs) {
+        let claims = query!(
+            r#"
+            select jobclaims.id, jobs.id as job, jobs.sitename, jobs.date, users.name,
+                jobs.max_crew,
+                (select count(*) from jobworkers where job = jobs.id) as "crew!: i64"
+                from jobclaims
+                inner join jobs on jobclaims.job = jobs.id
+                inner join users on jobclaims.worker = users.id
+            where jobclaims.status = 'pending'
+            order by jobs.date asc;
+            "#
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|c| {
+            json!({
+                "id": c.id,
+                "job": c.job,
+                "sitename": c.sitename,
+                "date": c.date.to_string(),
+                "name": c.name,
+                "crew": c.crew,
+                "max_crew": c.max_crew,
+            })
+        })
+        .collect::<Vec<_>>();
+
+        let events = query!(
+            r#"
+            select claimevents.job, claimevents.action, claimevents.at,
+                worker.name as worker_name, actor.name as actor_name
+                from claimevents
+                inner join users as worker on claimevents.worker = worker.id
+                inner join users as actor on claimevents.actor = actor.id
+            order by claimevents.id desc
+            limit 50;
+            "#
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|e| {
+            let at = e.at.to_timezone(*TZ.get().unwrap());
+            json!({
+                "job": e.job,
+                "action": e.action,
+                "at": at.format(format_description!("[year]-[month]-[day] [hour]:[minute]")).unwrap_or_default(),
+                "worker": e.worker_name,
+                "actor": e.actor_name,
+            })
+        })
+        .collect::<Vec<_>>();
+
+        (claims, events)
+    } else {
+        (vec![], vec![])
+    };
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Open Jobs",
+        "admin": role.is_staff(),
+        "logged_in": true,
+        "jobs": board,
+        "claims": claims,
+        "events": events,
+    });
+
+    Ok(RenderHtml("openjobs.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClaimForm {
+    job: i64,
+}
+
+pub(crate) async fn claim_job(
+    State(AppState { pool, events, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<ClaimForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, my_name, _) = get_user(&auth)?;
+
+    let mut tx = pool.begin().await?;
+
+    let job = query!(
+        r#"
+        select id, date, starttime, endtime, open, needs_confirmation
+            from jobs where id = $1;
+        "#,
+        form.job
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !job.open || job.date < now().date() {
+        return Err(CustomError::Conflict(format!("Job {} is not open", job.id)));
+    }
+
+    let conflicts = find_conflicts(
+        &mut tx,
+        Some(job.id),
+        job.date,
+        (db_time(&job.starttime), db_time(&job.endtime)),
+        &[my_id],
+    )
+    .await?;
+    if !conflicts.is_empty() {
+        return Err(CustomError::Conflict(format!(
+            "You can't claim this job: {}",
+            conflicts.iter().map(|c| c.description.as_str()).join("; ")
+        )));
+    }
+
+    if job.needs_confirmation {
+        let res = query!(
+            r#"
+            insert into jobclaims (job, worker)
+                select $1, $2
+                where not exists (
+                    select * from jobclaims
+                    where job = $1 and worker = $2 and status = 'pending'
+                )
+                and not exists (
+                    select * from jobworkers where job = $1 and worker = $2
+                );
+            "#,
+            job.id,
+            my_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(CustomError::Conflict(format!(
+                "You already claimed job {}",
+                job.id
+            )));
+        }
+    } else if !assign_if_room(&mut tx, job.id, my_id, my_id).await? {
+        return Err(CustomError::Conflict(format!(
+            "Job {} is already full or closed",
+            job.id
+        )));
+    }
+
+    record_event(&mut tx, job.id, my_id, my_id, "claim").await?;
+    tx.commit().await?;
+    publish(&events, job.id, Some(my_id), "claim");
+
+    info!(
+        "user {my_name} (id {my_id}) claimed open job {}{}",
+        job.id,
+        if job.needs_confirmation {
+            ", pending confirmation"
+        } else {
+            ""
+        }
+    );
+
+    Ok(Redirect::to("/openjobs"))
+}
+
+pub(crate) async fn release_job(
+    State(AppState { pool, events, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<ClaimForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, my_name, _) = get_user(&auth)?;
+
+    let mut tx = pool.begin().await?;
+
+    let released_claim = query!(
+        r#"
+        update jobclaims set status = 'released'
+            where job = $1 and worker = $2 and status = 'pending';
+        "#,
+        form.job,
+        my_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+
+    let before = crew_snapshots(&mut tx, form.job, Some(my_id))
+        .await?
+        .remove(&my_id);
+
+    // only open jobs can be given back, and only before any time is recorded
+    let released_assignment = query!(
+        r#"
+        delete from jobworkers
+            where job = $1 and worker = $2
+            and signin is null and signout is null
+            and exists (select * from jobs where id = $1 and open = true);
+        "#,
+        form.job,
+        my_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+
+    if !released_claim && !released_assignment {
+        return Err(CustomError::Forbidden(format!(
+            "Job {} can't be released, ask an administrator",
+            form.job
+        )));
+    }
+
+    if released_assignment {
+        bump_job_version(&mut tx, form.job).await?;
+        record(&mut tx, form.job, Some(my_id), my_id, before, None).await?;
+    }
+    record_event(&mut tx, form.job, my_id, my_id, "release").await?;
+    tx.commit().await?;
+    publish(&events, form.job, Some(my_id), "release");
+
+    info!("user {my_name} (id {my_id}) released open job {}", form.job);
+
+    Ok(Redirect::to("/openjobs"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DecideClaimForm {
+    id: i64,
+    approve: bool,
+}
+
+pub(crate) async fn decide_claim(
+    State(AppState { pool, events, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+    from: Requester,
+    Form(form): Form<DecideClaimForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut tx = pool.begin().await?;
+
+    let claim = query!(
+        "select job, worker from jobclaims where id = $1 and status = 'pending';",
+        form.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // the worker might have been booked elsewhere since they claimed it
+    if form.approve {
+        let job = query!(
+            "select date, starttime, endtime from jobs where id = $1;",
+            claim.job
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let conflicts = find_conflicts(
+            &mut tx,
+            Some(claim.job),
+            job.date,
+            (db_time(&job.starttime), db_time(&job.endtime)),
+            &[claim.worker],
+        )
+        .await?;
+        if !conflicts.is_empty() {
+            return Err(CustomError::Conflict(format!(
+                "The claim can't be confirmed: {}",
+                conflicts.iter().map(|c| c.description.as_str()).join("; ")
+            )));
+        }
+    }
+
+    if form.approve && !assign_if_room(&mut tx, claim.job, claim.worker, my_id).await? {
+        return Err(CustomError::Conflict(format!(
+            "Job {} is already full or closed",
+            claim.job
+        )));
+    }
+
+    let (status, action) = if form.approve {
+        ("confirmed", "confirm")
+    } else {
+        ("rejected", "reject")
+    };
+
+    query!(
+        "update jobclaims set status = $1 where id = $2;",
+        status,
+        form.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_event(&mut tx, claim.job, claim.worker, my_id, action).await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        &format!("{status} claim"),
+        &format!(
+            "claim {} by user {} on job {}",
+            form.id, claim.worker, claim.job
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+    publish(&events, claim.job, Some(claim.worker), action);
+    info!(
+        "admin {my_name} (id {my_id}) {} user {}'s claim on job {}",
+        status, claim.worker, claim.job
+    );
+
+    Ok(Redirect::to("/openjobs"))
+}
@@ -0,0 +1,1071 @@
+use std::collections::HashMap;
+
+use crate::checkinout::{time_sheet_page, CheckInOutPage};
+use crate::checklists::ready_to_check_out;
+use crate::dispatch::publish;
+use crate::history::{crew_snapshots, record};
+use crate::roles::{can, Permission, SelfOr};
+use crate::settings::{clock_policy, geofence_radius, ClockPolicy};
+use crate::sites::{distance_m, instant, job_location, job_tz, zone};
+use crate::validation::{rerender, FieldErrors};
+use crate::{empty_string_as_none, errors::CustomError, AppState};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, query_as, types::time::Date, Pool, Sqlite, SqliteConnection};
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime, Time};
+use time_tz::{OffsetDateTimeExt, Tz};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PunchKind {
+    Work,
+    Break,
+}
+
+impl PunchKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PunchKind::Work => "work",
+            PunchKind::Break => "break",
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct PunchRow {
+    id: i64,
+    job: i64,
+    kind: String,
+    paid: bool,
+    job_date: Date,
+    punchin: String,
+    punchout: Option<String>,
+    clocked_in: bool,
+    clocked_out: bool,
+    edited: bool,
+    orig_in: Option<String>,
+    orig_out: Option<String>,
+    tz: Option<String>,
+    in_lat: Option<f64>,
+    in_lon: Option<f64>,
+    in_accuracy: Option<f64>,
+    in_distance: Option<f64>,
+    out_lat: Option<f64>,
+    out_lon: Option<f64>,
+    out_accuracy: Option<f64>,
+    out_distance: Option<f64>,
+    offsite: bool,
+    offline: bool,
+    in_entered_by: Option<String>,
+    out_entered_by: Option<String>,
+}
+
+/// The inputs of a segment typed in by hand, as the time sheet shows them
+pub(crate) const PUNCH_FIELDS: [&str; 3] = ["punchin", "punchout", "segment"];
+
+/// How saving a segment typed in by hand went
+pub(crate) enum Punched {
+    Yes,
+    /// Something about its times is wrong, so it wasn't saved
+    Rejected(FieldErrors),
+}
+
+/// One stretch of work or break time within a job assignment
+#[derive(Debug, Clone)]
+pub struct Punch {
+    pub id: i64,
+    pub job: i64,
+    pub kind: PunchKind,
+    pub job_date: Date,
+    pub paid: bool,
+    pub punchin: PrimitiveDateTime,
+    pub punchout: Option<PrimitiveDateTime>,
+    pub clocked_in: bool,
+    pub clocked_out: bool,
+    pub edited: bool,
+    pub orig_in: Option<PrimitiveDateTime>,
+    pub orig_out: Option<PrimitiveDateTime>,
+    /// The site's time zone, punch times are its wall clock times
+    pub tz: &'static Tz,
+    pub in_lat: Option<f64>,
+    pub in_lon: Option<f64>,
+    pub in_accuracy: Option<f64>,
+    pub in_distance: Option<f64>,
+    pub out_lat: Option<f64>,
+    pub out_lon: Option<f64>,
+    pub out_accuracy: Option<f64>,
+    pub out_distance: Option<f64>,
+    /// A clock punch was further from the site than the geofence allows
+    pub offsite: bool,
+    /// Recorded on the worker's phone while it had no connection
+    pub offline: bool,
+    /// Name of the crew lead who clocked it in for the worker
+    pub in_entered_by: Option<String>,
+    /// Name of the crew lead who clocked it out for the worker
+    pub out_entered_by: Option<String>,
+}
+
+impl From<PunchRow> for Punch {
+    fn from(r: PunchRow) -> Self {
+        Punch {
+            id: r.id,
+            job: r.job,
+            kind: if r.kind == "break" {
+                PunchKind::Break
+            } else {
+                PunchKind::Work
+            },
+            job_date: r.job_date,
+            paid: r.paid,
+            punchin: db_datetime(&Some(r.punchin))
+                .unwrap_or(PrimitiveDateTime::new(r.job_date, Time::MIDNIGHT)),
+            punchout: db_datetime(&r.punchout),
+            clocked_in: r.clocked_in,
+            clocked_out: r.clocked_out,
+            edited: r.edited,
+            orig_in: db_datetime(&r.orig_in),
+            orig_out: db_datetime(&r.orig_out),
+            tz: zone(r.tz.as_deref()),
+            in_lat: r.in_lat,
+            in_lon: r.in_lon,
+            in_accuracy: r.in_accuracy,
+            in_distance: r.in_distance,
+            out_lat: r.out_lat,
+            out_lon: r.out_lon,
+            out_accuracy: r.out_accuracy,
+            out_distance: r.out_distance,
+            offsite: r.offsite,
+            offline: r.offline,
+            in_entered_by: r.in_entered_by,
+            out_entered_by: r.out_entered_by,
+        }
+    }
+}
+
+/// Parses a timestamp as stored in the database
+pub(crate) fn db_datetime(t: &Option<String>) -> Option<PrimitiveDateTime> {
+    t.as_ref().and_then(|t| {
+        PrimitiveDateTime::parse(
+            t,
+            format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]]"
+            ),
+        )
+        .ok()
+    })
+}
+
+/// Shows the time of day, with the date too when it isn't the job's date
+fn format_punch(t: Option<PrimitiveDateTime>, job_date: Date) -> String {
+    match t {
+        Some(t) if t.date() == job_date => t
+            .format(format_description!("[hour]:[minute]"))
+            .unwrap_or_default(),
+        Some(t) => t
+            .format(format_description!(
+                "[month repr:short] [day] [hour]:[minute]"
+            ))
+            .unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+/// The value a datetime-local input expects
+fn input_value(t: Option<PrimitiveDateTime>) -> String {
+    t.and_then(|t| {
+        t.format(format_description!("[year]-[month]-[day]T[hour]:[minute]"))
+            .ok()
+    })
+    .unwrap_or_default()
+}
+
+impl Punch {
+    /// Length of a finished segment in hours, open segments don't count yet.
+    /// Worked out between instants so a DST change in the middle counts right.
+    pub fn hours(&self) -> f32 {
+        self.punchout
+            .map(|out| {
+                (instant(out, self.tz) - instant(self.punchin, self.tz)).as_seconds_f32() / 3600.
+            })
+            .unwrap_or_default()
+    }
+
+    /// Open segments run until they're punched out, so they overlap anything after them
+    fn overlaps(&self, start: PrimitiveDateTime, end: Option<PrimitiveDateTime>) -> bool {
+        end.is_none_or(|e| e > self.punchin) && self.punchout.is_none_or(|out| out > start)
+    }
+
+    /// The segment as it was first recorded, before any edits
+    pub fn original(&self) -> String {
+        let (start, end) = if self.edited {
+            (self.orig_in, self.orig_out)
+        } else {
+            (Some(self.punchin), self.punchout)
+        };
+        let show = |t: Option<PrimitiveDateTime>| {
+            t.map(|t| format_punch(Some(t), self.job_date))
+                .unwrap_or("none".into())
+        };
+        format!("{} - {}", show(start), show(end))
+    }
+
+    /// Which ends of the segment a crew lead clocked for the worker, and who
+    pub fn entered_by(&self) -> Option<String> {
+        let ends = [
+            self.in_entered_by.as_ref().map(|lead| {
+                let at = format_punch(Some(self.punchin), self.job_date);
+                format!("in at {at} by lead {lead}")
+            }),
+            self.out_entered_by.as_ref().map(|lead| {
+                let at = format_punch(self.punchout, self.job_date);
+                format!("out at {at} by lead {lead}")
+            }),
+        ];
+        let ends = ends.into_iter().flatten().join(", ");
+        (!ends.is_empty()).then(|| format!("Clocked {ends}"))
+    }
+
+    /// How far from the site the clock punches were
+    pub fn location(&self) -> String {
+        let show = |distance: Option<f64>, accuracy: Option<f64>| match (distance, accuracy) {
+            (Some(d), Some(a)) => format!("{d:.0} m from site (within {a:.0} m)"),
+            (Some(d), None) => format!("{d:.0} m from site"),
+            (None, _) => "no location".into(),
+        };
+        format!(
+            "in {}, out {}",
+            show(self.in_distance, self.in_accuracy),
+            show(self.out_distance, self.out_accuracy)
+        )
+    }
+
+    pub fn view(&self) -> Value {
+        json!({
+            "id": self.id,
+            "kind": self.kind.as_str(),
+            "paid": self.paid,
+            "punchin": format_punch(Some(self.punchin), self.job_date),
+            "punchout": format_punch(self.punchout, self.job_date),
+            "punchin_value": input_value(Some(self.punchin)),
+            "punchout_value": input_value(self.punchout),
+            "open": self.punchout.is_none(),
+            "clocked": self.clocked_in && (self.clocked_out || self.punchout.is_none()),
+            "edited": self.edited,
+            "original": self.original(),
+            "offsite": self.offsite,
+            "offline": self.offline,
+            "in_entered_by": self.in_entered_by,
+            "out_entered_by": self.out_entered_by,
+            "entered_by": self.entered_by(),
+            "location": self.location(),
+            "hours": format!("{:.2}", self.hours()),
+        })
+    }
+}
+
+/// Time worked on a job, leaving out unpaid breaks
+pub(crate) fn paid_hours(punches: &[Punch]) -> f32 {
+    punches.iter().filter(|p| p.paid).map(Punch::hours).sum()
+}
+
+pub(crate) async fn job_punches(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<Vec<Punch>, sqlx::Error> {
+    Ok(query_as!(
+        PunchRow,
+        r#"
+        select punches.id, punches.job, jobs.date as job_date, punches.kind, punches.paid,
+            punches.punchin, punches.punchout, punches.clocked_in, punches.clocked_out,
+            punches.edited, punches.orig_in, punches.orig_out, sites.tz as "tz?",
+            punches.in_lat, punches.in_lon, punches.in_accuracy, punches.in_distance,
+            punches.out_lat, punches.out_lon, punches.out_accuracy, punches.out_distance,
+            punches.offsite, punches.offline, in_leads.name as "in_entered_by?",
+            out_leads.name as "out_entered_by?"
+            from punches inner join jobs
+            on punches.job = jobs.id
+            left join sites
+            on jobs.sitename = sites.name
+            left join users as in_leads
+            on punches.in_entered_by = in_leads.id
+            left join users as out_leads
+            on punches.out_entered_by = out_leads.id
+        where punches.job = $1 and punches.worker = $2
+        order by punches.punchin asc;
+        "#,
+        job,
+        worker
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(Punch::from)
+    .collect())
+}
+
+/// Keeps jobworkers.signin and signout as the first punch in and the last
+/// punch out, with no sign out while a segment is still open
+async fn sync_summary(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: i64,
+    actor: i64,
+) -> Result<(), sqlx::Error> {
+    let before = crew_snapshots(conn, job, Some(worker))
+        .await?
+        .remove(&worker);
+    query!(
+        r#"
+        update jobworkers set
+            signin = (select min(punchin) from punches where job = $1 and worker = $2),
+            signout = case
+                when exists (
+                    select * from punches where job = $1 and worker = $2 and punchout is null
+                ) then null
+                else (select max(punchout) from punches where job = $1 and worker = $2)
+            end
+        where job = $1 and worker = $2;
+        "#,
+        job,
+        worker
+    )
+    .execute(&mut *conn)
+    .await?;
+    let after = crew_snapshots(conn, job, Some(worker))
+        .await?
+        .remove(&worker);
+    record(conn, job, Some(worker), actor, before, after).await
+}
+
+/// Where the worker's browser said they were for a clock punch
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fix {
+    lat: f64,
+    lon: f64,
+    accuracy: Option<f64>,
+    /// Meters from the site, when the site has coordinates
+    distance: Option<f64>,
+    offsite: bool,
+}
+
+impl Fix {
+    fn columns(fix: Option<Fix>) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>, bool) {
+        fix.map(|f| (Some(f.lat), Some(f.lon), f.accuracy, f.distance, f.offsite))
+            .unwrap_or_default()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn open_segment(
+    conn: &mut SqliteConnection,
+    job: i64,
+    worker: i64,
+    kind: PunchKind,
+    stamp: PrimitiveDateTime,
+    fix: Option<Fix>,
+    offline: bool,
+    entered_by: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    let kind_str = kind.as_str();
+    let paid = kind == PunchKind::Work;
+    let (lat, lon, accuracy, distance, offsite) = Fix::columns(fix);
+    query!(
+        r#"
+        insert into punches (job, worker, kind, paid, punchin, clocked_in,
+                in_lat, in_lon, in_accuracy, in_distance, offsite, offline, in_entered_by)
+            values ($1, $2, $3, $4, $5, true, $6, $7, $8, $9, $10, $11, $12);
+        "#,
+        job,
+        worker,
+        kind_str,
+        paid,
+        stamp,
+        lat,
+        lon,
+        accuracy,
+        distance,
+        offsite,
+        offline,
+        entered_by
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn close_segment(
+    conn: &mut SqliteConnection,
+    id: i64,
+    stamp: PrimitiveDateTime,
+    fix: Option<Fix>,
+    offline: bool,
+    entered_by: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    let (lat, lon, accuracy, distance, offsite) = Fix::columns(fix);
+    query!(
+        r#"
+        update punches set punchout = $1, clocked_out = true,
+            out_lat = $2, out_lon = $3, out_accuracy = $4, out_distance = $5,
+            offsite = offsite or $6, offline = offline or $7,
+            out_entered_by = $8
+        where id = $9;
+        "#,
+        stamp,
+        lat,
+        lon,
+        accuracy,
+        distance,
+        offsite,
+        offline,
+        entered_by,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClockAction {
+    In,
+    Break,
+    Resume,
+    Out,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClockForm {
+    JobId: i64,
+    WorkerId: i64,
+    action: ClockAction,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    lat: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    lon: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    accuracy: Option<f64>,
+}
+
+/// Opens and closes segments for a clock action at the site's current time,
+/// returning the time it was recorded at. `actor` is who pressed the button,
+/// for the assignment's history, and `lead` is set when a crew lead did it
+/// for the worker.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn record_clock(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+    actor: i64,
+    lead: Option<i64>,
+    action: ClockAction,
+    fix: Option<Fix>,
+    at: OffsetDateTime,
+    offline: bool,
+) -> Result<PrimitiveDateTime, CustomError> {
+    let local = at.to_timezone(job_tz(pool, job).await?);
+    let stamp = PrimitiveDateTime::new(local.date(), local.time()).replace_nanosecond(0)?;
+    let today = local.date();
+
+    // before the transaction, it may fill in the assignment's checklist
+    if let ClockAction::Out = action {
+        ready_to_check_out(pool, job, worker).await?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let open = query!(
+        r#"
+        select id, kind, punchin from punches
+            where job = $1 and worker = $2 and punchout is null;
+        "#,
+        job,
+        worker
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if open
+        .as_ref()
+        .and_then(|p| db_datetime(&Some(p.punchin.clone())))
+        .is_some_and(|start| stamp < start)
+    {
+        return Err(CustomError::Validation(
+            "That is before the current segment started".to_string(),
+        ));
+    }
+
+    match (action, open) {
+        (ClockAction::In, None) => {
+            let on_job = query!(
+                r#"
+                select jobworkers.job from jobworkers inner join jobs
+                    on jobworkers.job = jobs.id
+                where jobworkers.job = $1
+                    and jobworkers.worker = $2
+                    and jobs.date = $3;
+                "#,
+                job,
+                worker,
+                today
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+            if !on_job {
+                return Err(CustomError::Validation(
+                    "You can only clock in on the day of the job".to_string(),
+                ));
+            }
+            let covered = query!(
+                r#"
+                select id from punches
+                    where job = $1 and worker = $2
+                    and punchin <= $3 and punchout > $3;
+                "#,
+                job,
+                worker,
+                stamp
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+            if covered {
+                return Err(CustomError::Conflict(
+                    "There is already time recorded for right now".to_string(),
+                ));
+            }
+            open_segment(
+                &mut tx,
+                job,
+                worker,
+                PunchKind::Work,
+                stamp,
+                fix,
+                offline,
+                lead,
+            )
+            .await?;
+        }
+        (ClockAction::Break, Some(p)) if p.kind == "work" => {
+            close_segment(&mut tx, p.id, stamp, fix, offline, lead).await?;
+            open_segment(
+                &mut tx,
+                job,
+                worker,
+                PunchKind::Break,
+                stamp,
+                fix,
+                offline,
+                lead,
+            )
+            .await?;
+        }
+        (ClockAction::Resume, Some(p)) if p.kind == "break" => {
+            close_segment(&mut tx, p.id, stamp, fix, offline, lead).await?;
+            open_segment(
+                &mut tx,
+                job,
+                worker,
+                PunchKind::Work,
+                stamp,
+                fix,
+                offline,
+                lead,
+            )
+            .await?;
+        }
+        (ClockAction::Out, Some(p)) => {
+            close_segment(&mut tx, p.id, stamp, fix, offline, lead).await?;
+        }
+        (ClockAction::In, Some(_)) => {
+            return Err(CustomError::Conflict(
+                "You are already clocked in".to_string(),
+            ));
+        }
+        (action, _) => {
+            return Err(CustomError::Conflict(format!(
+                "You can't {:?} right now, refresh the page and try again",
+                action
+            )));
+        }
+    }
+
+    sync_summary(&mut tx, job, worker, actor).await?;
+    tx.commit().await?;
+
+    Ok(stamp)
+}
+
+/// Whether `lead` is the crew lead on `job` and `worker` is on its crew
+pub(crate) async fn leads_crew(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    lead: i64,
+    worker: i64,
+) -> Result<bool, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select crew.worker from jobworkers as crew inner join jobworkers as leads
+            on crew.job = leads.job
+        where crew.job = $1 and leads.worker = $2 and leads.lead and crew.worker = $3;
+        "#,
+        job,
+        lead,
+        worker
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+/// Records a punch with the server's clock instead of a typed time, in the
+/// site's local time
+pub(crate) async fn clock(
+    State(state): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Form(form): Form<ClockForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (job, worker) = (form.JobId, form.WorkerId);
+
+    clock_at(&state, &user, form, OffsetDateTime::now_utc(), false).await?;
+
+    Ok(Redirect::to(&format!(
+        "/checkinout?id={job}&worker={worker}"
+    )))
+}
+
+/// Checks and records a clock punch made at `at`, which is in the past when
+/// the punch was queued on a phone without a connection
+pub(crate) async fn clock_at(
+    AppState { pool, events, .. }: &AppState,
+    user: &SelfOr<can::EditTimesheets>,
+    form: ClockForm,
+    at: OffsetDateTime,
+    offline: bool,
+) -> Result<(), CustomError> {
+    let (my_id, my_name, role) = (user.id, user.name.as_str(), user.role);
+
+    let worker = form.WorkerId;
+
+    // a crew lead clocking a teammate in or out is recorded as entered by them
+    let lead = match user.check(worker) {
+        Ok(()) => None,
+        Err(_)
+            if role.can(Permission::LeadCrews)
+                && leads_crew(pool, form.JobId, my_id, worker).await? =>
+        {
+            Some(my_id)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let here = match (form.lat, form.lon) {
+        (Some(lat), Some(lon))
+            if (-90. ..=90.).contains(&lat) && (-180. ..=180.).contains(&lon) =>
+        {
+            Some((lat, lon))
+        }
+        (None, None) => None,
+        _ => {
+            return Err(CustomError::Validation(
+                "The location needs both a latitude between -90 and 90 and a longitude between -180 and 180"
+                    .to_string(),
+            ))
+        }
+    };
+    if form
+        .accuracy
+        .is_some_and(|a| !(0. ..f64::INFINITY).contains(&a))
+    {
+        return Err(CustomError::Validation(
+            "The location's accuracy has to be a distance in meters".to_string(),
+        ));
+    }
+
+    // only offsite when even the nearest point the phone could be at is
+    // outside the fence, a rough fix near the edge doesn't count
+    let fix = match here {
+        Some(here) => {
+            let radius = geofence_radius(pool).await?;
+            let distance = job_location(pool, form.JobId)
+                .await?
+                .map(|site| distance_m(site, here));
+            Some(Fix {
+                lat: here.0,
+                lon: here.1,
+                accuracy: form.accuracy,
+                distance,
+                offsite: distance.is_some_and(|d| d - form.accuracy.unwrap_or(0.) > radius),
+            })
+        }
+        None => None,
+    };
+
+    let stamp = record_clock(
+        pool,
+        form.JobId,
+        worker,
+        my_id,
+        lead,
+        form.action,
+        fix,
+        at,
+        offline,
+    )
+    .await?;
+
+    publish(events, form.JobId, Some(worker), "checkinout");
+
+    match lead {
+        Some(_) => info!(
+            "clocked {:?} on job {} at {stamp}{}, entered by lead {my_name} (id {my_id}) on behalf of user {worker}",
+            form.action,
+            form.JobId,
+            if offline { " while offline" } else { "" }
+        ),
+        None => info!(
+            "{} {my_name} (id {my_id}) clocked {:?} for user {worker} on job {} at {stamp}{}",
+            role.as_str(),
+            form.action,
+            form.JobId,
+            if offline { " while offline" } else { "" }
+        ),
+    }
+    if let Some(Fix {
+        offsite: true,
+        distance: Some(d),
+        ..
+    }) = fix
+    {
+        warn!(
+            "user {worker} clocked {:?} on job {} {d:.0} m from the site",
+            form.action, form.JobId
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PunchForm {
+    JobId: i64,
+    WorkerId: i64,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    id: Option<i64>,
+    kind: PunchKind,
+    punchin: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    punchout: Option<String>,
+    paid: Option<String>,
+    delete: Option<String>,
+}
+
+/// Adds, corrects or removes a segment by hand
+/// Checks and saves a segment typed in by hand
+pub(crate) async fn save_punch(
+    AppState { pool, events, .. }: &AppState,
+    user: &SelfOr<can::EditTimesheets>,
+    form: &PunchForm,
+) -> Result<Punched, CustomError> {
+    let (my_id, my_name, role) = (user.id, user.name.as_str(), user.role);
+    let staff = user.granted();
+
+    let worker = form.WorkerId;
+    user.check(worker)?;
+
+    if !staff && clock_policy(pool).await? == ClockPolicy::ClockOnly {
+        return Err(CustomError::Forbidden(
+            "Times can only be recorded with the clock buttons".to_string(),
+        ));
+    }
+
+    let on_job = query!(
+        "select worker from jobworkers where job = $1 and worker = $2;",
+        form.JobId,
+        worker
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+    if !on_job {
+        return Err(CustomError::NotFound(format!(
+            "There is no time sheet for user {worker} on job {}",
+            form.JobId
+        )));
+    }
+
+    let punches = job_punches(pool, form.JobId, worker).await?;
+    let existing = match form.id {
+        Some(id) => Some(punches.iter().find(|p| p.id == id).ok_or_else(|| {
+            CustomError::NotFound(format!("Segment {id} isn't part of this time sheet"))
+        })?),
+        None => None,
+    };
+
+    let mut tx = pool.begin().await?;
+
+    if let (Some(p), Some(_)) = (existing, &form.delete) {
+        query!("delete from punches where id = $1;", p.id)
+            .execute(&mut *tx)
+            .await?;
+        sync_summary(&mut tx, form.JobId, worker, my_id).await?;
+        tx.commit().await?;
+
+        publish(events, form.JobId, Some(worker), "checkinout");
+
+        info!(
+            "{} {my_name} (id {my_id}) deleted {} segment {} ({}) for user {worker} on job {}",
+            role.as_str(),
+            p.kind.as_str(),
+            p.id,
+            p.original(),
+            form.JobId
+        );
+
+        return Ok(Punched::Yes);
+    }
+
+    let fmt = format_description!("[year]-[month]-[day]T[hour]:[minute]");
+    // times are entered to the minute, so a clocked punch only counts as
+    // changed when the minute differs and keeps its seconds otherwise
+    let same_minute = |a: PrimitiveDateTime, b: PrimitiveDateTime| {
+        a.date() == b.date() && a.hour() == b.hour() && a.minute() == b.minute()
+    };
+
+    let mut errors = FieldErrors::default();
+    let start = PrimitiveDateTime::parse(form.punchin.trim(), &fmt)
+        .map_err(|_| errors.add("punchin", "Pick the day and time it started"))
+        .ok();
+    let end = match form.punchout.as_deref() {
+        Some(t) => PrimitiveDateTime::parse(t.trim(), &fmt)
+            .map_err(|_| errors.add("punchout", "Pick the day and time it ended"))
+            .ok(),
+        None => None,
+    };
+    let Some(start) = start.filter(|_| errors.is_empty()) else {
+        return Ok(Punched::Rejected(errors));
+    };
+    let start = match existing {
+        Some(p) if same_minute(p.punchin, start) => p.punchin,
+        _ => start,
+    };
+    let end = match (existing.and_then(|p| p.punchout), end) {
+        (Some(old), Some(new)) if same_minute(old, new) => Some(old),
+        _ => end,
+    };
+
+    if end.is_some_and(|e| e <= start) {
+        errors.add("punchout", "Segments have to end after they start");
+        return Ok(Punched::Rejected(errors));
+    }
+
+    // typing in the last time out finishes the job the same as clocking out
+    if !staff
+        && end.is_some()
+        && punches
+            .iter()
+            .filter(|p| Some(p.id) != form.id)
+            .all(|p| p.punchout.is_some())
+    {
+        match ready_to_check_out(pool, form.JobId, worker).await {
+            Err(CustomError::Validation(message)) => {
+                errors.add("punchout", message);
+                return Ok(Punched::Rejected(errors));
+            }
+            done => done?,
+        }
+    }
+
+    if let Some(other) = punches
+        .iter()
+        .filter(|p| Some(p.id) != form.id)
+        .find(|p| p.overlaps(start, end))
+    {
+        errors.add(
+            "segment",
+            format!("That overlaps the segment from {}", other.original()),
+        );
+        return Ok(Punched::Rejected(errors));
+    }
+
+    let paid = form.kind == PunchKind::Work || form.paid.is_some();
+    let kind = form.kind.as_str();
+
+    match existing {
+        Some(p) => {
+            let start_same = start == p.punchin;
+            let end_same = end == p.punchout;
+            let changed = !start_same || !end_same || form.kind != p.kind || paid != p.paid;
+            // corrections to clocked punches and to other workers' sheets are flagged for the reports
+            let flagged = changed && (p.clocked_in || p.clocked_out || worker != my_id);
+
+            query!(
+                r#"
+                update punches set
+                    kind = $1,
+                    paid = $2,
+                    punchin = $3,
+                    punchout = $4,
+                    clocked_in = clocked_in and $5,
+                    clocked_out = clocked_out and $6,
+                    orig_in = case when $7 and not edited then punchin else orig_in end,
+                    orig_out = case when $7 and not edited then punchout else orig_out end,
+                    edited = edited or $7,
+                    edited_by = case when $7 then $8 else edited_by end
+                where id = $9;
+                "#,
+                kind,
+                paid,
+                start,
+                end,
+                start_same,
+                end_same,
+                flagged,
+                my_id,
+                p.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            let flagged = worker != my_id;
+            let edited_by = flagged.then_some(my_id);
+
+            query!(
+                r#"
+                insert into punches (job, worker, kind, paid, punchin, punchout, edited, edited_by)
+                    values ($1, $2, $3, $4, $5, $6, $7, $8);
+                "#,
+                form.JobId,
+                worker,
+                kind,
+                paid,
+                start,
+                end,
+                flagged,
+                edited_by
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    sync_summary(&mut tx, form.JobId, worker, my_id).await?;
+    tx.commit().await?;
+
+    publish(events, form.JobId, Some(worker), "checkinout");
+
+    info!(
+        "{} {my_name} (id {my_id}) {} {} segment {} - {} ({}) for user {worker} on job {}",
+        role.as_str(),
+        if existing.is_some() {
+            "updated"
+        } else {
+            "added"
+        },
+        kind,
+        start,
+        end.map(|e| e.to_string()).unwrap_or("open".into()),
+        if paid { "paid" } else { "unpaid" },
+        form.JobId
+    );
+
+    Ok(Punched::Yes)
+}
+
+/// Adds, changes or deletes a segment by hand, showing the time sheet again
+/// with the messages next to the times when it can't be saved
+pub(crate) async fn punch(
+    State(state): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Form(form): Form<PunchForm>,
+) -> Result<Response, CustomError> {
+    let errors = match save_punch(&state, &user, &form).await? {
+        Punched::Yes => {
+            return Ok(Redirect::to(&format!(
+                "/checkinout?id={}&worker={}",
+                form.JobId, form.WorkerId
+            ))
+            .into_response())
+        }
+        Punched::Rejected(errors) => errors,
+    };
+
+    let page = CheckInOutPage {
+        id: form.JobId,
+        worker: form.WorkerId,
+    };
+    let mut data = time_sheet_page(&state.pool, &user.with(), &page, None).await?;
+    let typed = json!({
+        "kind": form.kind.as_str(),
+        "paid": form.kind == PunchKind::Work || form.paid.is_some(),
+        "punchin_value": form.punchin,
+        "punchout_value": form.punchout.clone().unwrap_or_default(),
+        "errors": errors.view(&PUNCH_FIELDS),
+    });
+    match form.id {
+        Some(id) => {
+            if let Some(p) = data["punches"]
+                .as_array_mut()
+                .and_then(|ps| ps.iter_mut().find(|p| p["id"] == id))
+            {
+                for (k, v) in typed.as_object().into_iter().flatten() {
+                    p[k] = v.clone();
+                }
+            }
+        }
+        None => data["new_punch"] = typed,
+    }
+
+    Ok(rerender("checkinout.hbs", state.engine, data))
+}
+
+/// All segments for a worker's jobs between two dates, by job
+pub(crate) async fn worker_punches(
+    pool: &Pool<Sqlite>,
+    worker: i64,
+    from: Date,
+    to: Date,
+) -> Result<HashMap<i64, Vec<Punch>>, sqlx::Error> {
+    Ok(query_as!(
+        PunchRow,
+        r#"
+        select punches.id, punches.job, jobs.date as job_date, punches.kind, punches.paid,
+            punches.punchin,
+            punches.punchout, punches.clocked_in, punches.clocked_out,
+            punches.edited, punches.orig_in, punches.orig_out, sites.tz as "tz?",
+            punches.in_lat, punches.in_lon, punches.in_accuracy, punches.in_distance,
+            punches.out_lat, punches.out_lon, punches.out_accuracy, punches.out_distance,
+            punches.offsite, punches.offline, in_leads.name as "in_entered_by?",
+            out_leads.name as "out_entered_by?"
+            from punches inner join jobs
+            on punches.job = jobs.id
+            left join sites
+            on jobs.sitename = sites.name
+            left join users as in_leads
+            on punches.in_entered_by = in_leads.id
+            left join users as out_leads
+            on punches.out_entered_by = out_leads.id
+        where punches.worker = $1
+            and jobs.date >= $2 and jobs.date <= $3
+        order by punches.punchin asc;
+        "#,
+        worker,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(Punch::from)
+    .into_group_map_by(|p| p.job))
+}
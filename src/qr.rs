@@ -0,0 +1,250 @@
+use crate::conflicts::db_time;
+use crate::login::to_login;
+use crate::roles::{can, Allowed};
+use crate::sites::{job_tz, site_tz};
+use crate::{errors::CustomError, now_in, AppState, SIGNING_KEY};
+use crate::{get_user, Backend};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use git_version::git_version;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, EcLevel, QrCode};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::query;
+use time::macros::format_description;
+use tracing::{info, warn};
+
+/// What a QR code points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Job(i64),
+    Site(String),
+}
+
+impl Target {
+    fn payload(&self) -> String {
+        match self {
+            Target::Job(id) => format!("job:{id}"),
+            Target::Site(name) => format!("site:{name}"),
+        }
+    }
+
+    fn from_payload(payload: &str) -> Option<Target> {
+        match payload.split_once(':')? {
+            ("job", id) => id.parse().ok().map(Target::Job),
+            ("site", name) => Some(Target::Site(name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// QR codes get their own key from the signing key, so a code's signature
+/// can't be reused anywhere else
+fn mac() -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_KEY.get().unwrap()).unwrap();
+    mac.update(b"cz4r qr code\0");
+    mac
+}
+
+fn sign(target: &Target) -> String {
+    let payload = target.payload();
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+}
+
+fn verify(token: &str) -> Option<Target> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac();
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+    Target::from_payload(std::str::from_utf8(&payload).ok()?)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct QrForm {
+    job: Option<i64>,
+    site: Option<String>,
+}
+
+/// A printable QR code for a job or a site
+pub(crate) async fn qrpage(
+    State(AppState {
+        pool,
+        engine,
+        site_url,
+        ..
+    }): State<AppState>,
+    _: Allowed<can::ScheduleJobs>,
+    Form(form): Form<QrForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (target, label, detail) = match (form.job, form.site) {
+        (Some(id), _) => {
+            let job = query!(
+                "select sitename, workorder, date from jobs where id = $1;",
+                id
+            )
+            .fetch_optional(&pool)
+            .await?
+            .ok_or_else(|| CustomError::NotFound(format!("There is no job {id}")))?;
+            (
+                Target::Job(id),
+                job.sitename,
+                format!("Work order {}, {}", job.workorder, job.date),
+            )
+        }
+        (None, Some(site)) => (
+            Target::Site(site.clone()),
+            site,
+            "Opens the scanning worker's job here today".to_string(),
+        ),
+        (None, None) => return Err(CustomError::Validation("Pick a job or a site".to_string())),
+    };
+
+    let link = format!("https://{site_url}/scan?t={}", sign(&target));
+    let svg = QrCode::with_error_correction_level(link.as_bytes(), EcLevel::M)?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R QR Code",
+        "admin": true,
+        "logged_in": true,
+        "label": label,
+        "detail": detail,
+        "svg": svg,
+        "link": link,
+    });
+
+    Ok(RenderHtml("qr.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ScanForm {
+    t: String,
+}
+
+/// Where a scanned code lands, it opens the scanner's own assignment. Someone
+/// not logged in yet comes back to it after logging in.
+pub(crate) async fn scan(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<ScanForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    if auth.user.is_none() {
+        let back = format!("/scan?{}", serde_urlencoded::to_string([("t", &form.t)])?);
+        return Ok(to_login(Some(&back), false).into_response());
+    }
+    let (my_id, my_name, role) = get_user(&auth)?;
+
+    let Some(target) = verify(&form.t) else {
+        warn!("user {my_name} (id {my_id}) scanned a QR code with a bad signature");
+        return Err(CustomError::Forbidden(
+            "This QR code is not valid".to_string(),
+        ));
+    };
+
+    let jobs = match &target {
+        Target::Job(id) => {
+            let today = now_in(job_tz(&pool, *id).await?).date();
+            let jobs = query!(
+                r#"
+                select jobs.id, jobs.workorder, jobs.starttime, jobs.date from jobs
+                    inner join jobworkers
+                    on jobs.id = jobworkers.job
+                where jobs.id = $1 and jobworkers.worker = $2;
+                "#,
+                id,
+                my_id
+            )
+            .fetch_all(&pool)
+            .await?;
+            if let Some(j) = jobs.iter().find(|j| j.date != today) {
+                info!(
+                    "user {my_name} (id {my_id}) scanned the QR code for job {id} on {} instead of today",
+                    j.date
+                );
+                return Err(CustomError::Validation(format!(
+                    "This code is for your job on {}, not today",
+                    j.date
+                )));
+            }
+            jobs.into_iter()
+                .map(|j| (j.id, j.workorder, j.starttime))
+                .collect::<Vec<_>>()
+        }
+        Target::Site(site) => {
+            let today = now_in(site_tz(&pool, site).await?).date();
+            query!(
+                r#"
+                select jobs.id, jobs.workorder, jobs.starttime from jobs inner join jobworkers
+                    on jobs.id = jobworkers.job
+                where jobs.sitename = $1 and jobs.date = $2 and jobworkers.worker = $3
+                order by jobs.starttime asc, jobs.id asc;
+                "#,
+                site,
+                today,
+                my_id
+            )
+            .fetch_all(&pool)
+            .await?
+            .into_iter()
+            .map(|j| (j.id, j.workorder, j.starttime))
+            .collect::<Vec<_>>()
+        }
+    };
+
+    info!(
+        "user {my_name} (id {my_id}) scanned the QR code for {:?}",
+        target
+    );
+
+    match jobs.as_slice() {
+        [] => Err(match target {
+            Target::Job(_) => {
+                CustomError::Forbidden("You are not assigned to this job".to_string())
+            }
+            Target::Site(site) => CustomError::NotFound(format!("You have no job at {site} today")),
+        }),
+        [(id, _, _)] => {
+            Ok(Redirect::to(&format!("/checkinout?id={id}&worker={my_id}")).into_response())
+        }
+        jobs => {
+            let data = json!({
+            "git_ver": git_version!(),
+                "title": "CZ4R Pick a Job",
+                "admin": role.is_staff(),
+                "logged_in": true,
+                "worker": my_id,
+                "jobs": jobs
+                    .iter()
+                    .map(|(id, workorder, starttime)| {
+                        json!({
+                            "id": id,
+                            "workorder": workorder,
+                            "starttime": db_time(starttime)
+                                .and_then(|t| t.format(format_description!("[hour]:[minute]")).ok()),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            });
+            Ok(RenderHtml("scan.hbs", engine, data).into_response())
+        }
+    }
+}
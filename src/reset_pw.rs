@@ -1,7 +1,8 @@
 //Name=&Address=&Phone=&Email=&Hourly=&Mileage=&Drivetime=
 
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed};
 use crate::AppState;
 use crate::Backend;
 use axum::extract::Path;
@@ -27,10 +28,16 @@ pub(crate) struct ResetPwForm {
 
 pub(crate) async fn reset_pw(
     State(AppState { pool, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
+    from: Requester,
     Form(form): Form<ResetPwForm>,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
+    let mut tx = pool.begin().await?;
+
     query!(
         r#"
         update users
@@ -39,9 +46,18 @@ pub(crate) async fn reset_pw(
         "#,
         form.id
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
 
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "reset password",
+        &format!("user {}", form.id),
+    )
+    .await?;
+    tx.commit().await?;
     info!(
         "admin {my_name} (id {my_id}) reset user {}'s password",
         form.id
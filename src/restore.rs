@@ -1,5 +1,6 @@
+use crate::audit::{audit, Requester};
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed};
 use crate::AppState;
 
 use super::Worker;
@@ -36,10 +37,8 @@ struct RestoreListItem {
 
 pub async fn restorepage(
     State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    me: Allowed<can::ManageUsers>,
 ) -> Result<impl IntoResponse, CustomError> {
-    get_admin(&auth)?;
-
     let workers = query_as!(
         RestoreListItem,
         "select id, name from users where users.deactivated = true order by id asc"
@@ -52,6 +51,7 @@ pub async fn restorepage(
         "title": "CZ4R Restore Workers",
         "admin": true,
         "logged_in": true,
+        "can": me.role.view(),
         "workers": workers
     });
 
@@ -59,19 +59,33 @@ pub async fn restorepage(
 }
 
 pub(crate) async fn restore(
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageUsers>,
     State(AppState { pool, .. }): State<AppState>,
+    from: Requester,
     Form(restore_form): Form<RestoreForm>, //Extension(worker): Extension<Worker>
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
+    let mut tx = pool.begin().await?;
 
     query!(
         "update users set deactivated = false where id = $1",
         restore_form.user
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
 
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "restored worker",
+        &format!("user {}", restore_form.user),
+    )
+    .await?;
+    tx.commit().await?;
     info!(
         "admin {my_name} (id {my_id}) restored deactivated user {}",
         restore_form.user
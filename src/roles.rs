@@ -0,0 +1,339 @@
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use crate::{errors::CustomError, Backend};
+use axum::{extract::FromRequestParts, http::request::Parts};
+use axum_login::AuthSession;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Something a user may be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Create, change and delete jobs, staff them and confirm claims
+    ScheduleJobs,
+    /// See every job and time sheet, and their history
+    ViewAllJobs,
+    /// Change other workers' time sheets, punches, signatures and attachments
+    EditTimesheets,
+    /// See pay rates and pay reports
+    ViewRates,
+    /// Create, change, deactivate and log out users, and reset passwords
+    ManageUsers,
+    /// Sites, service codes, checklists, kiosks and settings
+    ManageSettings,
+    /// Approve and deny time off
+    DecideTimeOff,
+    /// Download the whole database
+    ExportData,
+    /// See and download the audit log
+    ViewAuditLog,
+    /// Be put in charge of a job's crew
+    LeadCrews,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 10] = [
+        Permission::ScheduleJobs,
+        Permission::ViewAllJobs,
+        Permission::EditTimesheets,
+        Permission::ViewRates,
+        Permission::ManageUsers,
+        Permission::ManageSettings,
+        Permission::DecideTimeOff,
+        Permission::ExportData,
+        Permission::ViewAuditLog,
+        Permission::LeadCrews,
+    ];
+
+    /// How templates ask about it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ScheduleJobs => "schedule_jobs",
+            Permission::ViewAllJobs => "view_all_jobs",
+            Permission::EditTimesheets => "edit_timesheets",
+            Permission::ViewRates => "view_rates",
+            Permission::ManageUsers => "manage_users",
+            Permission::ManageSettings => "manage_settings",
+            Permission::DecideTimeOff => "decide_timeoff",
+            Permission::ExportData => "export_data",
+            Permission::ViewAuditLog => "view_audit_log",
+            Permission::LeadCrews => "lead_crews",
+        }
+    }
+
+    /// Finishes "You don't have permission to ..."
+    fn describe(&self) -> &'static str {
+        match self {
+            Permission::ScheduleJobs => "schedule jobs",
+            Permission::ViewAllJobs => "see other workers' jobs",
+            Permission::EditTimesheets => "change other workers' time sheets",
+            Permission::ViewRates => "see pay rates",
+            Permission::ManageUsers => "manage users",
+            Permission::ManageSettings => "change settings",
+            Permission::DecideTimeOff => "decide time off",
+            Permission::ExportData => "export data",
+            Permission::ViewAuditLog => "see the audit log",
+            Permission::LeadCrews => "lead crews",
+        }
+    }
+}
+
+/// What a user is, which decides what they're allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Everything
+    Admin,
+    /// Office staff who schedule jobs
+    Dispatcher,
+    /// Office staff who check time sheets and pay
+    Payroll,
+    /// A worker who can be put in charge of a crew
+    CrewLead,
+    /// Their own jobs and time sheets
+    Worker,
+    /// Can look at everything but change nothing
+    Auditor,
+}
+
+impl Role {
+    pub const ALL: [Role; 6] = [
+        Role::Admin,
+        Role::Dispatcher,
+        Role::Payroll,
+        Role::CrewLead,
+        Role::Worker,
+        Role::Auditor,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Dispatcher => "dispatcher",
+            Role::Payroll => "payroll",
+            Role::CrewLead => "crew_lead",
+            Role::Worker => "worker",
+            Role::Auditor => "auditor",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Role::Admin => "Admin",
+            Role::Dispatcher => "Dispatcher",
+            Role::Payroll => "Payroll",
+            Role::CrewLead => "Crew lead",
+            Role::Worker => "Worker",
+            Role::Auditor => "Auditor",
+        }
+    }
+
+    fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Admin => &Permission::ALL,
+            Role::Dispatcher => &[ScheduleJobs, ViewAllJobs, DecideTimeOff],
+            Role::Payroll => &[ViewAllJobs, EditTimesheets, ViewRates, ExportData],
+            Role::CrewLead => &[LeadCrews],
+            Role::Worker => &[],
+            Role::Auditor => &[ViewAllJobs, ViewRates, ViewAuditLog],
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    /// Whether they get the admin panel, which is everything but leading crews
+    pub fn is_staff(&self) -> bool {
+        self.permissions()
+            .iter()
+            .any(|p| *p != Permission::LeadCrews)
+    }
+
+    /// Each permission by name, for templates to show only what can be used
+    pub fn view(&self) -> Value {
+        Value::Object(
+            Permission::ALL
+                .iter()
+                .map(|p| (p.as_str().to_string(), Value::Bool(self.can(*p))))
+                .collect(),
+        )
+    }
+
+    /// Every role for a select, with `selected` picked
+    pub fn options(selected: Role) -> Value {
+        Value::Array(
+            Role::ALL
+                .iter()
+                .map(|r| {
+                    json!({
+                        "value": r.as_str(),
+                        "label": r.label(),
+                        "selected": *r == selected,
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|r| r.as_str() == s)
+            .ok_or_else(|| format!("{s} isn't a role"))
+    }
+}
+
+/// What a handler can require with [`Allowed`] or [`SelfOr`]
+pub(crate) trait Grant {
+    fn allows(role: Role) -> bool;
+
+    /// Why someone without it was turned away
+    fn refusal() -> String;
+}
+
+/// Types to name permissions with in [`Allowed`] and [`SelfOr`]
+pub(crate) mod can {
+    use super::{Grant, Permission, Role};
+
+    macro_rules! grants {
+        ($($name:ident),*) => {
+            $(
+                pub(crate) struct $name;
+
+                impl Grant for $name {
+                    fn allows(role: Role) -> bool {
+                        role.can(Permission::$name)
+                    }
+
+                    fn refusal() -> String {
+                        format!("You don't have permission to {}", Permission::$name.describe())
+                    }
+                }
+            )*
+        };
+    }
+
+    grants!(
+        ScheduleJobs,
+        ViewAllJobs,
+        EditTimesheets,
+        ViewRates,
+        ManageUsers,
+        ManageSettings,
+        DecideTimeOff,
+        ExportData,
+        ViewAuditLog
+    );
+
+    /// Any permission that comes with the admin panel
+    pub(crate) struct Staff;
+
+    impl Grant for Staff {
+        fn allows(role: Role) -> bool {
+            role.is_staff()
+        }
+
+        fn refusal() -> String {
+            "The admin panel is only for office staff".to_string()
+        }
+    }
+}
+
+/// The logged in user, or the request is turned away
+async fn logged_in<S: Send + Sync>(
+    parts: &mut Parts,
+    state: &S,
+) -> Result<(i64, String, Role), CustomError> {
+    let auth = AuthSession::<Backend>::from_request_parts(parts, state)
+        .await
+        .map_err(|(_, e)| CustomError::Internal(anyhow::anyhow!(e)))?;
+    let user = auth.user.ok_or(CustomError::NotAuthenticated)?;
+    let role = user.role();
+    Ok((user.id, user.name, role))
+}
+
+/// The logged in user, when their role has permission `P`. Otherwise the
+/// request is turned away before the handler runs.
+pub(crate) struct Allowed<P> {
+    pub id: i64,
+    pub name: String,
+    pub role: Role,
+    permission: PhantomData<P>,
+}
+
+impl<S: Send + Sync, P: Grant> FromRequestParts<S> for Allowed<P> {
+    type Rejection = CustomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let (id, name, role) = logged_in(parts, state).await?;
+
+        if !P::allows(role) {
+            return Err(CustomError::Forbidden(P::refusal()));
+        }
+
+        Ok(Self {
+            id,
+            name,
+            role,
+            permission: PhantomData,
+        })
+    }
+}
+
+/// The logged in user, who may act on their own jobs and time sheets, and on
+/// everyone's when their role has permission `P`. Whose a request is about is
+/// only known once it's been read, so handlers ask with [`SelfOr::check`].
+pub(crate) struct SelfOr<P> {
+    pub id: i64,
+    pub name: String,
+    pub role: Role,
+    permission: PhantomData<P>,
+}
+
+impl<P: Grant> SelfOr<P> {
+    /// Whether they may act on everyone's
+    pub fn granted(&self) -> bool {
+        P::allows(self.role)
+    }
+
+    /// Turns them away unless `worker` is them or they have permission `P`
+    pub fn check(&self, worker: i64) -> Result<(), CustomError> {
+        if worker == self.id || self.granted() {
+            Ok(())
+        } else {
+            Err(CustomError::Forbidden(P::refusal()))
+        }
+    }
+
+    /// The same user, asking about permission `Q` instead
+    pub fn with<Q: Grant>(&self) -> SelfOr<Q> {
+        SelfOr {
+            id: self.id,
+            name: self.name.clone(),
+            role: self.role,
+            permission: PhantomData,
+        }
+    }
+}
+
+impl<S: Send + Sync, P: Grant> FromRequestParts<S> for SelfOr<P> {
+    type Rejection = CustomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let (id, name, role) = logged_in(parts, state).await?;
+
+        Ok(Self {
+            id,
+            name,
+            role,
+            permission: PhantomData,
+        })
+    }
+}
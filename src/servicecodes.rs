@@ -0,0 +1,336 @@
+use crate::audit::{audit, Requester};
+use crate::checklists::checklist_templates;
+use crate::roles::{can, Allowed};
+use crate::signatures::job_signature;
+use crate::Backend;
+use crate::{empty_string_as_none, errors::CustomError, AppState};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, Pool, Sqlite};
+use tracing::info;
+
+/// The time sheet fields the rules can complain about, by their name on the form
+pub const FIELDS: [&str; 6] = [
+    "MilesDriven",
+    "DriveTime",
+    "ExtraExpenses",
+    "Notes",
+    "Photos",
+    "Signature",
+];
+
+/// What a service code's assignments need before they're checked out of
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    pub signature: bool,
+    pub notes: bool,
+    pub miles: (Option<f64>, Option<f64>),
+    pub drive_hours: (Option<f64>, Option<f64>),
+    pub expenses: (Option<f64>, Option<f64>),
+    pub photos: i64,
+}
+
+/// What an assignment has filled in so far
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub notes: String,
+    pub miles: f64,
+    pub drive_hours: f64,
+    pub expenses: f64,
+    pub photos: i64,
+    pub signed: bool,
+}
+
+fn out_of_range(what: &str, value: f64, (min, max): (Option<f64>, Option<f64>)) -> Option<String> {
+    match (min, max) {
+        (Some(min), _) if value < min => Some(format!("{what} has to be at least {min}")),
+        (_, Some(max)) if value > max => Some(format!("{what} can be at most {max}")),
+        _ => None,
+    }
+}
+
+impl Rules {
+    /// What's wrong with each field that doesn't meet the rules
+    pub fn problems(&self, c: &Completion) -> Vec<(&'static str, String)> {
+        [
+            (
+                "MilesDriven",
+                out_of_range("Miles driven", c.miles, self.miles),
+            ),
+            (
+                "DriveTime",
+                out_of_range("Drive time in hours", c.drive_hours, self.drive_hours),
+            ),
+            (
+                "ExtraExpenses",
+                out_of_range("Extra expenses in dollars", c.expenses, self.expenses),
+            ),
+            (
+                "Notes",
+                (self.notes && c.notes.trim().is_empty())
+                    .then(|| "Field notes are needed for this service".to_string()),
+            ),
+            (
+                "Photos",
+                (c.photos < self.photos).then(|| {
+                    format!(
+                        "This service needs at least {} photo{}",
+                        self.photos,
+                        if self.photos == 1 { "" } else { "s" }
+                    )
+                }),
+            ),
+            (
+                "Signature",
+                (self.signature && !c.signed)
+                    .then(|| "The customer needs to sign off on this job".to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|p| (field, p)))
+        .collect()
+    }
+}
+
+/// The rules for a job's service code, codes without a row need nothing
+pub(crate) async fn job_rules(pool: &Pool<Sqlite>, job: i64) -> Result<Rules, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select servicecodes.signature_required, servicecodes.notes_required,
+            servicecodes.min_miles, servicecodes.max_miles,
+            servicecodes.min_drive_hours, servicecodes.max_drive_hours,
+            servicecodes.min_expenses, servicecodes.max_expenses,
+            servicecodes.min_photos
+            from jobs inner join servicecodes
+            on jobs.servicecode = servicecodes.code
+        where jobs.id = $1;
+        "#,
+        job
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|r| Rules {
+        signature: r.signature_required,
+        notes: r.notes_required,
+        miles: (r.min_miles, r.max_miles),
+        drive_hours: (r.min_drive_hours, r.max_drive_hours),
+        expenses: (r.min_expenses, r.max_expenses),
+        photos: r.min_photos,
+    })
+    .unwrap_or_default())
+}
+
+/// Photos on the whole job or on this worker's time sheet
+pub(crate) async fn photo_count(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<i64, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select count(*) as "count!: i64" from attachments
+            where job = $1 and (worker is null or worker = $2) and mime like 'image/%';
+        "#,
+        job,
+        worker
+    )
+    .fetch_one(pool)
+    .await?
+    .count)
+}
+
+/// What's been saved on an assignment's time sheet
+pub(crate) async fn saved_completion(
+    pool: &Pool<Sqlite>,
+    job: i64,
+    worker: i64,
+) -> Result<Completion, sqlx::Error> {
+    let jw = query!(
+        r#"
+        select notes, miles_driven, hours_driven, extraexpcents from jobworkers
+            where job = $1 and worker = $2;
+        "#,
+        job,
+        worker
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Completion {
+        notes: jw.notes,
+        miles: jw.miles_driven,
+        drive_hours: jw.hours_driven,
+        expenses: jw.extraexpcents as f64 / 100.,
+        photos: photo_count(pool, job, worker).await?,
+        signed: job_signature(pool, job).await?.is_some(),
+    })
+}
+
+fn show_limit(limit: Option<f64>) -> String {
+    limit.map(|l| l.to_string()).unwrap_or_default()
+}
+
+pub(crate) async fn servicecodespage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ManageSettings>,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut checklists = checklist_templates(&pool).await?;
+
+    let codes = query!(
+        r#"
+        select distinct jobs.servicecode as code,
+            servicecodes.signature_required as "signature_required?",
+            servicecodes.notes_required as "notes_required?",
+            servicecodes.min_miles, servicecodes.max_miles,
+            servicecodes.min_drive_hours, servicecodes.max_drive_hours,
+            servicecodes.min_expenses, servicecodes.max_expenses,
+            servicecodes.min_photos as "min_photos?"
+            from jobs left join servicecodes
+            on jobs.servicecode = servicecodes.code
+        order by jobs.servicecode asc;
+        "#
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        json!({
+            "code": r.code,
+            "signature_required": r.signature_required.unwrap_or(false),
+            "notes_required": r.notes_required.unwrap_or(false),
+            "min_miles": show_limit(r.min_miles),
+            "max_miles": show_limit(r.max_miles),
+            "min_drive_hours": show_limit(r.min_drive_hours),
+            "max_drive_hours": show_limit(r.max_drive_hours),
+            "min_expenses": show_limit(r.min_expenses),
+            "max_expenses": show_limit(r.max_expenses),
+            "min_photos": r.min_photos.unwrap_or(0),
+            "checklist": checklists.remove(&r.code).unwrap_or_default(),
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Service Codes",
+        "admin": true,
+        "logged_in": true,
+        "codes": codes,
+    });
+
+    Ok(RenderHtml("servicecodes.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ServiceCodeForm {
+    code: String,
+    signature_required: Option<String>,
+    notes_required: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_miles: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_miles: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_drive_hours: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_drive_hours: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_expenses: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_expenses: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_photos: Option<i64>,
+}
+
+pub(crate) async fn edit_servicecode(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<ServiceCodeForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let signature_required = form.signature_required.is_some();
+    let notes_required = form.notes_required.is_some();
+    let min_photos = form.min_photos.unwrap_or(0);
+
+    for (what, min, max) in [
+        ("miles", form.min_miles, form.max_miles),
+        ("drive hours", form.min_drive_hours, form.max_drive_hours),
+        ("expenses", form.min_expenses, form.max_expenses),
+    ] {
+        if min.is_some_and(|m| m < 0.) || max.is_some_and(|m| m < 0.) {
+            return Err(CustomError::Validation(format!(
+                "The limits on {what} can't be negative"
+            )));
+        }
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(CustomError::Validation(format!(
+                    "The least {what} can't be more than the most"
+                )));
+            }
+        }
+    }
+    if min_photos < 0 {
+        return Err(CustomError::Validation(
+            "The number of photos can't be negative".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    query!(
+        r#"
+        insert into servicecodes (code, signature_required, notes_required,
+            min_miles, max_miles, min_drive_hours, max_drive_hours,
+            min_expenses, max_expenses, min_photos)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            on conflict (code) do update
+            set signature_required = excluded.signature_required,
+                notes_required = excluded.notes_required,
+                min_miles = excluded.min_miles, max_miles = excluded.max_miles,
+                min_drive_hours = excluded.min_drive_hours,
+                max_drive_hours = excluded.max_drive_hours,
+                min_expenses = excluded.min_expenses, max_expenses = excluded.max_expenses,
+                min_photos = excluded.min_photos;
+        "#,
+        form.code,
+        signature_required,
+        notes_required,
+        form.min_miles,
+        form.max_miles,
+        form.min_drive_hours,
+        form.max_drive_hours,
+        form.min_expenses,
+        form.max_expenses,
+        min_photos
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit(&mut tx, &from, my_id, "changed service code", &form.code).await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) set service code {} to need: signature {signature_required}, notes {notes_required}, miles {:?}-{:?}, drive hours {:?}-{:?}, expenses {:?}-{:?}, {min_photos} photos",
+        form.code,
+        form.min_miles,
+        form.max_miles,
+        form.min_drive_hours,
+        form.max_drive_hours,
+        form.min_expenses,
+        form.max_expenses
+    );
+
+    Ok(Redirect::to("/admin/servicecodes"))
+}
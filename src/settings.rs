@@ -0,0 +1,168 @@
+use crate::audit::{audit, Requester};
+use crate::roles::{can, Allowed};
+use crate::Backend;
+use crate::{errors::CustomError, AppState};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{query, Pool, Sqlite, SqliteConnection};
+use tracing::info;
+
+/// How workers may record their sign-in and sign-out times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockPolicy {
+    /// Workers may type times or use the clock buttons
+    Manual,
+    /// Workers have to use the clock buttons, only admins can type times
+    ClockOnly,
+}
+
+impl ClockPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ClockPolicy::Manual => "manual",
+            ClockPolicy::ClockOnly => "clock_only",
+        }
+    }
+}
+
+pub(crate) async fn get_setting(
+    pool: &Pool<Sqlite>,
+    name: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    Ok(query!("select value from settings where name = $1;", name)
+        .fetch_optional(pool)
+        .await?
+        .map(|r| r.value))
+}
+
+async fn set_setting(
+    conn: &mut SqliteConnection,
+    name: &str,
+    value: &str,
+) -> Result<(), sqlx::Error> {
+    query!(
+        r#"
+        insert into settings (name, value) values ($1, $2)
+            on conflict (name) do update set value = excluded.value;
+        "#,
+        name,
+        value
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn clock_policy(pool: &Pool<Sqlite>) -> Result<ClockPolicy, sqlx::Error> {
+    Ok(match get_setting(pool, "clock_policy").await?.as_deref() {
+        Some("clock_only") => ClockPolicy::ClockOnly,
+        _ => ClockPolicy::Manual,
+    })
+}
+
+/// How far from a site, in meters, a clock punch can be before it's flagged
+pub(crate) async fn geofence_radius(pool: &Pool<Sqlite>) -> Result<f64, sqlx::Error> {
+    Ok(get_setting(pool, "geofence_radius")
+        .await?
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(200.))
+}
+
+/// How many times the straight-line suggestion entered driving can be before
+/// it's highlighted
+pub(crate) async fn mileage_ratio(pool: &Pool<Sqlite>) -> Result<f64, sqlx::Error> {
+    Ok(get_setting(pool, "mileage_ratio")
+        .await?
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(1.5))
+}
+
+pub(crate) async fn settingspage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ManageSettings>,
+) -> Result<impl IntoResponse, CustomError> {
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Settings",
+        "admin": true,
+        "logged_in": true,
+        "clock_policy": clock_policy(&pool).await?,
+        "geofence_radius": geofence_radius(&pool).await?,
+        "mileage_ratio": mileage_ratio(&pool).await?,
+    });
+
+    Ok(RenderHtml("settings.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SettingsForm {
+    clock_policy: ClockPolicy,
+    geofence_radius: f64,
+    mileage_ratio: f64,
+}
+
+pub(crate) async fn settings(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<SettingsForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    if !(form.geofence_radius.is_finite() && form.geofence_radius > 0.) {
+        return Err(CustomError::Validation(
+            "The geofence radius has to be more than 0 meters".to_string(),
+        ));
+    }
+
+    if !(form.mileage_ratio.is_finite() && form.mileage_ratio >= 1.) {
+        return Err(CustomError::Validation(
+            "The mileage ratio has to be at least 1".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    set_setting(&mut tx, "clock_policy", form.clock_policy.as_str()).await?;
+    set_setting(
+        &mut tx,
+        "geofence_radius",
+        &form.geofence_radius.to_string(),
+    )
+    .await?;
+    set_setting(&mut tx, "mileage_ratio", &form.mileage_ratio.to_string()).await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "changed settings",
+        &format!(
+            "clock policy {}, geofence radius {} m, mileage ratio {}",
+            form.clock_policy.as_str(),
+            form.geofence_radius,
+            form.mileage_ratio
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) set the clock policy to {}, the geofence radius to {} m and the mileage ratio to {}",
+        form.clock_policy.as_str(),
+        form.geofence_radius,
+        form.mileage_ratio
+    );
+
+    Ok(Redirect::to("/admin/settings"))
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use crate::dispatch::publish;
+use crate::punches::db_datetime;
+use crate::roles::{can, Grant, SelfOr};
+use crate::sites::job_tz;
+use crate::{errors::CustomError, AppState};
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use sqlx::{query, types::time::Date, Pool, Sqlite};
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::info;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// A signature pad drawing is a few kilobytes, anything near this isn't one
+const MAX_SIGNATURE_BYTES: usize = 512 * 1024;
+
+/// Who signed off on a job and when, in the site's local time
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub signer: String,
+    pub signed_at: Option<PrimitiveDateTime>,
+    pub offline: bool,
+}
+
+impl Signature {
+    pub fn describe(&self) -> String {
+        let when = self
+            .signed_at
+            .and_then(|t| {
+                t.format(format_description!(
+                    "[month repr:short] [day], [year] [hour]:[minute]"
+                ))
+                .ok()
+            })
+            .unwrap_or_default();
+        format!("Signed by {} {when}", self.signer)
+    }
+}
+
+pub(crate) async fn job_signature(
+    pool: &Pool<Sqlite>,
+    job: i64,
+) -> Result<Option<Signature>, sqlx::Error> {
+    Ok(query!(
+        "select signer, signed_at, offline from signatures where job = $1;",
+        job
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|r| Signature {
+        signer: r.signer,
+        signed_at: db_datetime(&Some(r.signed_at)),
+        offline: r.offline,
+    }))
+}
+
+/// Signatures on a worker's jobs between two dates, by job
+pub(crate) async fn worker_signatures(
+    pool: &Pool<Sqlite>,
+    worker: i64,
+    from: Date,
+    to: Date,
+) -> Result<HashMap<i64, Signature>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select signatures.job, signatures.signer, signatures.signed_at, signatures.offline
+            from signatures
+            inner join jobworkers on signatures.job = jobworkers.job
+            inner join jobs on signatures.job = jobs.id
+        where jobworkers.worker = $1 and jobs.date >= $2 and jobs.date <= $3;
+        "#,
+        worker,
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        (
+            r.job,
+            Signature {
+                signer: r.signer,
+                signed_at: db_datetime(&Some(r.signed_at)),
+                offline: r.offline,
+            },
+        )
+    })
+    .collect())
+}
+
+/// Only someone on the job can see or give its signature, or staff with
+/// permission `P`. When `worker` is given it has to be them as well.
+pub(crate) async fn check_on_job<P: Grant>(
+    pool: &Pool<Sqlite>,
+    user: &SelfOr<P>,
+    job: i64,
+    worker: Option<i64>,
+) -> Result<(), CustomError> {
+    if let Some(worker) = worker {
+        user.check(worker)?;
+    }
+
+    let on_job = query!(
+        "select job from jobworkers where job = $1 and worker = $2;",
+        job,
+        user.id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+
+    if !user.granted() && !on_job {
+        return Err(CustomError::Forbidden(
+            "You are not assigned to this job".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SignatureForm {
+    JobId: i64,
+    WorkerId: i64,
+    signer: String,
+    /// The pad's drawing as a data URL
+    image: String,
+}
+
+pub(crate) async fn sign(
+    State(state): State<AppState>,
+    user: SelfOr<can::EditTimesheets>,
+    Form(form): Form<SignatureForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (job, worker) = (form.JobId, form.WorkerId);
+
+    sign_at(&state, &user, form, OffsetDateTime::now_utc(), false).await?;
+
+    Ok(Redirect::to(&format!(
+        "/checkinout?id={job}&worker={worker}"
+    )))
+}
+
+/// Saves the customer's signature as given at `at`, replacing any earlier one
+pub(crate) async fn sign_at(
+    AppState { pool, events, .. }: &AppState,
+    user: &SelfOr<can::EditTimesheets>,
+    form: SignatureForm,
+    at: OffsetDateTime,
+    offline: bool,
+) -> Result<(), CustomError> {
+    check_on_job(pool, user, form.JobId, Some(form.WorkerId)).await?;
+
+    let signer = form.signer.trim();
+    if signer.is_empty() {
+        return Err(CustomError::Validation(
+            "The customer needs to print their name under the signature".to_string(),
+        ));
+    }
+
+    let image = form
+        .image
+        .strip_prefix("data:image/png;base64,")
+        .and_then(|b64| STANDARD.decode(b64).ok())
+        .filter(|png| png.starts_with(PNG_MAGIC) && png.len() <= MAX_SIGNATURE_BYTES)
+        .ok_or_else(|| {
+            CustomError::Validation("The signature didn't come through, try again".to_string())
+        })?;
+
+    let local = at.to_timezone(job_tz(pool, form.JobId).await?);
+    let stamp = PrimitiveDateTime::new(local.date(), local.time()).replace_nanosecond(0)?;
+
+    query!(
+        r#"
+        insert into signatures (job, signer, image, mime, signed_at, worker, offline)
+            values ($1, $2, $3, 'image/png', $4, $5, $6)
+            on conflict (job) do update
+            set signer = excluded.signer, image = excluded.image, mime = excluded.mime,
+                signed_at = excluded.signed_at, worker = excluded.worker,
+                offline = excluded.offline;
+        "#,
+        form.JobId,
+        signer,
+        image,
+        stamp,
+        form.WorkerId,
+        offline
+    )
+    .execute(pool)
+    .await?;
+
+    publish(events, form.JobId, Some(form.WorkerId), "checkinout");
+
+    info!(
+        "{} {} (id {}) took {signer}'s signature for job {} at {stamp}{}",
+        user.role.as_str(),
+        user.name,
+        user.id,
+        form.JobId,
+        if offline { " while offline" } else { "" }
+    );
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SignatureImageForm {
+    job: i64,
+}
+
+pub(crate) async fn signature(
+    State(AppState { pool, .. }): State<AppState>,
+    user: SelfOr<can::ViewAllJobs>,
+    Form(form): Form<SignatureImageForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    check_on_job(&pool, &user, form.job, None).await?;
+
+    let sig = query!(
+        "select image, mime from signatures where job = $1;",
+        form.job
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or_else(|| CustomError::NotFound(format!("Job {} hasn't been signed", form.job)))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, sig.mime),
+            (header::CACHE_CONTROL, "private, no-cache".to_string()),
+        ],
+        sig.image,
+    ))
+}
@@ -0,0 +1,211 @@
+use crate::audit::{audit, Requester};
+use crate::roles::{can, Allowed};
+use crate::Backend;
+use crate::{empty_string_as_none, errors::CustomError, AppState, TZ};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, Pool, Sqlite};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use time_tz::{timezones, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz};
+use tracing::info;
+
+/// The site's own time zone, or the server's when it doesn't have one
+pub(crate) fn zone(name: Option<&str>) -> &'static Tz {
+    name.and_then(timezones::get_by_name)
+        .unwrap_or_else(|| TZ.get().unwrap())
+}
+
+/// The time zone of a site by name
+pub(crate) async fn site_tz(
+    pool: &Pool<Sqlite>,
+    sitename: &str,
+) -> Result<&'static Tz, sqlx::Error> {
+    let tz = query!("select tz from sites where name = $1;", sitename)
+        .fetch_optional(pool)
+        .await?
+        .and_then(|r| r.tz);
+    Ok(zone(tz.as_deref()))
+}
+
+/// The time zone of the site a job is at
+pub(crate) async fn job_tz(pool: &Pool<Sqlite>, job: i64) -> Result<&'static Tz, sqlx::Error> {
+    let tz = query!(
+        r#"
+        select sites.tz from jobs inner join sites
+            on jobs.sitename = sites.name
+        where jobs.id = $1;
+        "#,
+        job
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|r| r.tz);
+    Ok(zone(tz.as_deref()))
+}
+
+/// The coordinates of the site a job is at, if it has them
+pub(crate) async fn job_location(
+    pool: &Pool<Sqlite>,
+    job: i64,
+) -> Result<Option<(f64, f64)>, sqlx::Error> {
+    Ok(query!(
+        r#"
+        select sites.lat, sites.lon from jobs inner join sites
+            on jobs.sitename = sites.name
+        where jobs.id = $1;
+        "#,
+        job
+    )
+    .fetch_optional(pool)
+    .await?
+    .and_then(|r| r.lat.zip(r.lon)))
+}
+
+/// Great circle distance in meters between two latitude, longitude pairs
+pub(crate) fn distance_m((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.;
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.).sin().powi(2);
+    2. * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// The instant a site's wall clock time refers to. When the clocks go back
+/// the earlier one is used, a time skipped when they go forward is read as UTC.
+pub(crate) fn instant(t: PrimitiveDateTime, tz: &Tz) -> OffsetDateTime {
+    match t.assume_timezone(tz) {
+        OffsetResult::Some(t) | OffsetResult::Ambiguous(t, _) => t,
+        OffsetResult::None => t.assume_utc(),
+    }
+}
+
+pub(crate) async fn sitespage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ManageSettings>,
+) -> Result<impl IntoResponse, CustomError> {
+    let sites = query!(
+        r#"
+        select distinct jobs.sitename as name, sites.tz as "tz?",
+            sites.lat as "lat?", sites.lon as "lon?"
+            from jobs left join sites
+            on jobs.sitename = sites.name
+        order by jobs.sitename asc;
+        "#
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        json!({
+            "name": r.name,
+            "tz": r.tz.unwrap_or_default(),
+            "lat": r.lat.map(|l| l.to_string()).unwrap_or_default(),
+            "lon": r.lon.map(|l| l.to_string()).unwrap_or_default(),
+        })
+    })
+    .collect::<Vec<_>>();
+
+    let zones = timezones::iter()
+        .map(|tz| tz.name())
+        .sorted()
+        .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Sites",
+        "admin": true,
+        "logged_in": true,
+        "server_tz": TZ.get().unwrap().name(),
+        "sites": sites,
+        "zones": zones,
+    });
+
+    Ok(RenderHtml("sites.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SiteForm {
+    name: String,
+    tz: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    lat: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    lon: Option<f64>,
+}
+
+pub(crate) async fn edit_site(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ManageSettings>,
+    from: Requester,
+    Form(form): Form<SiteForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let tz = match form.tz.trim() {
+        "" => None,
+        tz => match timezones::get_by_name(tz) {
+            Some(tz) => Some(tz.name()),
+            None => {
+                return Err(CustomError::Validation(format!(
+                    "{tz} is not a known time zone"
+                )))
+            }
+        },
+    };
+
+    match (form.lat, form.lon) {
+        (Some(lat), Some(lon))
+            if (-90. ..=90.).contains(&lat) && (-180. ..=180.).contains(&lon) => {}
+        (None, None) => {}
+        _ => return Err(CustomError::Validation(
+            "A site needs both a latitude between -90 and 90 and a longitude between -180 and 180"
+                .to_string(),
+        )),
+    }
+
+    let mut tx = pool.begin().await?;
+
+    if tz.is_none() && form.lat.is_none() {
+        query!("delete from sites where name = $1;", form.name)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        query!(
+            r#"
+            insert into sites (name, tz, lat, lon) values ($1, $2, $3, $4)
+                on conflict (name) do update
+                set tz = excluded.tz, lat = excluded.lat, lon = excluded.lon;
+            "#,
+            form.name,
+            tz,
+            form.lat,
+            form.lon
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit(&mut tx, &from, my_id, "changed site", &form.name).await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) set site {} to time zone {} at {:?}, {:?}",
+        form.name,
+        tz.unwrap_or("of the server"),
+        form.lat,
+        form.lon
+    );
+
+    Ok(Redirect::to("/admin/sites"))
+}
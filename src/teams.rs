@@ -0,0 +1,299 @@
+use crate::audit::{audit, Requester};
+use crate::roles::{can, Allowed, Permission, Role};
+use crate::{empty_string_as_none, errors::CustomError, AppState};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    Form,
+};
+use axum_template::RenderHtml;
+use git_version::git_version;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, Pool, Sqlite};
+use tracing::info;
+
+/// A named group of workers, by trade, region or crew
+#[derive(Debug, Clone)]
+pub(crate) struct Team {
+    pub id: i64,
+    pub name: String,
+    /// Made crew lead on a job when the whole team is assigned to it
+    pub lead: Option<i64>,
+    pub members: Vec<i64>,
+}
+
+/// Every team with its active members, by name
+pub(crate) async fn all_teams(pool: &Pool<Sqlite>) -> Result<Vec<Team>, sqlx::Error> {
+    let mut members = query!(
+        r#"
+        select team_members.team, team_members.worker from team_members inner join users
+            on team_members.worker = users.id
+        where users.deactivated = false
+        order by users.name;
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| (r.team, r.worker))
+    .into_group_map();
+
+    Ok(query!("select id, name, lead from teams order by name;")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|r| Team {
+            id: r.id,
+            name: r.name,
+            lead: r.lead,
+            members: members.remove(&r.id).unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Every team for a select, with `selected` picked. Members are joined with
+/// "-" like the worker lists on the job form.
+pub(crate) async fn team_options(
+    pool: &Pool<Sqlite>,
+    selected: Option<i64>,
+) -> Result<Vec<Value>, sqlx::Error> {
+    Ok(all_teams(pool)
+        .await?
+        .into_iter()
+        .map(|t| {
+            json!({
+                "id": t.id,
+                "name": t.name,
+                "lead": t.lead,
+                "members": t.members.iter().join("-"),
+                "selected": selected == Some(t.id),
+            })
+        })
+        .collect())
+}
+
+pub(crate) async fn teamspage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::ScheduleJobs>,
+) -> Result<impl IntoResponse, CustomError> {
+    let workers =
+        query!("select id, name, role from users where deactivated = false order by name;")
+            .fetch_all(&pool)
+            .await?
+            .into_iter()
+            .map(|r| {
+                let can_lead = r
+                    .role
+                    .parse::<Role>()
+                    .is_ok_and(|role| role.can(Permission::LeadCrews));
+                (r.id, r.name, can_lead)
+            })
+            .collect::<Vec<_>>();
+
+    let teams = all_teams(&pool)
+        .await?
+        .into_iter()
+        .map(|t| {
+            json!({
+                "id": t.id,
+                "name": t.name,
+                "workers": workers
+                    .iter()
+                    .map(|(id, name, can_lead)| {
+                        json!({
+                            "id": id,
+                            "name": name,
+                            "can_lead": can_lead,
+                            "member": t.members.contains(id),
+                            "lead": t.lead == Some(*id),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Teams",
+        "admin": true,
+        "logged_in": true,
+        "teams": teams,
+        "workers": workers
+            .iter()
+            .map(|(id, name, can_lead)| json!({ "id": id, "name": name, "can_lead": can_lead }))
+            .collect::<Vec<_>>(),
+    });
+
+    Ok(RenderHtml("teams.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TeamForm {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    id: Option<i64>,
+    name: String,
+    #[serde(default)]
+    members: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    lead: Option<i64>,
+}
+
+/// Creates a team, or renames one and sets its members and lead
+pub(crate) async fn edit_team(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+    from: Requester,
+    Form(form): Form<TeamForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err(CustomError::Validation("A team needs a name".to_string()));
+    }
+
+    let taken = query!(
+        "select id from teams where name = $1 and id is not $2;",
+        name,
+        form.id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .is_some();
+    if taken {
+        return Err(CustomError::Validation(format!(
+            "There's already a team called {name}"
+        )));
+    }
+
+    let workers = query!("select id, role from users where deactivated = false;")
+        .fetch_all(&pool)
+        .await?;
+    let members = form
+        .members
+        .split('-')
+        .filter_map(|n| n.parse::<i64>().ok())
+        .filter(|id| workers.iter().any(|w| w.id == *id))
+        .unique()
+        .collect::<Vec<_>>();
+
+    if let Some(lead) = form.lead {
+        let can_lead = workers.iter().any(|w| {
+            w.id == lead
+                && w.role
+                    .parse::<Role>()
+                    .is_ok_and(|role| role.can(Permission::LeadCrews))
+        });
+        if !can_lead || !members.contains(&lead) {
+            return Err(CustomError::Validation(
+                "The team lead has to be a member whose role can lead crews".to_string(),
+            ));
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let id = match form.id {
+        Some(id) => {
+            query!(
+                "update teams set name = $1, lead = $2 where id = $3;",
+                name,
+                form.lead,
+                id
+            )
+            .execute(&mut *tx)
+            .await?;
+            query!("delete from team_members where team = $1;", id)
+                .execute(&mut *tx)
+                .await?;
+            id
+        }
+        None => {
+            query!(
+                "insert into teams (name, lead) values ($1, $2) returning id;",
+                name,
+                form.lead
+            )
+            .fetch_one(&mut *tx)
+            .await?
+            .id
+        }
+    };
+
+    for member in &members {
+        query!(
+            "insert into team_members (team, worker) values ($1, $2);",
+            id,
+            member
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        if form.id.is_some() {
+            "changed team"
+        } else {
+            "created team"
+        },
+        &format!("team {id} ({name})"),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) set team {name} (id {id}) to users {:?}, led by {:?}",
+        members, form.lead
+    );
+
+    Ok(Redirect::to("/admin/teams"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteTeamForm {
+    id: i64,
+}
+
+pub(crate) async fn delete_team(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ScheduleJobs>,
+    from: Requester,
+    Form(form): Form<DeleteTeamForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let mut tx = pool.begin().await?;
+
+    query!("delete from team_members where team = $1;", form.id)
+        .execute(&mut *tx)
+        .await?;
+    let name = query!("delete from teams where id = $1 returning name;", form.id)
+        .fetch_one(&mut *tx)
+        .await?
+        .name;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        "deleted team",
+        &format!("team {} ({name})", form.id),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) deleted team {name} (id {})",
+        form.id
+    );
+
+    Ok(Redirect::to("/admin/teams"))
+}
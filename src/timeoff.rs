@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+
+use crate::audit::{audit, Requester};
+use crate::conflicts::db_time;
+use crate::roles::{can, Allowed, Role};
+use crate::validation::{rerender, FieldErrors};
+use crate::{errors::CustomError, AppState};
+use crate::{get_user, Backend};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_login::AuthSession;
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{query, query_as, types::time::Date, Pool, Sqlite};
+use time::{macros::format_description, Time, Weekday};
+use tracing::info;
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TimeOffEntry {
+    id: i64,
+    worker: i64,
+    name: String,
+    start_date: Date,
+    end_date: Date,
+    reason: String,
+    status: String,
+    jobs: i64,
+}
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Sunday,
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+    Weekday::Thursday,
+    Weekday::Friday,
+    Weekday::Saturday,
+];
+
+/// The inputs of one day's availability
+const AVAILABILITY_FIELDS: [&str; 2] = ["starttime", "endtime"];
+
+fn format_time(t: Option<Time>) -> String {
+    t.and_then(|t| t.format(format_description!("[hour]:[minute]")).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) async fn timeoffpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, _my_name, role) = get_user(&auth)?;
+
+    let data = timeoff_page(&pool, my_id, role, None).await?;
+
+    Ok(RenderHtml("timeoff.hbs", engine, data))
+}
+
+/// The time off page. When the availability form didn't go through, what was
+/// submitted is shown in place of what's saved, with each day's errors.
+async fn timeoff_page(
+    pool: &Pool<Sqlite>,
+    my_id: i64,
+    role: Role,
+    submitted: Option<(&HashMap<String, String>, &[FieldErrors])>,
+) -> Result<Value, CustomError> {
+    let requests = query_as!(
+        TimeOffEntry,
+        r#"
+        select timeoff.id, timeoff.worker, users.name, timeoff.start_date,
+            timeoff.end_date, timeoff.reason, timeoff.status, 0 as "jobs!: i64"
+            from timeoff inner join users
+            on timeoff.worker = users.id
+        where timeoff.worker = $1
+        order by timeoff.start_date desc;
+        "#,
+        my_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let patterns = query!(
+        "select weekday, available, starttime, endtime from availability where worker = $1;",
+        my_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // days with no pattern saved are available all day
+    let availability = WEEKDAYS
+        .iter()
+        .map(|day| {
+            let num = day.number_days_from_sunday() as i64;
+            let pattern = patterns.iter().find(|p| p.weekday == num);
+            match submitted {
+                Some((form, errors)) => json!({
+                    "num": num,
+                    "name": day.to_string(),
+                    "available": form.contains_key(&format!("available-{num}")),
+                    "starttime": form.get(&format!("starttime-{num}")).cloned().unwrap_or_default(),
+                    "endtime": form.get(&format!("endtime-{num}")).cloned().unwrap_or_default(),
+                    "errors": errors[num as usize].view(&AVAILABILITY_FIELDS),
+                }),
+                None => json!({
+                    "num": num,
+                    "name": day.to_string(),
+                    "available": pattern.is_none_or(|p| p.available),
+                    "starttime": format_time(pattern.and_then(|p| db_time(&p.starttime))),
+                    "endtime": format_time(pattern.and_then(|p| db_time(&p.endtime))),
+                    "errors": FieldErrors::default().view(&AVAILABILITY_FIELDS),
+                }),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Time Off",
+        "admin": role.is_staff(),
+        "logged_in": true,
+        "requests": requests,
+        "availability": availability,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TimeOffRequestForm {
+    start_date: Date,
+    end_date: Date,
+    reason: String,
+}
+
+pub(crate) async fn request_timeoff(
+    State(AppState { pool, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<TimeOffRequestForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, my_name, _) = get_user(&auth)?;
+
+    if form.end_date < form.start_date {
+        return Err(CustomError::Validation(
+            "Time off has to end on or after the day it starts".to_string(),
+        ));
+    }
+
+    let reason = form.reason.trim();
+
+    query!(
+        r#"
+        insert into timeoff (worker, start_date, end_date, reason)
+            values ($1, $2, $3, $4);
+        "#,
+        my_id,
+        form.start_date,
+        form.end_date,
+        reason
+    )
+    .execute(&pool)
+    .await?;
+
+    info!(
+        "user {my_name} (id {my_id}) requested time off from {} to {}",
+        form.start_date, form.end_date
+    );
+
+    Ok(Redirect::to("/timeoff"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CancelTimeOffForm {
+    id: i64,
+}
+
+pub(crate) async fn cancel_timeoff(
+    State(AppState { pool, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<CancelTimeOffForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let (my_id, my_name, _) = get_user(&auth)?;
+
+    query!(
+        r#"
+        delete from timeoff
+            where id = $1
+            and worker = $2
+            and status = 'pending';
+        "#,
+        form.id,
+        my_id
+    )
+    .execute(&pool)
+    .await?;
+
+    info!(
+        "user {my_name} (id {my_id}) cancelled time off request {}",
+        form.id
+    );
+
+    Ok(Redirect::to("/timeoff"))
+}
+
+pub(crate) async fn set_availability(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    mut auth: AuthSession<Backend>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Response, CustomError> {
+    let (my_id, my_name, role) = get_user(&auth)?;
+
+    let parse = |errors: &mut FieldErrors, field: &'static str, num: i64| -> Option<Time> {
+        match form.get(&format!("{field}-{num}")).map(|s| s.trim()) {
+            None | Some("") => None,
+            Some(s) => Time::parse(s, format_description!("[hour]:[minute]"))
+                .inspect_err(|_| {
+                    errors.add(field, format!("\"{s}\" isn't a time, write it like 08:30"))
+                })
+                .ok(),
+        }
+    };
+
+    let mut days = vec![];
+    let mut errors = vec![];
+    for day in WEEKDAYS {
+        let num = day.number_days_from_sunday() as i64;
+        let mut day_errors = FieldErrors::default();
+        let available = form.contains_key(&format!("available-{num}"));
+        let starttime = parse(&mut day_errors, "starttime", num);
+        let endtime = parse(&mut day_errors, "endtime", num);
+
+        if let (Some(s), Some(e)) = (starttime, endtime) {
+            if e <= s {
+                day_errors.add("endtime", "Availability has to end after it starts");
+            }
+        }
+
+        days.push((num, available, starttime, endtime));
+        errors.push(day_errors);
+    }
+
+    if errors.iter().any(|e| !e.is_empty()) {
+        let data = timeoff_page(&pool, my_id, role, Some((&form, &errors))).await?;
+        return Ok(rerender("timeoff.hbs", engine, data));
+    }
+
+    let mut tx = pool.begin().await?;
+
+    for (num, available, starttime, endtime) in days {
+        query!(
+            r#"
+            insert into availability (worker, weekday, available, starttime, endtime)
+                values ($1, $2, $3, $4, $5)
+            on conflict (worker, weekday) do update set
+                available = excluded.available,
+                starttime = excluded.starttime,
+                endtime = excluded.endtime;
+            "#,
+            my_id,
+            num,
+            available,
+            starttime,
+            endtime
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    info!("user {my_name} (id {my_id}) updated their weekly availability");
+
+    Ok(Redirect::to("/timeoff").into_response())
+}
+
+pub(crate) async fn timeoffadminpage(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    _: Allowed<can::DecideTimeOff>,
+) -> Result<impl IntoResponse, CustomError> {
+    let requests = query_as!(
+        TimeOffEntry,
+        r#"
+        select timeoff.id, timeoff.worker, users.name, timeoff.start_date,
+            timeoff.end_date, timeoff.reason, timeoff.status,
+            (
+                select count(*) from jobworkers inner join jobs
+                    on jobworkers.job = jobs.id
+                where jobworkers.worker = timeoff.worker
+                    and jobs.date >= timeoff.start_date
+                    and jobs.date <= timeoff.end_date
+            ) as "jobs!: i64"
+            from timeoff inner join users
+            on timeoff.worker = users.id
+        where users.deactivated = false
+        order by timeoff.status = 'pending' desc, timeoff.start_date desc;
+        "#
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let data = json!({
+    "git_ver": git_version!(),
+        "title": "CZ4R Time Off Requests",
+        "admin": true,
+        "logged_in": true,
+        "requests": requests,
+    });
+
+    Ok(RenderHtml("timeoffadmin.hbs", engine, data))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DecideTimeOffForm {
+    id: i64,
+    approve: bool,
+}
+
+pub(crate) async fn decide_timeoff(
+    State(AppState { pool, .. }): State<AppState>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::DecideTimeOff>,
+    from: Requester,
+    Form(form): Form<DecideTimeOffForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let status = if form.approve { "approved" } else { "denied" };
+
+    let mut tx = pool.begin().await?;
+
+    query!(
+        "update timeoff set status = $1, decided_by = $2 where id = $3;",
+        status,
+        my_id,
+        form.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    audit(
+        &mut tx,
+        &from,
+        my_id,
+        &format!("{status} time off"),
+        &format!("request {}", form.id),
+    )
+    .await?;
+    tx.commit().await?;
+    info!(
+        "admin {my_name} (id {my_id}) {} time off request {}",
+        status, form.id
+    );
+
+    Ok(Redirect::to("/admin/timeoff"))
+}
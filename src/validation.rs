@@ -0,0 +1,152 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use axum_template::RenderHtml;
+use rust_decimal::prelude::*;
+use rust_decimal::Decimal;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+use crate::AppEngine;
+
+/// Problems with a submitted form, by the name of the input they're about, so
+/// the form can be shown again with what was typed and each message next to
+/// its field instead of going to the error page
+#[derive(Debug, Default, Clone)]
+pub struct FieldErrors(Vec<(&'static str, String)>);
+
+impl From<Vec<(&'static str, String)>> for FieldErrors {
+    fn from(errors: Vec<(&'static str, String)>) -> Self {
+        Self(errors)
+    }
+}
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        if !self.has(field) {
+            self.0.push((field, message.into()));
+        }
+    }
+
+    pub fn has(&self, field: &str) -> bool {
+        self.0.iter().any(|(f, _)| *f == field)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Every message in one line, for places that can't show them by field
+    pub fn summary(&self) -> String {
+        self.0
+            .iter()
+            .map(|(_, m)| m.as_str())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// A field that has to be filled in
+    pub fn required<'a>(&mut self, field: &'static str, what: &str, value: &'a str) -> &'a str {
+        let value = value.trim();
+        if value.is_empty() {
+            self.add(field, format!("{what} can't be empty"));
+        }
+        value
+    }
+
+    /// An email address, if there is one
+    pub fn email(&mut self, field: &'static str, value: &str) {
+        let value = value.trim();
+        if !value.is_empty() && !value.contains('@') {
+            self.add(
+                field,
+                format!("\"{value}\" doesn't look like an email address"),
+            );
+        }
+    }
+
+    /// A number that isn't negative, empty counts as zero
+    pub fn number<T>(&mut self, field: &'static str, value: &str) -> T
+    where
+        T: FromStr + Default + PartialOrd,
+    {
+        match value.trim() {
+            "" => T::default(),
+            v => match v.parse::<T>() {
+                Ok(n) if n.partial_cmp(&T::default()).is_none() => {
+                    self.add(field, format!("\"{v}\" isn't a number"));
+                    T::default()
+                }
+                Ok(n) if n < T::default() => {
+                    self.add(field, "This can't be negative");
+                    T::default()
+                }
+                Ok(n) => n,
+                Err(_) => {
+                    self.add(field, format!("\"{v}\" isn't a number"));
+                    T::default()
+                }
+            },
+        }
+    }
+
+    /// A dollar amount, like 12.50, in cents. Empty counts as zero.
+    pub fn cents(&mut self, field: &'static str, value: &str) -> i32 {
+        let value = value.trim();
+        if value.is_empty() {
+            return 0;
+        }
+        match Decimal::from_str_exact(value)
+            .ok()
+            .and_then(|d| (d * Decimal::ONE_HUNDRED).to_i32())
+        {
+            Some(c) if c < 0 => {
+                self.add(field, "This can't be negative");
+                0
+            }
+            Some(c) => c,
+            None => {
+                self.add(
+                    field,
+                    format!("\"{value}\" isn't a dollar amount, write it like 12.50"),
+                );
+                0
+            }
+        }
+    }
+
+    /// Each of `fields` with its message, empty when it's fine. Templates are
+    /// strict, so every field the form shows needs a key.
+    pub fn view(&self, fields: &[&str]) -> Value {
+        Value::Object(
+            fields
+                .iter()
+                .map(|f| {
+                    let message = self
+                        .0
+                        .iter()
+                        .find(|(field, _)| field == f)
+                        .map(|(_, m)| m.clone())
+                        .unwrap_or_default();
+                    (f.to_string(), Value::String(message))
+                })
+                .collect::<Map<_, _>>(),
+        )
+    }
+}
+
+/// A checkbox, which browsers leave out when it isn't ticked. The edit
+/// conflict page sends off instead.
+pub fn checked(value: &Option<String>) -> bool {
+    !matches!(value.as_deref(), None | Some("" | "off" | "false" | "no"))
+}
+
+/// Shows the form again with its errors
+pub fn rerender(template: &'static str, engine: AppEngine, data: Value) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        RenderHtml(template, engine, data),
+    )
+        .into_response()
+}
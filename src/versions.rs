@@ -0,0 +1,127 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use axum_template::RenderHtml;
+use git_version::git_version;
+use serde_json::json;
+
+use crate::AppEngine;
+
+/// One input on a form that was saved over: what was sent, and what's saved now
+pub struct Field {
+    name: &'static str,
+    label: &'static str,
+    mine: String,
+    theirs: String,
+    mine_shown: String,
+    theirs_shown: String,
+}
+
+impl Field {
+    pub fn text(
+        name: &'static str,
+        label: &'static str,
+        mine: impl Into<String>,
+        theirs: impl Into<String>,
+    ) -> Self {
+        let (mine, theirs) = (mine.into(), theirs.into());
+        Self {
+            name,
+            label,
+            mine_shown: mine.clone(),
+            theirs_shown: theirs.clone(),
+            mine,
+            theirs,
+        }
+    }
+
+    /// A checkbox, sent back as on or off
+    pub fn flag(name: &'static str, label: &'static str, mine: bool, theirs: bool) -> Self {
+        let value = |b: bool| if b { "on" } else { "off" };
+        let shown = |b: bool| if b { "Yes" } else { "No" };
+        Self {
+            name,
+            label,
+            mine: value(mine).to_string(),
+            theirs: value(theirs).to_string(),
+            mine_shown: shown(mine).to_string(),
+            theirs_shown: shown(theirs).to_string(),
+        }
+    }
+
+    /// Shows the values differently than they're sent, like names for ids
+    pub fn shown(mut self, mine: impl Into<String>, theirs: impl Into<String>) -> Self {
+        self.mine_shown = mine.into();
+        self.theirs_shown = theirs.into();
+        self
+    }
+
+    /// Numbers count as the same however they're written, 2 and 2.00, and
+    /// empty number inputs count as zero like they do when saved
+    fn differs(&self) -> bool {
+        let number = |v: &str| match v.trim() {
+            "" => Some(0.),
+            v => v.parse::<f64>().ok(),
+        };
+        match (number(&self.mine), number(&self.theirs)) {
+            (Some(mine), Some(theirs)) => mine != theirs,
+            _ => self.mine.trim() != self.theirs.trim(),
+        }
+    }
+}
+
+/// A save sent from a copy of a form that's older than what's saved now.
+/// It isn't saved; instead both are shown side by side, to pick from field
+/// by field or to overwrite what's saved with.
+pub struct Stale {
+    /// What was being edited, like "job 12"
+    pub what: String,
+    /// Where the form posts to
+    pub action: &'static str,
+    /// The page to go back to without saving
+    pub back: String,
+    pub fields: Vec<Field>,
+    /// Inputs sent back as they are, including the version saved now
+    pub carried: Vec<(&'static str, String)>,
+}
+
+impl Stale {
+    pub fn render(self, engine: AppEngine, admin: bool) -> Response {
+        let data = json!({
+        "git_ver": git_version!(),
+            "title": "CZ4R Edit Conflict",
+            "admin": admin,
+            "logged_in": true,
+            "what": self.what,
+            "action": self.action,
+            "back": self.back,
+            "fields": self.fields.iter().map(|f| json!({
+                "name": f.name,
+                "label": f.label,
+                "mine": f.mine,
+                "theirs": f.theirs,
+                "mine_shown": f.mine_shown,
+                "theirs_shown": f.theirs_shown,
+                "differs": f.differs(),
+            })).collect::<Vec<_>>(),
+            "carried": self.carried.iter().map(|(name, value)| json!({
+                "name": name,
+                "value": value,
+            })).collect::<Vec<_>>(),
+        });
+
+        // the forms are sent with htmx, which would otherwise only swap in
+        // the part of the page it asked for
+        (
+            StatusCode::CONFLICT,
+            [
+                ("HX-Retarget", "#page"),
+                ("HX-Reswap", "innerHTML"),
+                ("HX-Reselect", "#page"),
+            ],
+            RenderHtml("editconflict.hbs", engine, data),
+        )
+            .into_response()
+    }
+}
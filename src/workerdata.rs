@@ -6,6 +6,7 @@ use axum::{
 
 use axum_template::RenderHtml;
 use git_version::git_version;
+use itertools::Itertools;
 use password_hash::{rand_core::le, PasswordHasher, Salt, SaltString};
 use serde::{Deserialize, Serialize};
 use sqlx::types::time::Date;
@@ -15,17 +16,27 @@ use time::{
 };
 use tracing::debug;
 
+use crate::mileage::suggested_travel;
+use crate::punches::{paid_hours, worker_punches, Punch};
+use crate::roles::{can, Allowed};
+use crate::settings::mileage_ratio;
+use crate::signatures::{worker_signatures, Signature};
+use crate::teams::team_options;
+use crate::Backend;
 use crate::{
+    empty_string_as_none,
     errors::{self, CustomError},
     now, AppState, Worker,
 };
-use crate::{get_admin, Backend};
 use axum_login::AuthSession;
 #[derive(Deserialize)]
 pub(crate) struct WorkerDataForm {
     worker: Option<i64>,
     start_date: Option<Date>,
     end_date: Option<Date>,
+    /// Only list this team's members to pick from
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    team: Option<i64>,
 }
 
 #[derive(Serialize, Default, Debug)]
@@ -41,19 +52,30 @@ pub struct WDEntry {
     pub MilesDriven: String,
     pub ExtraExpCents: String,
     pub Completed: bool,
+    pub Edited: bool,
+    pub Original: String,
+    pub Offsite: bool,
+    pub OffsiteNote: String,
+    pub EnteredBy: String,
+    pub SuggestedMiles: String,
+    pub SuggestedHours: String,
+    pub TravelOutlier: bool,
+    pub Signed: String,
 }
 
-fn hours_worked(signin: Time, signout: Time) -> f32 {
-    ((signout - signin).as_seconds_f32() / 3600.).max(1.0)
+fn hours_worked(punches: &[Punch]) -> f32 {
+    paid_hours(punches).max(1.0)
 }
 
 pub(crate) async fn workerdatapage(
     State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
+    Allowed {
+        id: my_id,
+        name: my_name,
+        ..
+    }: Allowed<can::ViewRates>,
     Form(worker): Form<WorkerDataForm>,
 ) -> Result<impl IntoResponse, CustomError> {
-    let (my_id, my_name) = get_admin(&auth)?;
-
     let users = sqlx::query_as!(
         Worker,
         "
@@ -64,8 +86,21 @@ pub(crate) async fn workerdatapage(
     .fetch_all(&pool)
     .await?;
 
+    let members = match worker.team {
+        Some(team) => Some(
+            query!("select worker from team_members where team = $1;", team)
+                .fetch_all(&pool)
+                .await?
+                .into_iter()
+                .map(|r| r.worker)
+                .collect::<Vec<_>>(),
+        ),
+        None => None,
+    };
+
     let selectlist = users
         .iter()
+        .filter(|w| members.as_ref().is_none_or(|m| m.contains(&w.id)))
         .map(|w| (w.id, w.name.as_str()))
         .collect::<Vec<_>>();
 
@@ -111,21 +146,17 @@ pub(crate) async fn workerdatapage(
         .fetch_all(&pool)
         .await?;
 
+        let punches = worker_punches(&pool, id, start_date, end_date).await?;
+        let no_punches = vec![];
+        let punches_for = |job: i64| punches.get(&job).unwrap_or(&no_punches);
+        let travel = suggested_travel(&punches);
+        let ratio = mileage_ratio(&pool).await?;
+        let signatures = worker_signatures(&pool, id, start_date, end_date).await?;
+
         let hours_worked_total = data
             .iter()
             .filter(|d| d.signin.is_some() && d.signout.is_some())
-            .map(|x| {
-                let signin = Some(Time::parse(&x.signin.clone().unwrap(), &Iso8601::TIME));
-                let signout = Some(Time::parse(&x.signout.clone().unwrap(), &Iso8601::TIME));
-                (signin, signout)
-            })
-            .filter(|(signin, signout)| signin.is_some() && signout.is_some())
-            .map(|(signin, signout)| (signin.unwrap(), signout.unwrap()))
-            .filter(|(signin, signout)| signin.is_ok() && signout.is_ok())
-            .map(|(signin, signout)| (signin.unwrap(), signout.unwrap()))
-            .fold(0.0, |acc, (signin, signout)| {
-                acc + hours_worked(signin, signout)
-            });
+            .fold(0.0, |acc, x| acc + hours_worked(punches_for(x.job)));
         let hours_driven_total = data
             .iter()
             .filter(|d| d.signin.is_some() && d.signout.is_some())
@@ -151,29 +182,32 @@ pub(crate) async fn workerdatapage(
             .into_iter()
             .map(|d| {
                 let Completed = d.signin.is_some() && d.signout.is_some();
+                let segments = punches_for(d.job);
+                let Edited = segments.iter().any(|p| p.edited);
+                let Original = if Edited {
+                    segments.iter().map(Punch::original).join(", ")
+                } else {
+                    String::new()
+                };
+                let Offsite = segments.iter().any(|p| p.offsite);
+                let OffsiteNote = segments
+                    .iter()
+                    .filter(|p| p.offsite)
+                    .map(Punch::location)
+                    .join(", ");
+                let EnteredBy = segments.iter().filter_map(Punch::entered_by).join("; ");
+                let suggested = travel.get(&d.job);
                 WDEntry {
                     Date: d.date.unwrap(),
                     Location: d.sitename,
                     FlatRate: d.using_flat_rate,
-                    HoursWorked: {
-                        if Completed {
-                            let signin =
-                                Time::parse(&d.signin.clone().unwrap(), &Iso8601::TIME).unwrap();
-                            let signout =
-                                Time::parse(&d.signout.clone().unwrap(), &Iso8601::TIME).unwrap();
-                            let val = hours_worked(signin, signout);
-                            format!("{:.2}", val)
-                        } else {
-                            String::from("N/A")
-                        }
+                    HoursWorked: if Completed {
+                        format!("{:.2}", hours_worked(segments))
+                    } else {
+                        String::from("N/A")
                     },
                     TrueHoursWorked: if Completed {
-                        {
-                            let signin = Time::parse(&d.signin.unwrap(), &Iso8601::TIME).unwrap();
-                            let signout = Time::parse(&d.signout.unwrap(), &Iso8601::TIME).unwrap();
-                            let val = (signout - signin).as_seconds_f32() / 3600.;
-                            format!("{:.2}", val)
-                        }
+                        format!("{:.2}", paid_hours(segments))
                     } else {
                         String::from("N/A")
                     },
@@ -183,6 +217,23 @@ pub(crate) async fn workerdatapage(
                     WorkerId: d.worker,
                     JobId: d.job,
                     Completed,
+                    Edited,
+                    Original,
+                    Offsite,
+                    OffsiteNote,
+                    EnteredBy,
+                    SuggestedMiles: suggested
+                        .map(|t| format!("{:.2}", t.miles))
+                        .unwrap_or_default(),
+                    SuggestedHours: suggested
+                        .map(|t| format!("{:.2}", t.hours))
+                        .unwrap_or_default(),
+                    TravelOutlier: suggested
+                        .is_some_and(|t| t.outlier(d.miles_driven, d.hours_driven, ratio)),
+                    Signed: signatures
+                        .get(&d.job)
+                        .map(Signature::describe)
+                        .unwrap_or_default(),
                 }
             })
             .collect::<Vec<_>>();
@@ -203,12 +254,21 @@ pub(crate) async fn workerdatapage(
             JobId: -1,
             WorkerId: -1,
             Completed: all_complete,
+            Edited: false,
+            Original: String::new(),
+            Offsite: false,
+            OffsiteNote: String::new(),
+            EnteredBy: String::new(),
+            SuggestedMiles: String::new(),
+            SuggestedHours: String::new(),
+            TravelOutlier: false,
+            Signed: String::new(),
         };
 
-        let user = selectlist.iter().find(|u| u.0 == id).unwrap();
+        let user = users.iter().find(|u| u.id == id).unwrap();
         debug!(
             "admin {my_name} (id {my_id}) retrieved data on user {} (id {}) from {} to {}",
-            user.1, id, from, to
+            user.name, id, from, to
         );
 
         (entries, totals)
@@ -229,6 +289,8 @@ pub(crate) async fn workerdatapage(
         "totals": totals,
         "from": &from,
         "to": &to,
+        "team": worker.team,
+        "teams": team_options(&pool, worker.team).await?,
         "target": "worker-data"
     });
 
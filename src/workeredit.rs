@@ -1,6 +1,7 @@
 use super::Worker;
 use crate::errors::CustomError;
-use crate::get_admin;
+use crate::roles::{can, Allowed, Role};
+use crate::validation::FieldErrors;
 use crate::AppState;
 use crate::Backend;
 use crate::IntoResponse;
@@ -11,8 +12,8 @@ use axum_login::AuthSession;
 use axum_template::RenderHtml;
 use git_version::git_version;
 use serde::Deserialize;
-use serde_json::json;
-use sqlx::Pool;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
 
 #[derive(Deserialize)]
 pub(crate) struct WorkerEditForm {
@@ -20,53 +21,115 @@ pub(crate) struct WorkerEditForm {
     creating: Option<bool>,
 }
 
-pub(crate) async fn workeredit(
-    State(AppState { pool, engine, .. }): State<AppState>,
-    mut auth: AuthSession<Backend>,
-    Form(worker): Form<WorkerEditForm>,
-) -> Result<impl IntoResponse, CustomError> {
-    let id = get_admin(&auth)?;
+/// The inputs on the worker form, by name
+pub(crate) const WORKER_FIELDS: [&str; 9] = [
+    "Name",
+    "Address",
+    "Phone",
+    "Email",
+    "Hourly",
+    "Mileage",
+    "Drivetime",
+    "Flatrate",
+    "Role",
+];
 
+/// The worker page. When a form didn't go through, `submitted` is shown in
+/// place of what's saved, with its errors.
+pub(crate) async fn worker_page(
+    pool: &Pool<Sqlite>,
+    own_id: i64,
+    selected: Option<i64>,
+    creating: bool,
+    submitted: Option<(Value, &FieldErrors)>,
+) -> Result<Value, CustomError> {
     let users = sqlx::query_as!(
         Worker,
         "
         select * from users where deactivated = false order by id asc;
         "
     )
-    .fetch_all(&pool)
-    .await
-    .unwrap();
-    let mut selectlist = users
+    .fetch_all(pool)
+    .await?;
+    let selectlist = users
         .iter()
         .map(|w| (w.id, w.name.as_str()))
         .collect::<Vec<_>>();
 
-    let data = serde_json::json!({
+    let (values, errors) = match submitted {
+        Some((values, errors)) => (Some(values), errors.view(&WORKER_FIELDS)),
+        None => (None, FieldErrors::default().view(&WORKER_FIELDS)),
+    };
+
+    Ok(serde_json::json!({
     "git_ver": git_version!(),
         "admin": true,
         "logged_in": true,
         "title": "CZ4R Worker Edit",
         "target": "worker-edit",
-        "creating": worker.creating == Some(true),
-        "selected": worker.worker,
+        "creating": creating,
+        "selected": selected,
         "selectlist": selectlist,
-        "own_id": id,
-        "workerlist": (users.iter().map(|u|json!({
-            "id": u.id,
-            "name": u.name,
-            "hash": u.hash,
-            "salt": u.salt,
-            "admin": u.admin,
-            "address": u.address,
-            "phone": u.phone,
-            "email": u.email,
-            "rate_hourly_cents": format!("{:.2}", (u.rate_hourly_cents as f64 / 100.)),
-            "rate_mileage_cents": format!("{:.2}", (u.rate_mileage_cents as f64 / 100.)),
-            "rate_drive_hourly_cents": format!("{:.2}", (u.rate_drive_hourly_cents as f64 / 100.)),
-            "flat_rate_cents": format!("{:.2}", (u.flat_rate_cents as f64 / 100.)),
-            "must_change_pw": u.must_change_pw
-        })).collect::<Vec<_>>())
-    });
+        "own_id": own_id,
+        "errors": errors,
+        "values": match (&values, creating) {
+            (Some(values), true) => values.clone(),
+            _ => json!({
+                "name": "",
+                "address": "",
+                "phone": "",
+                "email": "",
+                "role": "worker",
+                "roles": Role::options(Role::Worker),
+                "rate_hourly_cents": "0.00",
+                "rate_mileage_cents": "0.00",
+                "rate_drive_hourly_cents": "0.00",
+                "flat_rate_cents": "0.00",
+            }),
+        },
+        "workerlist": (users.iter().map(|u| {
+            let mut w = json!({
+                "id": u.id,
+                "name": u.name,
+                "hash": u.hash,
+                "salt": u.salt,
+                "role": u.role,
+                "roles": Role::options(u.role()),
+                "address": u.address,
+                "phone": u.phone,
+                "email": u.email,
+                "rate_hourly_cents": format!("{:.2}", (u.rate_hourly_cents as f64 / 100.)),
+                "rate_mileage_cents": format!("{:.2}", (u.rate_mileage_cents as f64 / 100.)),
+                "rate_drive_hourly_cents": format!("{:.2}", (u.rate_drive_hourly_cents as f64 / 100.)),
+                "flat_rate_cents": format!("{:.2}", (u.flat_rate_cents as f64 / 100.)),
+                "must_change_pw": u.must_change_pw,
+                "version": u.version,
+            });
+            if let (Some(Value::Object(typed)), false) = (&values, creating) {
+                if selected == Some(u.id) {
+                    for (k, v) in typed {
+                        w[k] = v.clone();
+                    }
+                }
+            }
+            w
+        }).collect::<Vec<_>>())
+    }))
+}
+
+pub(crate) async fn workeredit(
+    State(AppState { pool, engine, .. }): State<AppState>,
+    Allowed { id: my_id, .. }: Allowed<can::ManageUsers>,
+    Form(worker): Form<WorkerEditForm>,
+) -> Result<impl IntoResponse, CustomError> {
+    let data = worker_page(
+        &pool,
+        my_id,
+        worker.worker,
+        worker.creating == Some(true),
+        None,
+    )
+    .await?;
 
     Ok(RenderHtml("workeredit.hbs", engine, data))
 }
@@ -0,0 +1,35 @@
+// Registers the service worker, tells it who is logged in, has it send anything
+// saved while offline, and has it keep a copy of the check in pages linked
+// from this one.
+(() => {
+  'use strict'
+
+  if (!('serviceWorker' in navigator)) {
+    return
+  }
+
+  const tell = message => {
+    navigator.serviceWorker.ready.then(reg => reg.active && reg.active.postMessage(message))
+  }
+
+  const cacheLinks = () => {
+    const urls = [...document.querySelectorAll('a[href^="/checkinout"]')].map(a => a.getAttribute('href'))
+    if (document.location.pathname === '/checkinout') {
+      urls.push(document.location.pathname + document.location.search)
+    }
+    if (urls.length) {
+      tell({ cache: [...new Set(urls)] })
+    }
+  }
+
+  const user = document.querySelector('meta[name="cz4r-user"]')
+
+  navigator.serviceWorker.register('/sw.js').then(() => {
+    // the replay waits until the service worker knows whose forms to send
+    tell(user ? { user: Number(user.content) } : 'replay')
+    cacheLinks()
+  })
+
+  window.addEventListener('online', () => tell('replay'))
+  document.addEventListener('htmx:afterSettle', cacheLinks)
+})();
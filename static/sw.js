@@ -0,0 +1,262 @@
+// CZ4R service worker: keeps the pages a worker has opened available with no
+// signal, and holds on to clock punches and check in forms until it can send
+// them to /api/v1/offline-sync.
+
+const CACHE = "cz4r-pages-v1";
+const DB = "cz4r-offline";
+const STORE = "queue";
+// who the saved pages and queued forms belong to
+const META = "meta";
+
+// posts that can wait, and what the sync endpoint calls them
+const QUEUED = {
+  "/api/v1/clock": "clock",
+  "/api/v1/checkinout": "checkinout",
+  "/api/v1/punch": "punch",
+  "/api/v1/signature": "signature",
+  "/api/v1/checklist": "checklist",
+};
+
+self.addEventListener("install", () => self.skipWaiting());
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    caches
+      .keys()
+      .then((keys) => Promise.all(keys.filter((k) => k !== CACHE).map((k) => caches.delete(k))))
+      .then(() => self.clients.claim())
+  );
+});
+
+function openDb() {
+  return new Promise((resolve, reject) => {
+    const req = indexedDB.open(DB, 2);
+    req.onupgradeneeded = () => {
+      const db = req.result;
+      if (!db.objectStoreNames.contains(STORE)) {
+        db.createObjectStore(STORE, { keyPath: "key" });
+      }
+      if (!db.objectStoreNames.contains(META)) {
+        db.createObjectStore(META);
+      }
+    };
+    req.onsuccess = () => resolve(req.result);
+    req.onerror = () => reject(req.error);
+  });
+}
+
+function tx(mode, fn, name = STORE) {
+  return openDb().then(
+    (db) =>
+      new Promise((resolve, reject) => {
+        const t = db.transaction(name, mode);
+        const result = fn(t.objectStore(name));
+        t.oncomplete = () => resolve(result && result.result);
+        t.onerror = () => reject(t.error);
+      })
+  );
+}
+
+const allQueued = () => tx("readonly", (store) => store.getAll());
+const dropQueued = (key) => tx("readwrite", (store) => store.delete(key));
+
+const getOwner = () => tx("readonly", (store) => store.get("owner"), META);
+const setOwner = (user) =>
+  tx("readwrite", (store) => (user === null ? store.delete("owner") : store.put(user, "owner")), META);
+
+// Someone else is using the device, so the pages saved for the last one go.
+// Their queued forms stay until they log back in.
+async function changeOwner(user) {
+  if ((await getOwner()) !== user) {
+    await caches.delete(CACHE);
+    await setOwner(user);
+  }
+}
+
+// The check in form posts on every keystroke, only its latest state matters
+function sameCheckin(a, b) {
+  const pa = new URLSearchParams(a.body);
+  const pb = new URLSearchParams(b.body);
+  return pa.get("JobId") === pb.get("JobId") && pa.get("WorkerId") === pb.get("WorkerId");
+}
+
+function withVersion(body, version) {
+  const params = new URLSearchParams(body);
+  if (version !== null && version !== undefined) {
+    params.set("Version", version);
+  }
+  return params.toString();
+}
+
+// A time sheet saved from the queue has a new version, so the next edit to it
+// has to be sent with that one or it looks like someone else's change
+async function chainVersion(queued, entry, version) {
+  for (const next of queued) {
+    if (next.kind === "checkinout" && next.key !== entry.key && sameCheckin(next, entry)) {
+      next.body = withVersion(next.body, version);
+      await tx("readwrite", (store) => store.put(next));
+    }
+  }
+}
+
+async function enqueue(kind, body) {
+  const entry = {
+    key: crypto.randomUUID(),
+    kind: kind,
+    captured_at: new Date().toISOString(),
+    body: body,
+    owner: (await getOwner()) ?? null,
+  };
+  if (kind === "checkinout") {
+    for (const old of await allQueued()) {
+      if (old.kind === "checkinout" && sameCheckin(old, entry)) {
+        // the first edit's version is the one the server knows about
+        entry.body = withVersion(entry.body, new URLSearchParams(old.body).get("Version"));
+        await dropQueued(old.key);
+      }
+    }
+  }
+  await tx("readwrite", (store) => store.put(entry));
+  if (self.registration.sync) {
+    self.registration.sync.register("cz4r-replay").catch(() => {});
+  }
+}
+
+const SAVED_PAGE = `<!DOCTYPE html>
+<html lang="en" data-bs-theme="dark"><head><meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1"><title>CZ4R Saved Offline</title>
+<link href="https://cdn.jsdelivr.net/npm/bootstrap@5.3.0-alpha1/dist/css/bootstrap.min.css" rel="stylesheet"></head>
+<body><div class="container mt-5">
+<div class="alert alert-warning">
+You're offline. This was saved on your phone with the time you pressed it, and will be sent when you have signal again.
+</div>
+<a class="btn btn-primary" href="javascript:history.back()">Back</a>
+</div></body></html>`;
+
+async function queuePost(request, kind) {
+  const body = await request.clone().text();
+  try {
+    return await fetch(request);
+  } catch (e) {
+    await enqueue(kind, body);
+    if (request.headers.get("HX-Request")) {
+      return new Response(null, { status: 202 });
+    }
+    return new Response(SAVED_PAGE, {
+      status: 202,
+      headers: { "Content-Type": "text/html; charset=utf-8" },
+    });
+  }
+}
+
+async function networkFirst(request) {
+  const cache = await caches.open(CACHE);
+  try {
+    const response = await fetch(request);
+    if (response.ok && !response.redirected) {
+      cache.put(request, response.clone());
+    }
+    return response;
+  } catch (e) {
+    const cached = await cache.match(request);
+    if (cached) {
+      return cached;
+    }
+    throw e;
+  }
+}
+
+self.addEventListener("fetch", (event) => {
+  const url = new URL(event.request.url);
+  if (url.origin !== self.location.origin) {
+    return;
+  }
+  if (event.request.method === "POST" && url.pathname === "/logout") {
+    event.respondWith(changeOwner(null).then(() => fetch(event.request)));
+  } else if (event.request.method === "POST" && QUEUED[url.pathname]) {
+    event.respondWith(queuePost(event.request, QUEUED[url.pathname]));
+  } else if (event.request.method === "GET" && event.request.mode === "navigate") {
+    event.respondWith(networkFirst(event.request));
+  }
+});
+
+let replaying = null;
+
+async function replayAll() {
+  // in the order they were made, a signature has to land before the clock out
+  const owner = await getOwner();
+  if (owner === undefined) {
+    return; // nobody is logged in here to send them as
+  }
+  const queued = await allQueued();
+  queued.sort((a, b) => a.captured_at.localeCompare(b.captured_at));
+  for (const entry of queued) {
+    if (entry.owner !== undefined && entry.owner !== null && entry.owner !== owner) {
+      continue; // it waits for whoever saved it
+    }
+    let response;
+    try {
+      response = await fetch("/api/v1/offline-sync", {
+        method: "POST",
+        credentials: "same-origin",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(entry),
+      });
+    } catch (e) {
+      return; // still offline, try again later
+    }
+    if (
+      response.status >= 500 ||
+      response.status === 401 ||
+      response.status === 403 ||
+      response.redirected
+    ) {
+      // the server is down, or nobody is logged in on this device or the
+      // login is one that can't apply it, so keep it until whoever made it
+      // logs back in
+      return;
+    }
+    // applied, rejected or a duplicate, the server has it either way
+    if (entry.kind === "checkinout" && response.ok) {
+      const result = await response.json().catch(() => ({}));
+      if (result.version !== null && result.version !== undefined) {
+        await chainVersion(queued, entry, result.version);
+      }
+    }
+    await dropQueued(entry.key);
+  }
+}
+
+function replay() {
+  if (!replaying) {
+    replaying = replayAll().finally(() => (replaying = null));
+  }
+  return replaying;
+}
+
+self.addEventListener("sync", (event) => {
+  if (event.tag === "cz4r-replay") {
+    event.waitUntil(replay());
+  }
+});
+
+self.addEventListener("message", (event) => {
+  if (event.data === "replay") {
+    event.waitUntil(replay());
+  } else if (event.data && event.data.user !== undefined) {
+    event.waitUntil(changeOwner(event.data.user).then(replay));
+  } else if (event.data && event.data.cache) {
+    // pages the worker will want with no signal, like their assigned jobs
+    event.waitUntil(
+      caches.open(CACHE).then((cache) =>
+        Promise.all(
+          event.data.cache.map((u) =>
+            fetch(u, { credentials: "same-origin" })
+              .then((r) => (r.ok && !r.redirected ? cache.put(u, r) : null))
+              .catch(() => null)
+          )
+        )
+      )
+    );
+  }
+});